@@ -0,0 +1,208 @@
+use crate::AppConfig;
+use anyhow::{Result, bail};
+use rand::Rng;
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use std::time::Duration;
+use time::OffsetDateTime;
+use time::format_description::well_known::Rfc2822;
+
+/// Upper bound for `Retry-After`, which otherwise takes precedence over the policy
+const RETRY_AFTER_MAX_SECONDS: u64 = 60 * 60 * 24;
+
+/// Exponential backoff between delivery attempts: the first retry waits `base_delay`,
+/// every further retry `multiplier` times longer, up to `max_delay`. Delays are spread
+/// by up to `jitter` (a fraction of the delay) in either direction, so webhooks that
+/// failed together are not retried together.
+#[derive(Debug, Clone)]
+pub struct BackoffPolicy {
+    base_delay_seconds: u64,
+    multiplier: f64,
+    jitter: f64,
+    max_delay_seconds: u64,
+}
+
+impl BackoffPolicy {
+    pub fn from_config(config: &AppConfig) -> Result<Self> {
+        let policy = Self {
+            base_delay_seconds: config.worker_retry_base_delay_seconds,
+            multiplier: config.worker_retry_multiplier,
+            jitter: config.worker_retry_jitter,
+            max_delay_seconds: config.worker_max_delay_seconds,
+        };
+        policy.validate()?;
+        Ok(policy)
+    }
+
+    /// Applies the retry settings of a route, unset ones keep the worker settings
+    pub fn with_overrides(
+        &self,
+        base_delay_seconds: Option<i32>,
+        multiplier: Option<f64>,
+        max_delay_seconds: Option<i32>,
+    ) -> Result<Self> {
+        let policy = Self {
+            base_delay_seconds: base_delay_seconds
+                .map(|seconds| seconds.max(0) as u64)
+                .unwrap_or(self.base_delay_seconds),
+            multiplier: multiplier.unwrap_or(self.multiplier),
+            jitter: self.jitter,
+            max_delay_seconds: max_delay_seconds
+                .map(|seconds| seconds.max(0) as u64)
+                .unwrap_or(self.max_delay_seconds),
+        };
+        policy.validate()?;
+        Ok(policy)
+    }
+
+    /// Delay before the next attempt after `attempts` failed attempts
+    pub fn delay(&self, attempts: i32) -> Duration {
+        let exponent = (attempts - 1).clamp(0, 1000);
+        let max_delay = self.max_delay_seconds as f64;
+        // Large exponents overflow to infinity, which the clamp takes care of
+        let mut delay =
+            (self.base_delay_seconds as f64 * self.multiplier.powi(exponent)).min(max_delay);
+        if self.jitter > 0.0 {
+            delay *= 1.0 + rand::rng().random_range(-self.jitter..=self.jitter);
+        }
+        Duration::from_secs_f64(delay.clamp(0.0, max_delay))
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.multiplier.is_nan() || self.multiplier < 1.0 {
+            bail!(
+                "Retry multiplier must be at least 1, got {}",
+                self.multiplier
+            );
+        }
+        if !(0.0..=1.0).contains(&self.jitter) {
+            bail!("Retry jitter must be between 0 and 1, got {}", self.jitter);
+        }
+        if self.base_delay_seconds > self.max_delay_seconds {
+            bail!(
+                "Retry base delay of {} seconds exceeds the max delay of {} seconds",
+                self.base_delay_seconds,
+                self.max_delay_seconds
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Reads the `Retry-After` header, in seconds or as HTTP date
+pub fn parse_retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    let seconds = match value.parse::<u64>() {
+        Ok(seconds) => seconds,
+        Err(_) => {
+            let date = OffsetDateTime::parse(value, &Rfc2822).ok()?;
+            (date - OffsetDateTime::now_utc()).whole_seconds().max(0) as u64
+        }
+    };
+    Some(Duration::from_secs(seconds.min(RETRY_AFTER_MAX_SECONDS)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    fn policy(base_delay_seconds: u64, multiplier: f64, jitter: f64, max: u64) -> BackoffPolicy {
+        BackoffPolicy {
+            base_delay_seconds,
+            multiplier,
+            jitter,
+            max_delay_seconds: max,
+        }
+    }
+
+    fn retry_after(value: &str) -> Option<Duration> {
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, HeaderValue::from_str(value).unwrap());
+        parse_retry_after(&headers)
+    }
+
+    #[test]
+    fn delay_grows_exponentially_up_to_max() {
+        let policy = policy(60, 2.0, 0.0, 1800);
+        let delays = (0..=7)
+            .map(|attempts| policy.delay(attempts).as_secs())
+            .collect::<Vec<_>>();
+        assert_eq!(delays, [60, 60, 120, 240, 480, 960, 1800, 1800]);
+        assert_eq!(policy.delay(i32::MAX).as_secs(), 1800);
+    }
+
+    #[test]
+    fn multiplier_of_one_keeps_delay_constant() {
+        let policy = policy(30, 1.0, 0.0, 1800);
+        assert_eq!(policy.delay(1), Duration::from_secs(30));
+        assert_eq!(policy.delay(20), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn jitter_stays_within_bounds() {
+        let policy = policy(100, 2.0, 0.1, 150);
+        for _ in 0..200 {
+            let first = policy.delay(1).as_secs_f64();
+            assert!((90.0..=110.0).contains(&first), "{}", first);
+            // Jitter never exceeds the max delay
+            let capped = policy.delay(5).as_secs_f64();
+            assert!((135.0..=150.0).contains(&capped), "{}", capped);
+        }
+    }
+
+    #[test]
+    fn overrides_replace_set_values_only() {
+        let policy = policy(60, 2.0, 0.0, 1800)
+            .with_overrides(Some(10), None, Some(100))
+            .unwrap();
+        assert_eq!(policy.base_delay_seconds, 10);
+        assert_eq!(policy.multiplier, 2.0);
+        assert_eq!(policy.max_delay_seconds, 100);
+        assert_eq!(policy.delay(5).as_secs(), 100);
+    }
+
+    #[test]
+    fn invalid_policies_are_rejected() {
+        assert!(policy(60, 0.5, 0.0, 1800).validate().is_err());
+        assert!(policy(60, f64::NAN, 0.0, 1800).validate().is_err());
+        assert!(policy(60, 2.0, 1.5, 1800).validate().is_err());
+        assert!(policy(60, 2.0, -0.1, 1800).validate().is_err());
+        assert!(policy(3600, 2.0, 0.0, 1800).validate().is_err());
+        assert!(
+            policy(60, 2.0, 0.0, 1800)
+                .with_overrides(None, None, Some(10))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn retry_after_in_seconds() {
+        assert_eq!(retry_after("120"), Some(Duration::from_secs(120)));
+        assert_eq!(retry_after(" 0 "), Some(Duration::ZERO));
+        assert_eq!(
+            retry_after("31536000"),
+            Some(Duration::from_secs(RETRY_AFTER_MAX_SECONDS))
+        );
+    }
+
+    #[test]
+    fn retry_after_as_http_date() {
+        let date = (OffsetDateTime::now_utc() + time::Duration::seconds(120))
+            .format(&Rfc2822)
+            .unwrap();
+        let delay = retry_after(&date).unwrap().as_secs();
+        assert!((118..=120).contains(&delay), "{}", delay);
+        // Dates in the past mean retrying right away
+        assert_eq!(
+            retry_after("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(Duration::ZERO)
+        );
+    }
+
+    #[test]
+    fn invalid_retry_after_is_ignored() {
+        assert_eq!(retry_after("soon"), None);
+        assert_eq!(retry_after("-5"), None);
+        assert_eq!(parse_retry_after(&HeaderMap::new()), None);
+    }
+}
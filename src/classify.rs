@@ -0,0 +1,420 @@
+use anyhow::{Context, Result, bail};
+use config::Config;
+use regex::{Regex, RegexBuilder};
+use serde::Deserialize;
+use std::path::Path;
+use tracing::debug;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Classification {
+    /// Coarse category: `hard`, `soft` or `block`
+    pub category: String,
+    /// Stable reason, e.g. `user_unknown` or `mailbox_full`
+    pub sub_category: String,
+}
+
+/// A classification rule as written in the user supplied rules file
+#[derive(Debug, Deserialize)]
+struct RuleDefinition {
+    status: Option<String>,
+    pattern: Option<String>,
+    category: Option<String>,
+    sub_category: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct RulesFile {
+    #[serde(default)]
+    rules: Vec<RuleDefinition>,
+}
+
+#[derive(Debug)]
+struct Rule {
+    status: Option<Vec<String>>,
+    pattern: Option<Regex>,
+    category: Option<String>,
+    sub_category: String,
+}
+
+/// (status, diagnostic pattern, category, sub_category)
+type BuiltinRule = (
+    Option<&'static str>,
+    Option<&'static str>,
+    Option<&'static str>,
+    &'static str,
+);
+
+/// Diagnostic phrases come first since they are usually more specific than the status
+/// code. A missing category is derived from the status class (5 = hard, 4 = soft).
+const BUILTIN_RULES: &[BuiltinRule] = &[
+    (
+        None,
+        Some(r"mailbox (is )?full|over ?quota|quota exceeded|insufficient (system )?storage"),
+        Some("soft"),
+        "mailbox_full",
+    ),
+    (
+        None,
+        Some(r"(message|mail) (is )?too (large|big)|message size exceeds|exceeds .*size limit"),
+        Some("soft"),
+        "message_too_large",
+    ),
+    (
+        None,
+        Some(r"(mailbox|account|address) (is )?(disabled|inactive|suspended|deactivated|closed)"),
+        Some("hard"),
+        "mailbox_disabled",
+    ),
+    (
+        None,
+        Some(
+            r"host (or domain name )?not found|domain (not found|does not exist|doesn't exist)|no mx|name service error|nxdomain|unrouteable|unroutable",
+        ),
+        None,
+        "dns_failure",
+    ),
+    (
+        None,
+        Some(
+            r"user unknown|unknown (user|recipient)|no such (user|recipient|mailbox)|(recipient|user|mailbox|account|address) (not found|does not exist|doesn't exist)|does not exist|invalid (recipient|mailbox)|recipnotfound|recipientnotfound|couldn't be found",
+        ),
+        Some("hard"),
+        "user_unknown",
+    ),
+    (
+        None,
+        Some(
+            r"spam|blacklist|blocklist|block list|listed (at|on|in)|spamhaus|\brbl\b|dnsbl|reputation",
+        ),
+        Some("block"),
+        "spam_block",
+    ),
+    (
+        None,
+        Some(
+            r"\bpolicy\b|not (authorized|permitted)|relay(ing)? (access )?denied|\bdmarc\b|\bspf\b|\bdkim\b|administrative prohibition",
+        ),
+        Some("block"),
+        "policy",
+    ),
+    (
+        None,
+        Some(r"timed? ?out|connection (refused|reset|lost)|network is unreachable"),
+        Some("soft"),
+        "network",
+    ),
+    (Some("X.1.1"), None, Some("hard"), "user_unknown"),
+    (Some("X.1.2"), None, None, "dns_failure"),
+    (Some("X.1.3"), None, Some("hard"), "user_unknown"),
+    (Some("X.1.6"), None, Some("hard"), "mailbox_disabled"),
+    (Some("X.1.10"), None, Some("hard"), "dns_failure"),
+    (Some("X.2.1"), None, Some("hard"), "mailbox_disabled"),
+    (Some("X.2.2"), None, Some("soft"), "mailbox_full"),
+    (Some("X.2.3"), None, Some("soft"), "message_too_large"),
+    (Some("X.3.4"), None, Some("soft"), "message_too_large"),
+    (Some("X.4.4"), None, None, "dns_failure"),
+    (Some("X.4"), None, Some("soft"), "network"),
+    (Some("X.7"), None, Some("block"), "policy"),
+    (Some("5"), None, Some("hard"), "other"),
+    (Some("4"), None, Some("soft"), "other"),
+];
+
+pub struct Classifier {
+    rules: Vec<Rule>,
+}
+
+impl Classifier {
+    /// Creates a classifier from the built-in rules, preceded by the rules of the
+    /// optional user supplied rules file
+    pub fn new(rules_file: Option<&Path>) -> Result<Self> {
+        let mut rules = Vec::new();
+
+        if let Some(rules_file) = rules_file {
+            let file = Config::builder()
+                .add_source(config::File::from(rules_file))
+                .build()
+                .and_then(|c| c.try_deserialize::<RulesFile>())
+                .with_context(|| {
+                    format!(
+                        "Failed to read classification rules from {}",
+                        rules_file.display()
+                    )
+                })?;
+            debug!(count = file.rules.len(), "Loaded classification rules");
+
+            for definition in file.rules {
+                rules.push(Rule::new(
+                    definition.status.as_deref(),
+                    definition.pattern.as_deref(),
+                    definition.category,
+                    definition.sub_category,
+                )?);
+            }
+        }
+
+        for (status, pattern, category, sub_category) in BUILTIN_RULES {
+            rules.push(Rule::new(
+                *status,
+                *pattern,
+                category.map(str::to_string),
+                sub_category.to_string(),
+            )?);
+        }
+
+        Ok(Self { rules })
+    }
+
+    /// Classifies a bounce from its enhanced status code (RFC 3463) and diagnostic
+    /// text. Returns `None` for statuses that do not indicate a failure.
+    pub fn classify(&self, status: &str, diagnostic: &str) -> Option<Classification> {
+        let status_parts = status.trim().split('.').collect::<Vec<_>>();
+        let class = *status_parts.first()?;
+        if class != "4" && class != "5" {
+            return None;
+        }
+
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(&status_parts, diagnostic))
+            .map(|rule| Classification {
+                category: rule
+                    .category
+                    .clone()
+                    .unwrap_or_else(|| if class == "5" { "hard" } else { "soft" }.to_string()),
+                sub_category: rule.sub_category.clone(),
+            })
+    }
+}
+
+impl Rule {
+    fn new(
+        status: Option<&str>,
+        pattern: Option<&str>,
+        category: Option<String>,
+        sub_category: String,
+    ) -> Result<Self> {
+        if status.is_none() && pattern.is_none() {
+            bail!(
+                "Classification rule for {} needs a status or a pattern",
+                sub_category
+            );
+        }
+
+        Ok(Self {
+            status: status.map(|s| s.split('.').map(str::to_lowercase).collect()),
+            pattern: pattern
+                .map(|p| {
+                    RegexBuilder::new(p)
+                        .case_insensitive(true)
+                        .build()
+                        .with_context(|| format!("Invalid classification pattern {}", p))
+                })
+                .transpose()?,
+            category,
+            sub_category,
+        })
+    }
+
+    /// Status components are matched as prefix, `X` or `*` match any value
+    fn matches(&self, status_parts: &[&str], diagnostic: &str) -> bool {
+        if let Some(rule_parts) = &self.status {
+            if rule_parts.len() > status_parts.len() {
+                return false;
+            }
+            let status_matches = rule_parts
+                .iter()
+                .zip(status_parts)
+                .all(|(rule, part)| rule == "x" || rule == "*" || rule == part);
+            if !status_matches {
+                return false;
+            }
+        }
+
+        self.pattern
+            .as_ref()
+            .is_none_or(|pattern| pattern.is_match(diagnostic))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn classify(status: &str, diagnostic: &str) -> Option<(String, String)> {
+        Classifier::new(None)
+            .unwrap()
+            .classify(status, diagnostic)
+            .map(|c| (c.category, c.sub_category))
+    }
+
+    fn assert_classified(status: &str, diagnostic: &str, category: &str, sub_category: &str) {
+        assert_eq!(
+            classify(status, diagnostic),
+            Some((category.to_string(), sub_category.to_string())),
+            "{} {}",
+            status,
+            diagnostic
+        );
+    }
+
+    #[test]
+    fn user_unknown() {
+        // Gmail
+        assert_classified(
+            "5.1.1",
+            "550 5.1.1 The email account that you tried to reach does not exist. Please try double-checking the recipient's email address for typos or unnecessary spaces.",
+            "hard",
+            "user_unknown",
+        );
+        // Exchange
+        assert_classified(
+            "5.1.1",
+            "550 5.1.1 RESOLVER.ADR.RecipNotFound; not found",
+            "hard",
+            "user_unknown",
+        );
+        // Postfix
+        assert_classified(
+            "5.1.1",
+            "550 5.1.1 <nobody@example.org>: Recipient address rejected: User unknown in virtual mailbox table",
+            "hard",
+            "user_unknown",
+        );
+    }
+
+    #[test]
+    fn mailbox_full() {
+        assert_classified(
+            "4.2.2",
+            "452 4.2.2 The email account that you tried to reach is over quota. Please direct the recipient to https://support.google.com/mail/?p=OverQuotaTemp",
+            "soft",
+            "mailbox_full",
+        );
+        // Permanent status, but a full mailbox may be emptied again
+        assert_classified("5.2.2", "552 5.2.2 Mailbox full", "soft", "mailbox_full");
+    }
+
+    #[test]
+    fn blocks() {
+        // Gmail
+        assert_classified(
+            "5.7.1",
+            "550 5.7.1 [192.0.2.1] Our system has detected that this message is likely unsolicited mail. To reduce the amount of spam sent to Gmail, this message has been blocked.",
+            "block",
+            "spam_block",
+        );
+        // Outlook.com
+        assert_classified(
+            "5.7.1",
+            "550 5.7.1 Unfortunately, messages from [192.0.2.1] weren't sent. Please contact your Internet service provider since part of their network is on our block list (S3150).",
+            "block",
+            "spam_block",
+        );
+        // Yahoo deferral, classified by its status
+        assert_classified(
+            "4.7.0",
+            "421 4.7.0 [TSS04] Messages from 192.0.2.1 temporarily deferred due to unexpected volume or user complaints",
+            "block",
+            "policy",
+        );
+        assert_classified(
+            "5.7.26",
+            "550 5.7.26 This mail has been blocked because the sender is unauthenticated. Gmail requires all senders to authenticate with either SPF or DKIM.",
+            "block",
+            "policy",
+        );
+    }
+
+    #[test]
+    fn dns_failure_category_follows_status_class() {
+        let diagnostic = "Host or domain name not found. Name service error for name=example.invalid type=A: Host not found";
+        assert_classified("5.4.4", diagnostic, "hard", "dns_failure");
+        assert_classified("4.4.4", diagnostic, "soft", "dns_failure");
+    }
+
+    #[test]
+    fn network() {
+        assert_classified(
+            "4.4.1",
+            "connect to mx.example.org[192.0.2.1]:25: Connection timed out",
+            "soft",
+            "network",
+        );
+    }
+
+    #[test]
+    fn diagnostic_patterns_take_precedence_over_status() {
+        // The status says unknown user, the text says the mailbox is full
+        assert_classified("5.1.1", "552 Mailbox is full", "soft", "mailbox_full");
+        assert_classified("5.7.1", "550 User unknown", "hard", "user_unknown");
+        // Earlier patterns win over later ones
+        assert_classified(
+            "5.1.1",
+            "Account disabled, the address does not exist anymore",
+            "hard",
+            "mailbox_disabled",
+        );
+        assert_classified(
+            "5.7.1",
+            "User unknown, sender listed on spamhaus",
+            "hard",
+            "user_unknown",
+        );
+    }
+
+    #[test]
+    fn status_rules() {
+        assert_classified("5.1.10", "", "hard", "dns_failure");
+        assert_classified("5.1.1", "", "hard", "user_unknown");
+        assert_classified("5.2.1", "", "hard", "mailbox_disabled");
+        assert_classified("5.3.4", "", "soft", "message_too_large");
+        assert_classified("4.4.7", "", "soft", "network");
+        assert_classified("5.0.0", "554 delivery error", "hard", "other");
+        assert_classified("4.0.0", "", "soft", "other");
+    }
+
+    #[test]
+    fn non_failures_are_not_classified() {
+        assert_eq!(classify("2.0.0", "250 OK"), None);
+        assert_eq!(classify("", "User unknown"), None);
+    }
+
+    #[test]
+    fn rules_file_precedes_builtin_rules() {
+        let path =
+            std::env::temp_dir().join(format!("bounce-relay-rules-{}.toml", std::process::id()));
+        fs::write(
+            &path,
+            r#"
+[[rules]]
+pattern = "mailbox full"
+category = "hard"
+sub_category = "abandoned_mailbox"
+
+[[rules]]
+status = "5.7.*"
+sub_category = "custom_policy"
+"#,
+        )
+        .unwrap();
+        let classifier = Classifier::new(Some(&path));
+        fs::remove_file(&path).unwrap();
+        let classifier = classifier.unwrap();
+
+        let classification = classifier.classify("5.2.2", "552 Mailbox full").unwrap();
+        assert_eq!(classification.category, "hard");
+        assert_eq!(classification.sub_category, "abandoned_mailbox");
+        let classification = classifier.classify("5.7.1", "User unknown").unwrap();
+        assert_eq!(classification.category, "hard");
+        assert_eq!(classification.sub_category, "custom_policy");
+        let classification = classifier.classify("4.2.2", "Over quota").unwrap();
+        assert_eq!(classification.sub_category, "mailbox_full");
+    }
+
+    #[test]
+    fn rule_needs_status_or_pattern() {
+        assert!(Rule::new(None, None, None, "empty".to_string()).is_err());
+        assert!(Rule::new(None, Some("("), None, "invalid".to_string()).is_err());
+    }
+}
@@ -1,19 +1,46 @@
 use crate::AppConfig;
 use anyhow::{Context, Result, bail};
 use sea_query::{
-    ColumnDef, Expr, ForeignKey, Iden, Index, MysqlQueryBuilder, PostgresQueryBuilder,
-    QueryBuilder, SchemaBuilder, SqliteQueryBuilder, Table,
+    Alias, Expr, Iden, InsertStatement, IntoColumnRef, IntoIden, MysqlQueryBuilder, OnConflict,
+    PostgresQueryBuilder, QueryBuilder, SchemaBuilder, SimpleExpr, SqliteQueryBuilder,
 };
-use sqlx::AnyConnection;
-use sqlx::Connection;
-use sqlx::any::install_default_drivers;
-use tracing::{debug, info};
+use sea_query_binder::SqlxBinder;
+use sqlx::any::{AnyTransactionManager, install_default_drivers};
+use sqlx::{AnyConnection, Connection, Row, TransactionManager};
+use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+use time::OffsetDateTime;
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tracing::{info, warn};
 
 pub struct DBConnection {
     pub connection: AnyConnection,
     pub wrap_timestamp: bool,
-    pub query_builder: Box<dyn QueryBuilder>,
-    pub schema_builder: Box<dyn SchemaBuilder>,
+    pub query_builder: Box<dyn QueryBuilder + Send + Sync>,
+    pub schema_builder: Box<dyn SchemaBuilder + Send + Sync>,
+}
+
+impl DBConnection {
+    /// Starts a transaction on the connection. Unlike `Connection::begin`, helpers
+    /// taking the `DBConnection` can run inside it. It has to be ended with `commit` or
+    /// `rollback`.
+    pub async fn begin(&mut self) -> Result<()> {
+        AnyTransactionManager::begin(&mut self.connection, None)
+            .await
+            .with_context(|| "Failed to start transaction")
+    }
+
+    pub async fn commit(&mut self) -> Result<()> {
+        AnyTransactionManager::commit(&mut self.connection)
+            .await
+            .with_context(|| "Failed to commit transaction")
+    }
+
+    pub async fn rollback(&mut self) -> Result<()> {
+        AnyTransactionManager::rollback(&mut self.connection)
+            .await
+            .with_context(|| "Failed to roll back transaction")
+    }
 }
 
 pub enum EmailRoute {
@@ -24,6 +51,22 @@ pub enum EmailRoute {
     Url,
     SecretToken,
     IsEnabled,
+    BatchRecipients,
+    EventTypes,
+    TagPattern,
+    StopProcessing,
+    MatchRules,
+    MaxConcurrency,
+    OrderedDelivery,
+    RetryBaseDelaySeconds,
+    RetryMultiplier,
+    RetryMaxDelaySeconds,
+    RetryStatusCodes,
+    PermanentStatusCodes,
+    DisableAfterGone,
+    ConsecutiveGone,
+    NotifyUrl,
+    NotifyEmail,
 }
 impl Iden for EmailRoute {
     fn unquoted(&self, s: &mut dyn std::fmt::Write) {
@@ -38,6 +81,22 @@ impl Iden for EmailRoute {
                 Self::Url => "url",
                 Self::SecretToken => "secret_token",
                 Self::IsEnabled => "is_enabled",
+                Self::BatchRecipients => "batch_recipients",
+                Self::EventTypes => "event_types",
+                Self::TagPattern => "tag_pattern",
+                Self::StopProcessing => "stop_processing",
+                Self::MatchRules => "match_rules",
+                Self::MaxConcurrency => "max_concurrency",
+                Self::OrderedDelivery => "ordered_delivery",
+                Self::RetryBaseDelaySeconds => "retry_base_delay_seconds",
+                Self::RetryMultiplier => "retry_multiplier",
+                Self::RetryMaxDelaySeconds => "retry_max_delay_seconds",
+                Self::RetryStatusCodes => "retry_status_codes",
+                Self::PermanentStatusCodes => "permanent_status_codes",
+                Self::DisableAfterGone => "disable_after_gone",
+                Self::ConsecutiveGone => "consecutive_gone",
+                Self::NotifyUrl => "notify_url",
+                Self::NotifyEmail => "notify_email",
             }
         )
         .unwrap();
@@ -54,6 +113,11 @@ pub enum WebhookQueue {
     LastError,
     IsExpired,
     CreatedAt,
+    BounceEventId,
+    LockedBy,
+    LockedUntil,
+    LastStatusCode,
+    AttemptLog,
 }
 impl Iden for WebhookQueue {
     fn unquoted(&self, s: &mut dyn std::fmt::Write) {
@@ -70,12 +134,229 @@ impl Iden for WebhookQueue {
                 Self::LastError => "last_error",
                 Self::IsExpired => "is_expired",
                 Self::CreatedAt => "created_at",
+                Self::BounceEventId => "bounce_event_id",
+                Self::LockedBy => "locked_by",
+                Self::LockedUntil => "locked_until",
+                Self::LastStatusCode => "last_status_code",
+                Self::AttemptLog => "attempt_log",
+            }
+        )
+        .unwrap();
+    }
+}
+
+pub enum DeadLetter {
+    Table,
+    Id,
+    WebhookId,
+    EmailRouteId,
+    BounceEventId,
+    Payload,
+    Attempts,
+    AttemptLog,
+    LastError,
+    LastStatusCode,
+    Reason,
+    CreatedAt,
+    DeadLetteredAt,
+}
+impl Iden for DeadLetter {
+    fn unquoted(&self, s: &mut dyn std::fmt::Write) {
+        write!(
+            s,
+            "{}",
+            match self {
+                Self::Table => "webhook_dead_letters",
+                Self::Id => "id",
+                Self::WebhookId => "webhook_id",
+                Self::EmailRouteId => "email_route_id",
+                Self::BounceEventId => "bounce_event_id",
+                Self::Payload => "payload",
+                Self::Attempts => "attempts",
+                Self::AttemptLog => "attempt_log",
+                Self::LastError => "last_error",
+                Self::LastStatusCode => "last_status_code",
+                Self::Reason => "reason",
+                Self::CreatedAt => "created_at",
+                Self::DeadLetteredAt => "dead_lettered_at",
+            }
+        )
+        .unwrap();
+    }
+}
+
+pub enum BounceEvent {
+    Table,
+    Id,
+    EventType,
+    Domain,
+    Recipient,
+    Status,
+    Category,
+    SubCategory,
+    Reason,
+    MessageId,
+    RawMessage,
+    RawMessageEventId,
+    ReceivedAt,
+}
+impl Iden for BounceEvent {
+    fn unquoted(&self, s: &mut dyn std::fmt::Write) {
+        write!(
+            s,
+            "{}",
+            match self {
+                Self::Table => "bounce_events",
+                Self::Id => "id",
+                Self::EventType => "event_type",
+                Self::Domain => "domain",
+                Self::Recipient => "recipient",
+                Self::Status => "status",
+                Self::Category => "category",
+                Self::SubCategory => "sub_category",
+                Self::Reason => "reason",
+                Self::MessageId => "message_id",
+                Self::RawMessage => "raw_message",
+                Self::RawMessageEventId => "raw_message_event_id",
+                Self::ReceivedAt => "received_at",
+            }
+        )
+        .unwrap();
+    }
+}
+
+pub enum Suppression {
+    Table,
+    Id,
+    Domain,
+    Email,
+    Reason,
+    Description,
+    BounceEventId,
+    CreatedAt,
+}
+impl Iden for Suppression {
+    fn unquoted(&self, s: &mut dyn std::fmt::Write) {
+        write!(
+            s,
+            "{}",
+            match self {
+                Self::Table => "suppressions",
+                Self::Id => "id",
+                Self::Domain => "domain",
+                Self::Email => "email",
+                Self::Reason => "reason",
+                Self::Description => "description",
+                Self::BounceEventId => "bounce_event_id",
+                Self::CreatedAt => "created_at",
+            }
+        )
+        .unwrap();
+    }
+}
+
+pub enum SchemaMigration {
+    Table,
+    Version,
+    Name,
+    AppliedAt,
+}
+impl Iden for SchemaMigration {
+    fn unquoted(&self, s: &mut dyn std::fmt::Write) {
+        write!(
+            s,
+            "{}",
+            match self {
+                Self::Table => "schema_migrations",
+                Self::Version => "version",
+                Self::Name => "name",
+                Self::AppliedAt => "applied_at",
             }
         )
         .unwrap();
     }
 }
 
+/// Selects a boolean column as integer (1 or 0), since the Any driver cannot decode
+/// SQLite booleans
+pub fn bool_as_int<T: IntoColumnRef>(column: T) -> SimpleExpr {
+    Expr::case(Expr::col(column).eq(true), 1).finally(0).into()
+}
+
+/// Wraps a timestamp for use in queries, PostgreSQL needs an explicit cast
+pub fn timestamp_value(db: &DBConnection, value: OffsetDateTime) -> SimpleExpr {
+    if db.wrap_timestamp {
+        Expr::val(value).cast_as(Alias::new("timestamp"))
+    } else {
+        value.into()
+    }
+}
+
+/// Selects a timestamp column as text, since the Any driver cannot decode timestamps
+pub fn timestamp_as_text<T: IntoColumnRef>(db: &DBConnection, column: T) -> SimpleExpr {
+    let text_type = if db.connection.backend_name() == "MySQL" {
+        "char"
+    } else {
+        "text"
+    };
+    Expr::col(column).cast_as(Alias::new(text_type))
+}
+
+/// Executes the insert statement and returns the id of the new row. MySQL does not
+/// support RETURNING, so its last insert id is used instead.
+pub async fn insert_returning_id<T: IntoIden>(
+    db: &mut DBConnection,
+    mut statement: InsertStatement,
+    id_column: T,
+) -> Result<i64> {
+    let id_column = id_column.into_iden();
+    let (sql, values) = statement
+        .returning_col(id_column.clone())
+        .build_any_sqlx(&*db.query_builder);
+
+    if db.connection.backend_name() == "MySQL" {
+        let result = sqlx::query_with(&sql, values)
+            .execute(&mut db.connection)
+            .await?;
+        return result
+            .last_insert_id()
+            .with_context(|| "Database did not report the inserted id");
+    }
+
+    let row = sqlx::query_with(&sql, values)
+        .fetch_one(&mut db.connection)
+        .await?;
+    Ok(row.try_get(id_column.to_string().as_str())?)
+}
+
+/// Executes the insert statement unless a row with the same `unique_columns` exists
+/// already. Returns whether the row was inserted.
+pub async fn insert_if_absent<C, I>(
+    db: &mut DBConnection,
+    mut statement: InsertStatement,
+    unique_columns: I,
+) -> Result<bool>
+where
+    C: IntoIden,
+    I: IntoIterator<Item = C>,
+{
+    let (sql, values) = if db.connection.backend_name() == "MySQL" {
+        // sea-query has no INSERT IGNORE, and its ON DUPLICATE KEY UPDATE polyfill reports
+        // the existing row as affected
+        let (sql, values) = statement.build_any_sqlx(&*db.query_builder);
+        (sql.replacen("INSERT", "INSERT IGNORE", 1), values)
+    } else {
+        statement
+            .on_conflict(OnConflict::columns(unique_columns).do_nothing().to_owned())
+            .build_any_sqlx(&*db.query_builder)
+    };
+
+    let result = sqlx::query_with(&sql, values)
+        .execute(&mut db.connection)
+        .await?;
+    Ok(result.rows_affected() > 0)
+}
+
 pub async fn connect_database(config: &AppConfig) -> Result<DBConnection> {
     install_default_drivers();
 
@@ -109,154 +390,87 @@ pub async fn connect_database(config: &AppConfig) -> Result<DBConnection> {
     })
 }
 
-pub async fn initialize_database(mut db: DBConnection, print_only: bool) -> Result<()> {
-    let schema_builder = &*db.schema_builder;
+/// In-memory SQLite database with all migrations applied, for tests
+#[cfg(test)]
+pub async fn connect_test_database() -> DBConnection {
+    let mut db = connect_database(&crate::test_config()).await.unwrap();
+    crate::migrate::execute_migrate_up(&mut db, false)
+        .await
+        .unwrap();
+    db
+}
 
-    if !print_only {
-        info!("Creating email_routes table");
-    }
-    let email_routes = Table::create()
-        .table(EmailRoute::Table)
-        .if_not_exists()
-        .col(
-            ColumnDef::new(EmailRoute::Id)
-                .integer()
-                .not_null()
-                .auto_increment()
-                .primary_key(),
-        )
-        .col(ColumnDef::new(EmailRoute::Domain).string().not_null())
-        .col(ColumnDef::new(EmailRoute::User).string().null())
-        .col(ColumnDef::new(EmailRoute::Url).string().not_null())
-        .col(ColumnDef::new(EmailRoute::SecretToken).string().not_null())
-        .col(
-            ColumnDef::new(EmailRoute::IsEnabled)
-                .boolean()
-                .not_null()
-                .default(true),
-        )
-        .build_any(schema_builder);
-    if print_only {
-        println!("{};", email_routes);
-    } else {
-        sqlx::query(&email_routes)
-            .execute(&mut db.connection)
-            .await?;
-    }
+/// Connections of a server handling sessions concurrently. Connections are opened on
+/// demand, up to the size of the pool, and reused afterwards.
+pub struct DBPool {
+    idle: Mutex<Vec<DBConnection>>,
+    permits: Semaphore,
+}
 
-    if !print_only {
-        debug!("Creating index idx_route_lookup");
-    }
-    let email_routes_index = Index::create()
-        .name("idx_route_lookup")
-        .if_not_exists()
-        .table(EmailRoute::Table)
-        .col(EmailRoute::Domain)
-        .col(EmailRoute::User)
-        .col(EmailRoute::IsEnabled)
-        .build_any(schema_builder);
-    if print_only {
-        println!("{};", email_routes_index);
-    } else {
-        sqlx::query(&email_routes_index)
-            .execute(&mut db.connection)
-            .await?;
-    }
+/// Connection taken from a `DBPool`, returned to it when dropped
+pub struct PooledConnection<'a> {
+    db: Option<DBConnection>,
+    pool: &'a DBPool,
+    _permit: SemaphorePermit<'a>,
+}
 
-    if !print_only {
-        debug!("Creating index idx_route_enabled_lookup");
-    }
-    let email_routes_enabled_index = Index::create()
-        .name("idx_route_enabled_lookup")
-        .if_not_exists()
-        .table(EmailRoute::Table)
-        .col(EmailRoute::IsEnabled)
-        .build_any(schema_builder);
-    if print_only {
-        println!("{};", email_routes_enabled_index);
-    } else {
-        sqlx::query(&email_routes_enabled_index)
-            .execute(&mut db.connection)
-            .await?;
+impl DBPool {
+    pub fn new(db: DBConnection, size: usize) -> Self {
+        Self {
+            idle: Mutex::new(vec![db]),
+            permits: Semaphore::new(size.max(1)),
+        }
     }
 
-    if !print_only {
-        info!("Creating webhook_queue table");
+    /// Waits for a free connection, reconnecting it if it was lost
+    pub async fn acquire(&self, config: &AppConfig) -> Result<PooledConnection<'_>> {
+        let permit = self.permits.acquire().await?;
+        let idle = self.idle.lock().expect("pool lock poisoned").pop();
+        let db = match idle {
+            Some(mut db) => {
+                reconnect_if_needed(config, &mut db).await?;
+                db
+            }
+            None => connect_database(config).await?,
+        };
+        Ok(PooledConnection {
+            db: Some(db),
+            pool: self,
+            _permit: permit,
+        })
     }
-    let webhook_queue = Table::create()
-        .table(WebhookQueue::Table)
-        .if_not_exists()
-        .col(
-            ColumnDef::new(WebhookQueue::Id)
-                .integer()
-                .not_null()
-                .auto_increment()
-                .primary_key(),
-        )
-        .col(
-            ColumnDef::new(WebhookQueue::EmailRouteId)
-                .integer()
-                .not_null(),
-        )
-        .col(ColumnDef::new(WebhookQueue::Payload).text().not_null())
-        .col(
-            ColumnDef::new(WebhookQueue::Attempts)
-                .unsigned()
-                .integer()
-                .not_null()
-                .default(0),
-        )
-        .col(
-            ColumnDef::new(WebhookQueue::NextRetryAt)
-                .timestamp_with_time_zone()
-                .not_null()
-                .default(Expr::current_timestamp()),
-        )
-        .col(ColumnDef::new(WebhookQueue::LastError).text().null())
-        .col(
-            ColumnDef::new(WebhookQueue::IsExpired)
-                .boolean()
-                .not_null()
-                .default(false),
-        )
-        .col(
-            ColumnDef::new(WebhookQueue::CreatedAt)
-                .timestamp_with_time_zone()
-                .not_null()
-                .default(Expr::current_timestamp()),
-        )
-        .foreign_key(
-            ForeignKey::create()
-                .name("fk_queue_to_route")
-                .from(WebhookQueue::Table, WebhookQueue::EmailRouteId)
-                .to(EmailRoute::Table, EmailRoute::Id),
-        )
-        .build_any(schema_builder);
-    if print_only {
-        println!("{};", webhook_queue);
-    } else {
-        sqlx::query(&webhook_queue)
-            .execute(&mut db.connection)
-            .await?;
+}
+
+impl Deref for PooledConnection<'_> {
+    type Target = DBConnection;
+
+    fn deref(&self) -> &DBConnection {
+        self.db.as_ref().expect("connection taken")
     }
+}
 
-    if !print_only {
-        debug!("Creating index idx_queue_processing");
+impl DerefMut for PooledConnection<'_> {
+    fn deref_mut(&mut self) -> &mut DBConnection {
+        self.db.as_mut().expect("connection taken")
     }
-    let webhooks_queue_index = Index::create()
-        .name("idx_queue_processing")
-        .if_not_exists()
-        .table(WebhookQueue::Table)
-        .col(WebhookQueue::NextRetryAt)
-        .col(WebhookQueue::IsExpired)
-        .build_any(schema_builder);
-    if print_only {
-        println!("{};", webhooks_queue_index);
-    } else {
-        sqlx::query(&webhooks_queue_index)
-            .execute(&mut db.connection)
-            .await?;
+}
+
+impl Drop for PooledConnection<'_> {
+    fn drop(&mut self) {
+        // A connection left inside a transaction is closed rather than reused
+        if let Some(db) = self.db.take()
+            && AnyTransactionManager::get_transaction_depth(&db.connection) == 0
+        {
+            self.pool.idle.lock().expect("pool lock poisoned").push(db);
+        }
     }
+}
 
+/// Replaces the connection of long running servers if it was lost
+pub async fn reconnect_if_needed(config: &AppConfig, db: &mut DBConnection) -> Result<()> {
+    if db.connection.ping().await.is_err() {
+        warn!("Database connection lost, reconnecting");
+        *db = connect_database(config).await?;
+    }
     Ok(())
 }
@@ -0,0 +1,633 @@
+use crate::AppConfig;
+use crate::db::{DBConnection, DeadLetter, WebhookQueue, timestamp_as_text, timestamp_value};
+use crate::output::{OutputFormat, print_table};
+use crate::queue::{parse_age, parse_attempt_log, print_attempt_log, summarize_error};
+use crate::worker::signed_request;
+use anyhow::{Context, Result, bail};
+use clap::{Args, ValueEnum};
+use reqwest::Client;
+use sea_query::{Cond, Expr, Order, Query};
+use sea_query_binder::SqlxBinder;
+use sqlx::{Connection, Row};
+use std::process::Stdio;
+use time::{Duration, OffsetDateTime};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tracing::{info, warn};
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum DeadLetterReason {
+    /// Failed worker_max_retries times
+    MaxRetries,
+    /// Failed with a response that is not retried
+    PermanentFailure,
+}
+
+impl DeadLetterReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeadLetterReason::MaxRetries => "max_retries",
+            DeadLetterReason::PermanentFailure => "permanent_failure",
+        }
+    }
+}
+
+/// Selects dead letters by id, or all matching the filters with `--all`
+#[derive(Debug, Args)]
+pub struct DeadLetterSelection {
+    /// Dead letter IDs
+    #[arg(required_unless_present = "all", conflicts_with = "all")]
+    ids: Vec<i32>,
+    /// All dead letters matching the filters
+    #[arg(long)]
+    all: bool,
+    #[command(flatten)]
+    filter: DeadLetterFilter,
+}
+
+/// Filters shared by the dead-letter subcommands
+#[derive(Debug, Args)]
+pub struct DeadLetterFilter {
+    /// Only dead letters of this route
+    #[arg(long, value_name = "ID")]
+    route: Option<i32>,
+    /// Only dead letters given up on longer ago than this (e.g. 30m, 12h, 7d)
+    #[arg(long, value_name = "AGE", value_parser = parse_age)]
+    older_than: Option<Duration>,
+    /// Only dead letters given up on for this reason
+    #[arg(long, value_enum)]
+    reason: Option<DeadLetterReason>,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct DeadLetterEntry {
+    id: i32,
+    webhook_id: i32,
+    email_route_id: i32,
+    bounce_event_id: Option<i32>,
+    payload: String,
+    attempts: i32,
+    attempt_log: Option<String>,
+    last_error: Option<String>,
+    last_status_code: Option<i32>,
+    reason: String,
+    created_at: String,
+    dead_lettered_at: String,
+}
+
+impl DeadLetterEntry {
+    fn event(&self) -> String {
+        serde_json::from_str::<serde_json::Value>(&self.payload)
+            .ok()
+            .and_then(|payload| payload["event"].as_str().map(str::to_string))
+            .unwrap_or_default()
+    }
+
+    fn to_json(&self, with_payload: bool) -> serde_json::Value {
+        let mut json = serde_json::json!({
+            "id": self.id,
+            "webhook_id": self.webhook_id,
+            "route_id": self.email_route_id,
+            "event": self.event(),
+            "reason": self.reason,
+            "attempts": self.attempts,
+            "last_status_code": self.last_status_code,
+            "last_error": self.last_error,
+            "created_at": self.created_at,
+            "dead_lettered_at": self.dead_lettered_at,
+            "bounce_event_id": self.bounce_event_id,
+        });
+        if with_payload {
+            json["attempt_log"] = serde_json::json!(parse_attempt_log(self.attempt_log.as_deref()));
+            json["payload"] = serde_json::from_str(&self.payload)
+                .unwrap_or_else(|_| self.payload.as_str().into());
+        }
+        json
+    }
+}
+
+/// A webhook the worker gave up on, reported to the owner of its route
+#[derive(Debug)]
+pub struct DeadLetterNotice {
+    pub dead_letter_id: i32,
+    pub webhook_id: i32,
+    pub reason: DeadLetterReason,
+    pub attempts: i32,
+    pub last_status_code: Option<u16>,
+    pub last_error: String,
+}
+
+/// Where the owner of a route wants to hear about dead letters
+#[derive(Debug)]
+pub struct NotifyTarget<'a> {
+    pub route_id: i32,
+    pub url: &'a str,
+    pub secret_token: &'a str,
+    pub notify_url: Option<&'a str>,
+    pub notify_email: Option<&'a str>,
+}
+
+pub async fn execute_dead_letter_list(
+    db: &mut DBConnection,
+    filter: &DeadLetterFilter,
+    limit: u64,
+    format: OutputFormat,
+) -> Result<()> {
+    let condition = filter_condition(db, filter);
+    let entries = load_entries(db, condition, Some(limit)).await?;
+
+    match format {
+        OutputFormat::Json => {
+            let entries = entries
+                .iter()
+                .map(|entry| entry.to_json(false))
+                .collect::<Vec<_>>();
+            println!("{}", serde_json::to_string_pretty(&entries)?);
+        }
+        OutputFormat::Table => {
+            let rows = entries
+                .iter()
+                .map(|entry| {
+                    vec![
+                        entry.id.to_string(),
+                        entry.email_route_id.to_string(),
+                        entry.event(),
+                        entry.reason.clone(),
+                        entry.attempts.to_string(),
+                        entry.dead_lettered_at.clone(),
+                        summarize_error(entry.last_error.as_deref()),
+                    ]
+                })
+                .collect::<Vec<_>>();
+            print_table(
+                &[
+                    "ID",
+                    "ROUTE",
+                    "EVENT",
+                    "REASON",
+                    "ATTEMPTS",
+                    "DEAD LETTERED AT",
+                    "LAST ERROR",
+                ],
+                &rows,
+            );
+        }
+    }
+    Ok(())
+}
+
+pub async fn execute_dead_letter_show(
+    db: &mut DBConnection,
+    id: i32,
+    format: OutputFormat,
+) -> Result<()> {
+    let entry = load_entries(db, Cond::all().add(Expr::col(DeadLetter::Id).eq(id)), None)
+        .await?
+        .pop()
+        .with_context(|| format!("Dead letter {} not found", id))?;
+
+    match format {
+        OutputFormat::Json => {
+            println!("{}", serde_json::to_string_pretty(&entry.to_json(true))?);
+        }
+        OutputFormat::Table => {
+            let fields = [
+                ("ID", entry.id.to_string()),
+                ("Webhook", entry.webhook_id.to_string()),
+                ("Route", entry.email_route_id.to_string()),
+                ("Event", entry.event()),
+                ("Reason", entry.reason.clone()),
+                ("Attempts", entry.attempts.to_string()),
+                ("Created at", entry.created_at.clone()),
+                ("Dead lettered", entry.dead_lettered_at.clone()),
+                (
+                    "Bounce event",
+                    entry
+                        .bounce_event_id
+                        .map(|id| id.to_string())
+                        .unwrap_or_default(),
+                ),
+                (
+                    "Last status",
+                    entry
+                        .last_status_code
+                        .map(|code| code.to_string())
+                        .unwrap_or_default(),
+                ),
+                ("Last error", entry.last_error.clone().unwrap_or_default()),
+            ];
+            for (name, value) in fields {
+                println!("{:<14} {}", format!("{}:", name), value);
+            }
+            print_attempt_log(entry.attempt_log.as_deref());
+            let payload = serde_json::from_str::<serde_json::Value>(&entry.payload)
+                .and_then(|payload| serde_json::to_string_pretty(&payload))
+                .unwrap_or(entry.payload);
+            println!("Payload:\n{}", payload);
+        }
+    }
+    Ok(())
+}
+
+/// Moves dead letters back into the queue for delivery on the next worker iteration,
+/// e.g. after the receiver was fixed
+pub async fn execute_dead_letter_redrive(
+    db: &mut DBConnection,
+    selection: &DeadLetterSelection,
+) -> Result<()> {
+    let ids = select_ids(db, selection).await?;
+    if ids.is_empty() {
+        info!(count = 0, "Re-drove dead letters");
+        return Ok(());
+    }
+
+    let query_builder = &*db.query_builder;
+    let (insert_sql, insert_values) = Query::insert()
+        .into_table(WebhookQueue::Table)
+        .columns([
+            WebhookQueue::EmailRouteId,
+            WebhookQueue::BounceEventId,
+            WebhookQueue::Payload,
+            WebhookQueue::CreatedAt,
+        ])
+        .select_from(
+            Query::select()
+                .columns([
+                    DeadLetter::EmailRouteId,
+                    DeadLetter::BounceEventId,
+                    DeadLetter::Payload,
+                    DeadLetter::CreatedAt,
+                ])
+                .from(DeadLetter::Table)
+                .and_where(Expr::col(DeadLetter::Id).is_in(ids.iter().copied()))
+                .order_by(DeadLetter::Id, Order::Asc)
+                .to_owned(),
+        )?
+        .build_any_sqlx(query_builder);
+    let (delete_sql, delete_values) = Query::delete()
+        .from_table(DeadLetter::Table)
+        .and_where(Expr::col(DeadLetter::Id).is_in(ids.iter().copied()))
+        .build_any_sqlx(query_builder);
+
+    let mut transaction = db.connection.begin().await?;
+    let count = sqlx::query_with(&insert_sql, insert_values)
+        .execute(&mut *transaction)
+        .await
+        .with_context(|| "Failed to queue dead letters")?
+        .rows_affected();
+    sqlx::query_with(&delete_sql, delete_values)
+        .execute(&mut *transaction)
+        .await
+        .with_context(|| "Failed to delete dead letters")?;
+    transaction.commit().await?;
+
+    if selection.all {
+        info!(count = count, "Re-drove dead letters");
+    } else if count < ids.len() as u64 {
+        bail!(
+            "Re-drove {} of {} dead letters, the others do not exist",
+            count,
+            ids.len()
+        );
+    } else {
+        info!(count = count, "Re-drove dead letters");
+    }
+    Ok(())
+}
+
+pub async fn execute_dead_letter_purge(
+    db: &mut DBConnection,
+    selection: &DeadLetterSelection,
+) -> Result<()> {
+    let ids = select_ids(db, selection).await?;
+    let query_builder = &*db.query_builder;
+    let (sql, values) = Query::delete()
+        .from_table(DeadLetter::Table)
+        .and_where(Expr::col(DeadLetter::Id).is_in(ids.iter().copied()))
+        .build_any_sqlx(query_builder);
+    let count = sqlx::query_with(&sql, values)
+        .execute(&mut db.connection)
+        .await
+        .with_context(|| "Failed to delete dead letters")?
+        .rows_affected();
+
+    if !selection.all && count < ids.len() as u64 {
+        bail!(
+            "Purged {} of {} dead letters, the others do not exist",
+            count,
+            ids.len()
+        );
+    }
+    info!(count = count, "Purged dead letters");
+    Ok(())
+}
+
+/// Tells the owner of a route about webhooks given up on in this worker iteration, by
+/// a signed webhook to its notify URL and an email to its notify address. Failures are
+/// only logged, the dead letters are kept either way.
+pub async fn notify_dead_letters(
+    config: &AppConfig,
+    client: &Client,
+    target: &NotifyTarget<'_>,
+    notices: &[DeadLetterNotice],
+) {
+    if let Some(notify_url) = target.notify_url {
+        let payload = serde_json::json!({
+            "event": "dead_letter",
+            "route_id": target.route_id,
+            "url": target.url,
+            "dead_letters": notices
+                .iter()
+                .map(|notice| serde_json::json!({
+                    "id": notice.dead_letter_id,
+                    "webhook_id": notice.webhook_id,
+                    "reason": notice.reason.as_str(),
+                    "attempts": notice.attempts,
+                    "last_status_code": notice.last_status_code,
+                    "last_error": notice.last_error,
+                }))
+                .collect::<Vec<_>>(),
+        });
+        let result = async {
+            let response = signed_request(
+                client,
+                notify_url,
+                target.secret_token,
+                &payload.to_string(),
+            )?
+            .send()
+            .await?;
+            response.error_for_status()?;
+            anyhow::Ok(())
+        }
+        .await;
+        if let Err(e) = result {
+            warn!(
+                route_id = target.route_id,
+                url = notify_url,
+                error = format!("{:#}", e),
+                "Failed to send dead letter notification"
+            );
+        }
+    }
+
+    if let Some(notify_email) = target.notify_email
+        && let Err(e) = send_email(config, target, notify_email, notices).await
+    {
+        warn!(
+            route_id = target.route_id,
+            email = notify_email,
+            error = format!("{:#}", e),
+            "Failed to email dead letter notification"
+        );
+    }
+}
+
+/// Hands the notification to the local MTA, which delivers it like any other mail
+async fn send_email(
+    config: &AppConfig,
+    target: &NotifyTarget<'_>,
+    notify_email: &str,
+    notices: &[DeadLetterNotice],
+) -> Result<()> {
+    let mut message = String::new();
+    if let Some(from) = &config.notification_from {
+        message.push_str(&format!("From: {}\n", from));
+    }
+    message.push_str(&format!("To: {}\n", notify_email));
+    message.push_str(&format!(
+        "Subject: Webhooks of route {} were given up on\n",
+        target.route_id
+    ));
+    message.push_str("Auto-Submitted: auto-generated\n");
+    message.push_str("MIME-Version: 1.0\n");
+    message.push_str("Content-Type: text/plain; charset=utf-8\n");
+    message.push_str("Content-Transfer-Encoding: 8bit\n\n");
+    message.push_str(&format!(
+        "Delivering the following webhooks of route {} to {} failed, they were moved\n\
+         to the dead letters:\n\n",
+        target.route_id, target.url
+    ));
+    for notice in notices {
+        message.push_str(&format!(
+            "Dead letter {} (webhook {}): {} after {} attempts\n  {}\n",
+            notice.dead_letter_id,
+            notice.webhook_id,
+            notice.reason.as_str(),
+            notice.attempts,
+            notice.last_error.lines().next().unwrap_or_default()
+        ));
+    }
+    message.push_str(
+        "\nInspect them with `bounce-relay dead-letter show <id>` and deliver them again\n\
+         with `bounce-relay dead-letter redrive <id>...` once the receiver is fixed.\n",
+    );
+
+    let mut child = Command::new(&config.notification_sendmail_path)
+        .args(["-i", "--", notify_email])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| {
+            format!(
+                "Failed to run {}",
+                config.notification_sendmail_path.display()
+            )
+        })?;
+    let mut stdin = child.stdin.take().context("sendmail has no stdin")?;
+    stdin.write_all(message.as_bytes()).await?;
+    drop(stdin);
+
+    let output = child.wait_with_output().await?;
+    if !output.status.success() {
+        bail!(
+            "sendmail failed with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+/// Resolves the selection to dead letter ids up front, so dead letters added meanwhile
+/// are left alone
+async fn select_ids(db: &mut DBConnection, selection: &DeadLetterSelection) -> Result<Vec<i32>> {
+    if !selection.all {
+        return Ok(selection.ids.clone());
+    }
+    let condition = filter_condition(db, &selection.filter);
+    let query_builder = &*db.query_builder;
+    let (sql, values) = Query::select()
+        .column(DeadLetter::Id)
+        .from(DeadLetter::Table)
+        .cond_where(condition)
+        .build_any_sqlx(query_builder);
+    sqlx::query_with(&sql, values)
+        .fetch_all(&mut db.connection)
+        .await
+        .with_context(|| "Failed to load dead letters")?
+        .iter()
+        .map(|row| Ok(row.try_get::<i32, _>(0)?))
+        .collect()
+}
+
+fn filter_condition(db: &DBConnection, filter: &DeadLetterFilter) -> Cond {
+    let mut condition = Cond::all();
+    if let Some(route) = filter.route {
+        condition = condition.add(Expr::col(DeadLetter::EmailRouteId).eq(route));
+    }
+    if let Some(age) = filter.older_than {
+        let before = OffsetDateTime::now_utc() - age;
+        condition =
+            condition.add(Expr::col(DeadLetter::DeadLetteredAt).lt(timestamp_value(db, before)));
+    }
+    if let Some(reason) = filter.reason {
+        condition = condition.add(Expr::col(DeadLetter::Reason).eq(reason.as_str()));
+    }
+    condition
+}
+
+async fn load_entries(
+    db: &mut DBConnection,
+    condition: Cond,
+    limit: Option<u64>,
+) -> Result<Vec<DeadLetterEntry>> {
+    let query_builder = &*db.query_builder;
+    let mut query = Query::select();
+    query
+        .columns([
+            DeadLetter::Id,
+            DeadLetter::WebhookId,
+            DeadLetter::EmailRouteId,
+            DeadLetter::BounceEventId,
+            DeadLetter::Payload,
+            DeadLetter::Attempts,
+            DeadLetter::AttemptLog,
+            DeadLetter::LastError,
+            DeadLetter::LastStatusCode,
+            DeadLetter::Reason,
+        ])
+        .expr_as(
+            timestamp_as_text(db, DeadLetter::CreatedAt),
+            DeadLetter::CreatedAt,
+        )
+        .expr_as(
+            timestamp_as_text(db, DeadLetter::DeadLetteredAt),
+            DeadLetter::DeadLetteredAt,
+        )
+        .from(DeadLetter::Table)
+        .cond_where(condition)
+        .order_by(DeadLetter::Id, Order::Asc);
+    if let Some(limit) = limit {
+        query.limit(limit);
+    }
+    let (sql, values) = query.build_any_sqlx(query_builder);
+
+    sqlx::query_as_with(&sql, values)
+        .fetch_all(&mut db.connection)
+        .await
+        .with_context(|| "Failed to load dead letters")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{EmailRoute, connect_test_database};
+
+    fn selection(ids: &[i32]) -> DeadLetterSelection {
+        DeadLetterSelection {
+            ids: ids.to_vec(),
+            all: false,
+            filter: DeadLetterFilter {
+                route: None,
+                older_than: None,
+                reason: None,
+            },
+        }
+    }
+
+    async fn dead_letter_test_database() -> DBConnection {
+        let mut db = connect_test_database().await;
+        let (sql, values) = Query::insert()
+            .into_table(EmailRoute::Table)
+            .columns([EmailRoute::Domain, EmailRoute::Url, EmailRoute::SecretToken])
+            .values_panic([
+                "example.com".into(),
+                "https://hooks.example.com/".into(),
+                "secret".into(),
+            ])
+            .build_any_sqlx(&*db.query_builder);
+        sqlx::query_with(&sql, values)
+            .execute(&mut db.connection)
+            .await
+            .unwrap();
+        let (sql, values) = Query::insert()
+            .into_table(DeadLetter::Table)
+            .columns([
+                DeadLetter::WebhookId,
+                DeadLetter::EmailRouteId,
+                DeadLetter::Payload,
+                DeadLetter::Attempts,
+                DeadLetter::LastError,
+                DeadLetter::Reason,
+                DeadLetter::CreatedAt,
+            ])
+            .values_panic([
+                7.into(),
+                1.into(),
+                r#"{"event":"bounce"}"#.into(),
+                5.into(),
+                "HTTP 500".into(),
+                DeadLetterReason::MaxRetries.as_str().into(),
+                Expr::current_timestamp().into(),
+            ])
+            .build_any_sqlx(&*db.query_builder);
+        sqlx::query_with(&sql, values)
+            .execute(&mut db.connection)
+            .await
+            .unwrap();
+        db
+    }
+
+    async fn queued_webhooks(db: &mut DBConnection) -> Vec<(i32, String, i32)> {
+        let (sql, values) = Query::select()
+            .columns([
+                WebhookQueue::EmailRouteId,
+                WebhookQueue::Payload,
+                WebhookQueue::Attempts,
+            ])
+            .from(WebhookQueue::Table)
+            .build_any_sqlx(&*db.query_builder);
+        sqlx::query_as_with(&sql, values)
+            .fetch_all(&mut db.connection)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn redrive_moves_dead_letters_to_the_queue() {
+        let mut db = dead_letter_test_database().await;
+        execute_dead_letter_redrive(&mut db, &selection(&[1]))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            queued_webhooks(&mut db).await,
+            [(1, r#"{"event":"bounce"}"#.to_string(), 0)]
+        );
+        let dead_letters = load_entries(&mut db, Cond::all(), None).await.unwrap();
+        assert!(dead_letters.is_empty());
+    }
+
+    #[tokio::test]
+    async fn redrive_reports_missing_dead_letters() {
+        let mut db = dead_letter_test_database().await;
+        assert!(
+            execute_dead_letter_redrive(&mut db, &selection(&[1, 2]))
+                .await
+                .is_err()
+        );
+        assert_eq!(queued_webhooks(&mut db).await.len(), 1);
+    }
+}
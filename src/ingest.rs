@@ -1,35 +1,170 @@
 use crate::AppConfig;
-use crate::db::{DBConnection, EmailRoute, WebhookQueue};
+use crate::classify::{Classification, Classifier};
+use crate::db::{
+    BounceEvent, DBConnection, EmailRoute, WebhookQueue, bool_as_int, connect_database,
+    insert_returning_id,
+};
+use crate::match_rules::MatchRules;
+use crate::migrate::ensure_schema_current;
+use crate::ndr::parse_ndr;
+use crate::spool::spool_message;
+use crate::suppression::{SuppressionReason, count_soft_bounces, suppress};
 use anyhow::{Context, Result};
+use flate2::Compression;
+use flate2::write::GzEncoder;
 use mail_parser::{Message, MessageParser, MimeHeaders, PartType};
-use sea_query::{Expr, Iden, Query};
+use regex::Regex;
+use sea_query::{Alias, Cond, Expr, Func, Iden, Order, Query, SimpleExpr};
 use sea_query_binder::SqlxBinder;
+use serde::Deserialize;
 use sqlx::Row;
 use std::collections::HashMap;
+use std::io::Write;
 use time::UtcDateTime;
 use time::format_description::well_known::Rfc3339;
 use tokio::io;
 use tokio::io::AsyncReadExt;
-use tracing::{debug, info, warn};
+use tracing::{debug, error, info, warn};
 
 #[derive(Debug, Default)]
-struct BounceInfo {
-    recipient: String,
-    reason: String,
-    status: String,
-    action: String,
+pub struct BounceInfo {
+    pub recipient: String,
+    pub reason: String,
+    pub status: String,
+    pub action: String,
+    pub remote_mta: Option<String>,
+    pub last_attempt_date: Option<String>,
+    pub classification: Option<Classification>,
+    /// Recipient reported in the bounce, if the VERP address replaced it
+    pub reported_recipient: Option<String>,
+    /// Name of the parser that recognized the bounce ("dsn" or a vendor NDR parser)
+    pub parser: &'static str,
+}
+
+impl BounceInfo {
+    /// Maps the DSN action (RFC 3464 section 2.3.3) to the webhook event type
+    fn event_type(&self) -> Option<&'static str> {
+        match self.action.to_ascii_lowercase().as_str() {
+            "failed" => Some("bounce"),
+            "delayed" => Some("delay"),
+            "delivered" | "relayed" | "expanded" => Some("delivery"),
+            _ => None,
+        }
+    }
+
+    fn is_permanent(&self) -> bool {
+        self.action.eq_ignore_ascii_case("failed") && self.status.starts_with('5')
+    }
+
+    fn category(&self) -> Option<&str> {
+        self.classification.as_ref().map(|c| c.category.as_str())
+    }
+
+    fn sub_category(&self) -> Option<&str> {
+        self.classification
+            .as_ref()
+            .map(|c| c.sub_category.as_str())
+    }
+}
+
+/// Per-message fields of a bounce together with its per-recipient entries
+#[derive(Debug, Default)]
+struct BounceReport {
+    reporting_mta: Option<String>,
+    arrival_date: Option<String>,
+    recipients: Vec<BounceInfo>,
+}
+
+#[derive(Debug, Default)]
+struct ComplaintInfo {
+    feedback_type: String,
+    user_agent: Option<String>,
+    original_mail_from: Option<String>,
+    original_rcpt_to: Vec<String>,
+    arrival_date: Option<String>,
+    source_ip: Option<String>,
+}
+
+impl ComplaintInfo {
+    /// The complaining address, from Original-Rcpt-To or the original message
+    fn email<'a>(&'a self, original_info: &'a MessageInfo) -> &'a str {
+        self.original_rcpt_to
+            .first()
+            .unwrap_or(&original_info.to)
+            .as_str()
+    }
+
+    /// The complaining address, unless the report and the original message name none
+    fn address<'a>(&'a self, original_info: &'a MessageInfo) -> Option<&'a str> {
+        Some(self.email(original_info)).filter(|email| email.contains('@'))
+    }
+}
+
+#[derive(Debug)]
+enum Notification {
+    Bounce(BounceReport),
+    Complaint(ComplaintInfo),
 }
 
 #[derive(Debug, Default)]
 struct MessageInfo {
     from: String,
+    to: String,
     subject: String,
     message_id: Option<String>,
     metadata: HashMap<String, String>,
 }
 
-pub async fn execute_ingest(config: AppConfig, mut db: DBConnection) -> Result<()> {
-    // Parse message
+/// SMTP envelope of an incoming message, if known
+#[derive(Debug, Default, Clone)]
+pub struct Envelope {
+    pub sender: Option<String>,
+    pub recipient: Option<String>,
+}
+
+/// Exit code for messages that cannot be parsed (sysexits.h)
+pub const EX_DATAERR: u8 = 65;
+/// Exit code for transient failures, Postfix defers and retries the delivery (sysexits.h)
+pub const EX_TEMPFAIL: u8 = 75;
+
+/// Failure of the ingest pipeline, classified by whether retrying can help
+#[derive(Debug)]
+pub enum IngestError {
+    /// Transient failure such as an unavailable database
+    Temporary(anyhow::Error),
+    /// The message itself cannot be processed
+    Data(anyhow::Error),
+}
+
+impl IngestError {
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Self::Temporary(_) => EX_TEMPFAIL,
+            Self::Data(_) => EX_DATAERR,
+        }
+    }
+}
+
+impl std::fmt::Display for IngestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Temporary(e) | Self::Data(e) => write!(f, "{:#}", e),
+        }
+    }
+}
+
+impl std::error::Error for IngestError {}
+
+// Unclassified failures are treated as transient, so no bounce is lost
+impl From<anyhow::Error> for IngestError {
+    fn from(error: anyhow::Error) -> Self {
+        Self::Temporary(error)
+    }
+}
+
+/// Ingests a single email from stdin. If the database is unavailable, the email is
+/// written to the spool directory instead, to be replayed by the worker.
+pub async fn execute_ingest(config: AppConfig, envelope: Envelope) -> Result<(), IngestError> {
     let mut buffer = Vec::new();
     io::stdin()
         .read_to_end(&mut buffer)
@@ -37,98 +172,812 @@ pub async fn execute_ingest(config: AppConfig, mut db: DBConnection) -> Result<(
         .with_context(|| "Failed to read stdin")?;
     debug!(bytes = buffer.len(), "Read email from stdin");
 
+    let result = match (
+        Classifier::new(config.classification_rules_file.as_deref()),
+        connect_database(&config).await,
+    ) {
+        // Spooled messages are replayed once the schema has been upgraded
+        (Ok(classifier), Ok(mut db)) => match ensure_schema_current(&mut db).await {
+            Ok(()) => ingest_message(&config, &classifier, &mut db, &buffer, &envelope).await,
+            Err(e) => Err(IngestError::Temporary(e)),
+        },
+        (Err(e), _) | (_, Err(e)) => Err(IngestError::Temporary(e)),
+    };
+
+    match result {
+        Err(IngestError::Temporary(e)) => {
+            warn!(
+                error = format!("{:#}", e),
+                "Failed to ingest email, spooling it for later replay"
+            );
+            match spool_message(&config.spool_directory, &buffer, &envelope) {
+                Ok(path) => {
+                    info!(path = %path.display(), "Spooled email");
+                    Ok(())
+                }
+                Err(spool_error) => {
+                    error!(
+                        error = format!("{:#}", spool_error),
+                        "Failed to spool email"
+                    );
+                    Err(IngestError::Temporary(e))
+                }
+            }
+        }
+        result => result,
+    }
+}
+
+/// Parses a raw message and queues webhooks for all matching routes. Messages that are
+/// not bounces or have no matching route are ignored without error.
+pub async fn ingest_message(
+    config: &AppConfig,
+    classifier: &Classifier,
+    db: &mut DBConnection,
+    raw_message: &[u8],
+    envelope: &Envelope,
+) -> Result<(), IngestError> {
     let message = MessageParser::default()
-        .parse(&buffer)
-        .with_context(|| "Failed to parse email")?;
+        .parse(raw_message)
+        .with_context(|| "Failed to parse email")
+        .map_err(IngestError::Data)?;
 
-    let target_address = message
-        .to()
-        .and_then(|a| a.first())
-        .and_then(|a| a.address.clone())
-        .map(|a| a.to_string())
-        .unwrap_or("unknown".to_string());
+    let target_address = resolve_target_address(config, &message, envelope);
 
     let (full_user, domain) = target_address.split_once('@').unwrap_or(("", ""));
-    let user = full_user
-        .split_once(config.recipient_delimiter)
-        .map(|(user, _)| user)
-        .unwrap_or(full_user);
+    let domain = &normalize_domain(domain);
+    let verp = config
+        .verp_delimiters
+        .and_then(|delimiters| decode_verp(full_user, delimiters));
+    // The VERP prefix (e.g. "bounces") identifies the route. Otherwise the address
+    // extension (e.g. "campaign42" of "bounces+campaign42") is passed on as tag.
+    let (user, tag) = match &verp {
+        Some((prefix, _)) => (prefix.as_str(), None),
+        None => match full_user.split_once(config.recipient_delimiter) {
+            Some((user, tag)) => (user, Some(tag)),
+            None => (full_user, None),
+        },
+    };
+    let verp_recipient = verp.as_ref().map(|(_, recipient)| recipient.as_str());
 
-    info!(domain = domain, user = full_user, "Processing email");
+    info!(
+        domain = domain,
+        user = full_user,
+        sender = envelope.sender,
+        verp_recipient = verp_recipient,
+        "Processing email"
+    );
+
+    // Validate that this is a feedback report (ARF) or a bounce email (DSN delivery-status
+    // part or known NDR format)
+    let notification = if let Some(complaint_info) = parse_feedback_report(&message) {
+        debug!(
+            feedback_type = complaint_info.feedback_type,
+            "Validated email as feedback report"
+        );
+        Notification::Complaint(complaint_info)
+    } else if let Some(mut bounce_report) = parse_dsn(&message) {
+        debug!(
+            recipients = bounce_report.recipients.len(),
+            "Validated email as bounce notification"
+        );
+        bounce_report.recipients.retain(|info| {
+            let known = info.event_type().is_some();
+            if !known {
+                debug!(
+                    recipient = info.recipient,
+                    action = info.action,
+                    "Skipping recipient with unknown action"
+                );
+            }
+            known
+        });
+        if bounce_report.recipients.is_empty() {
+            warn!("Bounce notification contains no recipients, ignoring");
+            return Ok(());
+        }
 
-    // Validate that this is a bounce email (has DSN delivery-status part)
-    let Some(bounce_info) = parse_dsn(&message) else {
-        warn!("Email is not a bounce notification, ignoring");
+        apply_verp_recipient(&mut bounce_report, verp_recipient);
+        for info in &mut bounce_report.recipients {
+            if matches!(info.event_type(), Some("bounce" | "delay")) {
+                info.classification = classifier.classify(&info.status, &info.reason);
+            }
+        }
+        Notification::Bounce(bounce_report)
+    } else {
+        warn!("Email is not a bounce notification or feedback report, ignoring");
         return Ok(());
     };
-    debug!("Validated email as bounce notification");
-
-    // Find valid webhook destinations (both specific user routes and catch-all domain routes)
-    let query_builder = &*db.query_builder;
-    let (sql, values) = Query::select()
-        .columns([EmailRoute::Id])
-        .from(EmailRoute::Table)
-        .and_where(Expr::col(EmailRoute::Domain).eq(domain))
-        .and_where(
-            Expr::col(EmailRoute::User)
-                .is_null()
-                .or(Expr::col(EmailRoute::User).eq(user))
-                .or(Expr::col(EmailRoute::User).eq(full_user)),
+
+    let original_info = parse_original_message(&message);
+
+    // Events, suppressions and webhooks are written in one transaction. A failed ingest
+    // is spooled or retried by the MTA, replaying it must not queue duplicates.
+    db.begin().await?;
+    let result: Result<(), IngestError> = async {
+        // Record the events before routing, so the history is kept even without routes
+        let event_ids = store_bounce_events(
+            config,
+            db,
+            domain,
+            &notification,
+            &original_info,
+            raw_message,
         )
-        .and_where(Expr::col(EmailRoute::IsEnabled).eq(true))
-        .build_any_sqlx(query_builder);
-    let routes = sqlx::query_with(&sql, values)
-        .fetch_all(&mut db.connection)
         .await
-        .with_context(|| "Failed to load applicable routes")?;
-    if routes.is_empty() {
-        warn!(domain = domain, user = user, "No active routes found");
-        return Ok(());
-    }
-    debug!(count = routes.len(), "Found matching routes");
+        .with_context(|| "Failed to store bounce events")?;
+        update_suppressions(
+            config,
+            db,
+            domain,
+            &notification,
+            &original_info,
+            &event_ids,
+        )
+        .await
+        .with_context(|| "Failed to update suppressions")?;
 
-    // Extract relevant webhook information
-    let original_info = parse_original_message(&message);
-    let payload = serde_json::json!({
-        "event": "bounce",
-        "timestamp": UtcDateTime::now().format(&Rfc3339)?,
-        "recipient": target_address,
-        "message_id": original_info.message_id,
-        "from": original_info.from,
-        "subject": original_info.subject,
-        "metadata": original_info.metadata,
-        "email": bounce_info.recipient,
-        "reason": bounce_info.reason,
-        "status": bounce_info.status,
-        "action": bounce_info.action,
-        "is_permanent": bounce_info.status.starts_with("5"),
-    })
-    .to_string();
-
-    // Insert into webhook queue for delivery
-    for route in routes {
-        let route_id: i32 = route
-            .try_get(EmailRoute::Id.to_string().as_str())
-            .with_context(|| "Could not read route id")?;
-
-        let (sql, values) = Query::insert()
-            .into_table(WebhookQueue::Table)
-            .columns([WebhookQueue::EmailRouteId, WebhookQueue::Payload])
-            .values_panic([route_id.into(), payload.clone().into()])
+        // Find valid webhook destinations (specific user routes and catch-all routes of the
+        // domain, wildcard parent domains and the global catch-all). Domains are compared in
+        // lowercase, local parts too unless configured to be case sensitive.
+        let case_sensitive = config.case_sensitive_local_part;
+        let local_parts = [
+            normalize_local_part(user, case_sensitive),
+            normalize_local_part(full_user, case_sensitive),
+        ];
+        let route_user = route_user_condition(
+            db.connection.backend_name(),
+            &[&local_parts[0], &local_parts[1]],
+        );
+        let query_builder = &*db.query_builder;
+        let (sql, values) = Query::select()
+            .columns([EmailRoute::Id, EmailRoute::Domain, EmailRoute::User])
+            .expr_as(
+                bool_as_int(EmailRoute::BatchRecipients),
+                EmailRoute::BatchRecipients,
+            )
+            .column(EmailRoute::EventTypes)
+            .column(EmailRoute::TagPattern)
+            .column(EmailRoute::MatchRules)
+            .expr_as(
+                bool_as_int(EmailRoute::StopProcessing),
+                EmailRoute::StopProcessing,
+            )
+            .from(EmailRoute::Table)
+            .and_where(
+                Expr::expr(Func::lower(Expr::col(EmailRoute::Domain)))
+                    .is_in(domain_patterns(domain)),
+            )
+            .cond_where(route_user)
+            .and_where(Expr::col(EmailRoute::IsEnabled).eq(true))
+            .order_by(EmailRoute::Id, Order::Asc)
             .build_any_sqlx(query_builder);
-        sqlx::query_with(&sql, values)
-            .execute(&mut db.connection)
+        let routes = sqlx::query_with(&sql, values)
+            .fetch_all(&mut db.connection)
             .await
-            .with_context(|| format!("Failed to insert payload for route {}", route_id))?;
-        info!(route_id = route_id, "Queued webhook");
+            .with_context(|| "Failed to load applicable routes")?;
+        if routes.is_empty() {
+            warn!(domain = domain, user = user, "No active routes found");
+            return Ok(());
+        }
+        debug!(count = routes.len(), "Found matching routes");
+
+        let mut routes = routes
+            .into_iter()
+            .map(|route| {
+                let route_domain: String = route
+                    .try_get(EmailRoute::Domain.to_string().as_str())
+                    .with_context(|| "Could not read route domain")?;
+                let route_user: Option<String> = route
+                    .try_get(EmailRoute::User.to_string().as_str())
+                    .with_context(|| "Could not read route user")?;
+                Ok((
+                    route_precedence(domain, &route_domain, route_user.is_some()),
+                    route,
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        routes.sort_by_key(|(precedence, _)| *precedence);
+
+        // Extract relevant webhook information
+        let timestamp = UtcDateTime::now()
+            .format(&Rfc3339)
+            .with_context(|| "Failed to format timestamp")?;
+        // Payloads (tagged with their event type and bounce event) for routes receiving one
+        // event per recipient, and for routes receiving a single event per type with a
+        // `recipients` array. Batch payloads reference the first bounce event of their type.
+        let (payloads, batch_payloads) = match notification {
+            Notification::Bounce(bounce_report) => {
+                let payloads = bounce_report
+                    .recipients
+                    .iter()
+                    .zip(&event_ids)
+                    .filter_map(|(bounce_info, event_id)| {
+                        let event = bounce_info.event_type()?;
+                        let payload = serde_json::json!({
+                            "event": event,
+                            "timestamp": timestamp,
+                            "recipient": target_address,
+                            "tag": tag,
+                            "tag_fields": null,
+                            "verp_recipient": verp_recipient,
+                            "message_id": original_info.message_id,
+                            "from": original_info.from,
+                            "subject": original_info.subject,
+                            "metadata": original_info.metadata,
+                            "email": bounce_info.recipient,
+                            "reported_email": bounce_info.reported_recipient,
+                            "reason": bounce_info.reason,
+                            "status": bounce_info.status,
+                            "action": bounce_info.action,
+                            "is_permanent": bounce_info.is_permanent(),
+                            "category": bounce_info.category(),
+                            "sub_category": bounce_info.sub_category(),
+                            "remote_mta": bounce_info.remote_mta,
+                            "last_attempt_date": bounce_info.last_attempt_date,
+                            "reporting_mta": bounce_report.reporting_mta,
+                            "arrival_date": bounce_report.arrival_date,
+                            "parser": bounce_info.parser,
+                        });
+                        Some((event, *event_id, payload))
+                    })
+                    .collect::<Vec<_>>();
+                let batch_payloads = ["bounce", "delay", "delivery"]
+                    .into_iter()
+                    .filter_map(|event| {
+                        let event_id = bounce_report
+                            .recipients
+                            .iter()
+                            .position(|bounce_info| bounce_info.event_type() == Some(event))
+                            .map(|index| event_ids[index])?;
+                        let recipients = bounce_report
+                            .recipients
+                            .iter()
+                            .filter(|bounce_info| bounce_info.event_type() == Some(event))
+                            .map(|bounce_info| {
+                                serde_json::json!({
+                                    "email": bounce_info.recipient,
+                                    "reported_email": bounce_info.reported_recipient,
+                                    "reason": bounce_info.reason,
+                                    "status": bounce_info.status,
+                                    "action": bounce_info.action,
+                                    "is_permanent": bounce_info.is_permanent(),
+                                    "category": bounce_info.category(),
+                                    "sub_category": bounce_info.sub_category(),
+                                    "remote_mta": bounce_info.remote_mta,
+                                    "last_attempt_date": bounce_info.last_attempt_date,
+                                    "parser": bounce_info.parser,
+                                })
+                            })
+                            .collect::<Vec<_>>();
+                        let payload = serde_json::json!({
+                            "event": event,
+                            "timestamp": timestamp,
+                            "recipient": target_address,
+                            "tag": tag,
+                            "tag_fields": null,
+                            "verp_recipient": verp_recipient,
+                            "message_id": original_info.message_id,
+                            "from": original_info.from,
+                            "subject": original_info.subject,
+                            "metadata": original_info.metadata,
+                            "reporting_mta": bounce_report.reporting_mta,
+                            "arrival_date": bounce_report.arrival_date,
+                            "recipients": recipients,
+                        });
+                        Some((event, event_id, payload))
+                    })
+                    .collect::<Vec<_>>();
+                (payloads, batch_payloads)
+            }
+            Notification::Complaint(complaint_info) => {
+                let payload = serde_json::json!({
+                    "event": "complaint",
+                    "timestamp": timestamp,
+                    "recipient": target_address,
+                    "tag": tag,
+                    "tag_fields": null,
+                    "message_id": original_info.message_id,
+                    "from": original_info.from,
+                    "subject": original_info.subject,
+                    "metadata": original_info.metadata,
+                    "email": complaint_info.email(&original_info),
+                    "feedback_type": complaint_info.feedback_type,
+                    "user_agent": complaint_info.user_agent,
+                    "original_mail_from": complaint_info.original_mail_from,
+                    "original_rcpt_to": complaint_info.original_rcpt_to,
+                    "arrival_date": complaint_info.arrival_date,
+                    "source_ip": complaint_info.source_ip,
+                });
+                (
+                    vec![("complaint", event_ids[0], payload.clone())],
+                    vec![("complaint", event_ids[0], payload)],
+                )
+            }
+        };
+
+        // Insert into webhook queue for delivery, in order of precedence
+        for (_, route) in routes {
+            let route_id: i32 = route
+                .try_get(EmailRoute::Id.to_string().as_str())
+                .with_context(|| "Could not read route id")?;
+            let batch_recipients: i32 = route
+                .try_get(EmailRoute::BatchRecipients.to_string().as_str())
+                .with_context(|| "Could not read route batch_recipients")?;
+
+            let event_types: Option<String> = route
+                .try_get(EmailRoute::EventTypes.to_string().as_str())
+                .with_context(|| "Could not read route event_types")?;
+            let tag_pattern: Option<String> = route
+                .try_get(EmailRoute::TagPattern.to_string().as_str())
+                .with_context(|| "Could not read route tag_pattern")?;
+            let stop_processing: i32 = route
+                .try_get(EmailRoute::StopProcessing.to_string().as_str())
+                .with_context(|| "Could not read route stop_processing")?;
+            let tag_fields = tag_pattern
+                .as_deref()
+                .zip(tag)
+                .and_then(|(pattern, tag)| parse_tag(route_id, pattern, tag));
+            let match_rules: Option<String> = route
+                .try_get(EmailRoute::MatchRules.to_string().as_str())
+                .with_context(|| "Could not read route match_rules")?;
+            // Skip routes with invalid rules rather than sending webhooks they did not ask for
+            let match_rules = match match_rules.as_deref().map(MatchRules::parse).transpose() {
+                Ok(match_rules) => match_rules,
+                Err(e) => {
+                    error!(
+                        route_id = route_id,
+                        error = format!("{:#}", e),
+                        "Skipping route with invalid match rules"
+                    );
+                    continue;
+                }
+            };
+
+            // Routes without event types subscribe to all events
+            let route_payloads = if batch_recipients != 0 {
+                &batch_payloads
+            } else {
+                &payloads
+            }
+            .iter()
+            .filter(|(event, _, _)| {
+                event_types.as_deref().is_none_or(|types| {
+                    types
+                        .split(',')
+                        .any(|t| t.trim().eq_ignore_ascii_case(event))
+                })
+            })
+            .filter(|(_, _, payload)| match_rules.as_ref().is_none_or(|r| r.matches(payload)))
+            .map(|(_, event_id, payload)| (event_id, payload))
+            .collect::<Vec<_>>();
+            if route_payloads.is_empty() {
+                debug!(
+                    route_id = route_id,
+                    "Route is not subscribed to event types or match rules do not match"
+                );
+                continue;
+            }
+
+            for (event_id, payload) in &route_payloads {
+                let payload = match &tag_fields {
+                    Some(tag_fields) => {
+                        let mut payload = (*payload).clone();
+                        payload["tag_fields"] = tag_fields.clone();
+                        payload.to_string()
+                    }
+                    None => payload.to_string(),
+                };
+                let (sql, values) = Query::insert()
+                    .into_table(WebhookQueue::Table)
+                    .columns([
+                        WebhookQueue::EmailRouteId,
+                        WebhookQueue::Payload,
+                        WebhookQueue::BounceEventId,
+                    ])
+                    .values_panic([
+                        route_id.into(),
+                        payload.as_str().into(),
+                        (**event_id).into(),
+                    ])
+                    .build_any_sqlx(query_builder);
+                sqlx::query_with(&sql, values)
+                    .execute(&mut db.connection)
+                    .await
+                    .with_context(|| format!("Failed to insert payload for route {}", route_id))?;
+            }
+            info!(
+                route_id = route_id,
+                count = route_payloads.len(),
+                "Queued webhook"
+            );
+
+            if stop_processing != 0 {
+                debug!(route_id = route_id, "Route stops processing further routes");
+                break;
+            }
+        }
+
+        Ok(())
+    }
+    .await;
+    match &result {
+        Ok(_) => db.commit().await?,
+        Err(_) => {
+            if let Err(e) = db.rollback().await {
+                warn!(error = format!("{:#}", e), "Failed to roll back ingest");
+            }
+        }
+    }
+    result
+}
+
+/// Lowercases the domain and converts internationalized domain names to punycode, so
+/// domains match regardless of case and encoding
+pub fn normalize_domain(domain: &str) -> String {
+    idna::domain_to_ascii(domain).unwrap_or_else(|_| domain.to_lowercase())
+}
+
+/// Lowercases the local part unless `case_sensitive_local_part` is set. Route users
+/// are stored normalized, so both sides fold the same way for any letters rather than
+/// the ASCII-only `LOWER()` of SQLite.
+pub fn normalize_local_part(local_part: &str, case_sensitive: bool) -> String {
+    if case_sensitive {
+        local_part.to_string()
+    } else {
+        local_part.to_lowercase()
+    }
+}
+
+/// Routes without user, or whose user is one of the normalized local parts. MySQL
+/// compares them as binary strings, its default collations would ignore case and
+/// accents.
+fn route_user_condition(backend: &str, local_parts: &[&str]) -> Cond {
+    let binary = |expr: SimpleExpr| {
+        if backend == "MySQL" {
+            Expr::expr(expr).cast_as(Alias::new("binary"))
+        } else {
+            expr
+        }
+    };
+    local_parts.iter().fold(
+        Cond::any().add(Expr::col(EmailRoute::User).is_null()),
+        |condition, local_part| {
+            condition.add(
+                Expr::expr(binary(Expr::col(EmailRoute::User).into()))
+                    .eq(binary(Expr::val(*local_part).into())),
+            )
+        },
+    )
+}
+
+/// Domain patterns of routes that apply to the domain: the domain itself, wildcards of
+/// its parent domains (`*.example.com` for `mail.example.com`) and the global `*`
+fn domain_patterns(domain: &str) -> Vec<String> {
+    let mut patterns = vec![domain.to_string()];
+    let mut parent = domain;
+    while let Some((_, rest)) = parent.split_once('.') {
+        patterns.push(format!("*.{}", rest));
+        parent = rest;
+    }
+    patterns.push("*".to_string());
+    patterns
+}
+
+/// Sort key of a matching route, lower comes first: exact domain, then wildcard
+/// subdomains from the closest parent domain, then the global catch-all. Within each,
+/// user specific routes precede catch-all routes.
+fn route_precedence(domain: &str, route_domain: &str, has_user: bool) -> (usize, bool) {
+    let route_domain = route_domain.to_lowercase();
+    let domain_rank = if route_domain == domain {
+        0
+    } else if let Some(parent) = route_domain.strip_prefix("*.") {
+        // Number of labels between the domain and the wildcard's parent domain
+        domain.matches('.').count() - parent.matches('.').count()
+    } else {
+        usize::MAX
+    };
+    (domain_rank, !has_user)
+}
+
+/// Source of the address to route on: the SMTP envelope, the X-Original-To and
+/// Delivered-To headers added by Postfix, or the To header
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RecipientSource {
+    Envelope,
+    XOriginalTo,
+    DeliveredTo,
+    To,
+}
+
+/// The comma separated sources of `recipient_precedence`, in the order they are tried
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct RecipientPrecedence(Vec<RecipientSource>);
+
+impl TryFrom<String> for RecipientPrecedence {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        let sources = value
+            .split(',')
+            .map(str::trim)
+            .filter(|source| !source.is_empty())
+            .map(|source| {
+                Ok(match source.to_ascii_lowercase().as_str() {
+                    "envelope" => RecipientSource::Envelope,
+                    "x-original-to" => RecipientSource::XOriginalTo,
+                    "delivered-to" => RecipientSource::DeliveredTo,
+                    "to" => RecipientSource::To,
+                    _ => anyhow::bail!(
+                        "Unknown recipient source {} in recipient_precedence",
+                        source
+                    ),
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self(sources))
+    }
+}
+
+/// Picks the address to route on from the first source of `recipient_precedence`
+/// holding an address
+fn resolve_target_address(config: &AppConfig, message: &Message, envelope: &Envelope) -> String {
+    let header_address = |name: &str| {
+        message
+            .header_raw(name.to_string())
+            .map(strip_angle_brackets)
+    };
+
+    for source in &config.recipient_precedence.0 {
+        let address = match source {
+            RecipientSource::Envelope => envelope.recipient.clone(),
+            RecipientSource::XOriginalTo => header_address("X-Original-To"),
+            RecipientSource::DeliveredTo => header_address("Delivered-To"),
+            RecipientSource::To => message
+                .to()
+                .and_then(|a| a.first())
+                .and_then(|a| a.address.clone())
+                .map(|a| a.to_string()),
+        };
+        if let Some(address) = address.filter(|a| a.contains('@')) {
+            debug!(
+                source = ?source,
+                address = address,
+                "Resolved target address"
+            );
+            return address;
+        }
+    }
+
+    "unknown".to_string()
+}
+
+/// Parses the address extension with the route's tag pattern into an object of its named
+/// capture groups. Returns `None` if the pattern is invalid or does not match.
+fn parse_tag(route_id: i32, pattern: &str, tag: &str) -> Option<serde_json::Value> {
+    let regex = match Regex::new(pattern) {
+        Ok(regex) => regex,
+        Err(e) => {
+            warn!(
+                route_id = route_id,
+                error = e.to_string(),
+                "Invalid tag pattern"
+            );
+            return None;
+        }
+    };
+
+    let captures = regex.captures(tag)?;
+    let fields = regex
+        .capture_names()
+        .flatten()
+        .filter_map(|name| {
+            let value = captures.name(name)?.as_str();
+            Some((name.to_string(), serde_json::Value::from(value)))
+        })
+        .collect::<serde_json::Map<_, _>>();
+    Some(fields.into())
+}
+
+/// The two characters of `verp_delimiters` (e.g. `+=`), separating the prefix from the
+/// encoded recipient and its user from its domain
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct VerpDelimiters {
+    prefix: char,
+    domain: char,
+}
+
+impl TryFrom<String> for VerpDelimiters {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        let mut chars = value.chars();
+        let (Some(prefix), Some(domain), None) = (chars.next(), chars.next(), chars.next()) else {
+            anyhow::bail!("verp_delimiters must be two characters, got {}", value);
+        };
+        Ok(Self { prefix, domain })
     }
+}
+
+/// Decodes a VERP local part such as `bounces+alice=example.org` into its prefix and
+/// the original recipient. Returns `None` if the local part is not VERP encoded.
+fn decode_verp(local_part: &str, delimiters: VerpDelimiters) -> Option<(String, String)> {
+    // The recipient domain cannot contain the delimiter, so split at the last one
+    local_part
+        .split_once(delimiters.prefix)
+        .and_then(|(prefix, encoded)| {
+            let (user, domain) = encoded.rsplit_once(delimiters.domain)?;
+            if prefix.is_empty() || user.is_empty() || domain.is_empty() {
+                return None;
+            }
+            Some((prefix.to_string(), format!("{}@{}", user, domain)))
+        })
+}
+
+/// Writes one row per recipient (or one for a complaint) into `bounce_events` and
+/// returns their ids in the same order
+async fn store_bounce_events(
+    config: &AppConfig,
+    db: &mut DBConnection,
+    domain: &str,
+    notification: &Notification,
+    original_info: &MessageInfo,
+    raw_message: &[u8],
+) -> Result<Vec<i64>> {
+    let raw_message = if config.store_raw_message {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(raw_message)?;
+        Some(encoder.finish()?)
+    } else {
+        None
+    };
+
+    // (event type, recipient, status, category, sub_category, reason)
+    let events = match notification {
+        Notification::Bounce(bounce_report) => bounce_report
+            .recipients
+            .iter()
+            .map(|info| {
+                (
+                    info.event_type().unwrap_or_default(),
+                    info.recipient.as_str(),
+                    Some(info.status.as_str()),
+                    info.category(),
+                    info.sub_category(),
+                    Some(info.reason.as_str()),
+                )
+            })
+            .collect::<Vec<_>>(),
+        Notification::Complaint(complaint_info) => vec![(
+            "complaint",
+            complaint_info.email(original_info),
+            None,
+            None,
+            None,
+            Some(complaint_info.feedback_type.as_str()),
+        )],
+    };
 
+    // The raw message is stored on the first event, the others reference it
+    let mut ids: Vec<i64> = Vec::with_capacity(events.len());
+    for (event_type, recipient, status, category, sub_category, reason) in events {
+        let (raw_message, raw_message_event_id) = match ids.first() {
+            Some(first_id) if raw_message.is_some() => (None, Some(*first_id)),
+            _ => (raw_message.clone(), None),
+        };
+        let mut statement = Query::insert();
+        statement
+            .into_table(BounceEvent::Table)
+            .columns([
+                BounceEvent::EventType,
+                BounceEvent::Domain,
+                BounceEvent::Recipient,
+                BounceEvent::Status,
+                BounceEvent::Category,
+                BounceEvent::SubCategory,
+                BounceEvent::Reason,
+                BounceEvent::MessageId,
+                BounceEvent::RawMessage,
+                BounceEvent::RawMessageEventId,
+            ])
+            .values_panic([
+                event_type.into(),
+                domain.to_lowercase().into(),
+                recipient.to_lowercase().into(),
+                status.into(),
+                category.into(),
+                sub_category.into(),
+                reason.into(),
+                original_info.message_id.clone().into(),
+                raw_message.into(),
+                raw_message_event_id.into(),
+            ]);
+        ids.push(insert_returning_id(db, statement, BounceEvent::Id).await?);
+    }
+    debug!(count = ids.len(), "Stored bounce events");
+
+    Ok(ids)
+}
+
+/// Suppresses addresses of hard bounces and complaints immediately, and of soft bounces
+/// once they reach the configured threshold within the window
+async fn update_suppressions(
+    config: &AppConfig,
+    db: &mut DBConnection,
+    domain: &str,
+    notification: &Notification,
+    original_info: &MessageInfo,
+    event_ids: &[i64],
+) -> Result<()> {
+    match notification {
+        Notification::Bounce(bounce_report) => {
+            for (info, event_id) in bounce_report.recipients.iter().zip(event_ids) {
+                if info.event_type() != Some("bounce") {
+                    continue;
+                }
+                let description = format!("{} {}", info.status, info.reason);
+                match info.category() {
+                    // Blocks concern the sender rather than the address, never suppress them
+                    Some("hard") if info.is_permanent() => {
+                        suppress(
+                            db,
+                            domain,
+                            &info.recipient,
+                            SuppressionReason::HardBounce,
+                            Some(&description),
+                            Some(*event_id),
+                        )
+                        .await?;
+                    }
+                    Some("soft") if config.suppression_soft_bounce_threshold > 0 => {
+                        let count = count_soft_bounces(
+                            db,
+                            domain,
+                            &info.recipient,
+                            config.suppression_soft_bounce_window_days,
+                        )
+                        .await?;
+                        if count >= config.suppression_soft_bounce_threshold.into() {
+                            suppress(
+                                db,
+                                domain,
+                                &info.recipient,
+                                SuppressionReason::SoftBounce,
+                                Some(&description),
+                                Some(*event_id),
+                            )
+                            .await?;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Notification::Complaint(complaint_info) => {
+            let Some(email) = complaint_info.address(original_info) else {
+                warn!("Complaint names no recipient address, not suppressing");
+                return Ok(());
+            };
+            suppress(
+                db,
+                domain,
+                email,
+                SuppressionReason::Complaint,
+                Some(&complaint_info.feedback_type),
+                event_ids.first().copied(),
+            )
+            .await?;
+        }
+    }
     Ok(())
 }
 
 fn parse_original_message(email: &Message) -> MessageInfo {
     let mut info = MessageInfo {
         from: "unknown".to_string(),
+        to: "unknown".to_string(),
         subject: "unknown".to_string(),
         message_id: None,
         metadata: HashMap::new(),
@@ -142,6 +991,12 @@ fn parse_original_message(email: &Message) -> MessageInfo {
             .and_then(|a| a.address.clone())
             .map(|a| a.to_string())
             .unwrap_or("unknown".to_string());
+        info.to = message
+            .to()
+            .and_then(|t| t.first())
+            .and_then(|a| a.address.clone())
+            .map(|a| a.to_string())
+            .unwrap_or("unknown".to_string());
         info.message_id = message
             .message_id()
             .or(email.message_id())
@@ -177,58 +1032,675 @@ fn parse_original_message(email: &Message) -> MessageInfo {
     info
 }
 
-fn parse_dsn(email: &Message) -> Option<BounceInfo> {
+fn parse_feedback_report(email: &Message) -> Option<ComplaintInfo> {
+    let part = email.parts.iter().find(|part| {
+        part.content_type().is_some_and(|ct| {
+            ct.c_type == "message" && ct.subtype().unwrap_or("") == "feedback-report"
+        })
+    })?;
+
+    let mut info = ComplaintInfo {
+        feedback_type: "abuse".to_string(),
+        ..Default::default()
+    };
+    for (name, value) in parse_report_fields(part.text_contents().unwrap_or("")) {
+        match name.as_str() {
+            "feedback-type" => info.feedback_type = value,
+            "user-agent" => info.user_agent = Some(value),
+            "original-mail-from" => info.original_mail_from = Some(strip_angle_brackets(&value)),
+            "original-rcpt-to" => info.original_rcpt_to.push(strip_angle_brackets(&value)),
+            "arrival-date" | "received-date" => info.arrival_date = Some(value),
+            "source-ip" => info.source_ip = Some(value),
+            _ => {}
+        }
+    }
+
+    Some(info)
+}
+
+/// Parses the `Name: value` fields of a report part (RFC 5965/RFC 3464), unfolding
+/// continuation lines. Field names are returned in lowercase.
+fn parse_report_fields(text: &str) -> Vec<(String, String)> {
+    parse_report_blocks(text).into_iter().flatten().collect()
+}
+
+/// Like [`parse_report_fields`], but keeps the blank line separated field groups apart
+fn parse_report_blocks(text: &str) -> Vec<Vec<(String, String)>> {
+    let mut blocks: Vec<Vec<(String, String)>> = vec![Vec::new()];
+
+    for line in text.lines() {
+        if line.trim().is_empty() {
+            if blocks.last().is_some_and(|block| !block.is_empty()) {
+                blocks.push(Vec::new());
+            }
+            continue;
+        }
+        let fields = blocks.last_mut().unwrap();
+
+        // Handle continuation lines (RFC 2822 folding)
+        if line.starts_with(' ') || line.starts_with('\t') {
+            if let Some((_, value)) = fields.last_mut() {
+                let continuation = line.trim();
+                if !continuation.is_empty() {
+                    value.push(' ');
+                    value.push_str(continuation);
+                }
+            }
+            continue;
+        }
+
+        if let Some((name, value)) = line.split_once(':') {
+            fields.push((name.trim().to_lowercase(), value.trim().to_string()));
+        }
+    }
+
+    blocks.retain(|block| !block.is_empty());
+    blocks
+}
+
+fn strip_angle_brackets(value: &str) -> String {
+    value
+        .trim()
+        .trim_start_matches('<')
+        .trim_end_matches('>')
+        .to_string()
+}
+
+fn parse_dsn(email: &Message) -> Option<BounceReport> {
     for part in &email.parts {
         match part.content_type() {
             Some(ct)
                 if ct.c_type == "message" && ct.subtype().unwrap_or("") == "delivery-status" =>
             {
-                let mut info = BounceInfo {
-                    recipient: "unknown".to_string(),
-                    reason: "No reason found".to_string(),
-                    status: "5.0.0".to_string(),
-                    action: "failed".to_string(),
-                };
+                let mut report = BounceReport::default();
 
                 let text = part.text_contents().unwrap_or("");
-                let mut current_header: Option<&str> = None;
-
-                for line in text.lines() {
-                    // Handle continuation lines (RFC 2822 folding)
-                    if line.starts_with(' ') || line.starts_with('\t') {
-                        if let Some(header) = current_header {
-                            let continuation = line.trim();
-                            if header == "diagnostic-code" && !continuation.is_empty() {
-                                info.reason.push(' ');
-                                info.reason.push_str(continuation);
+                for (index, block) in parse_report_blocks(text).into_iter().enumerate() {
+                    let is_recipient_block = block
+                        .iter()
+                        .any(|(name, _)| name == "final-recipient" || name == "original-recipient");
+
+                    // The first block holds the per-message fields (RFC 3464 section 2.2)
+                    if index == 0 && !is_recipient_block {
+                        for (name, value) in block {
+                            match name.as_str() {
+                                "reporting-mta" => report.reporting_mta = Some(strip_type(&value)),
+                                "arrival-date" => report.arrival_date = Some(value),
+                                _ => {}
                             }
                         }
                         continue;
                     }
+                    if !is_recipient_block {
+                        continue;
+                    }
 
-                    current_header = None;
-                    let lower = line.to_lowercase();
-
-                    if lower.starts_with("original-recipient:")
-                        || (lower.starts_with("final-recipient:") && info.recipient.eq("unknown"))
-                    {
-                        info.recipient =
-                            line.split(';').next_back().unwrap_or("").trim().to_string();
-                    } else if lower.starts_with("diagnostic-code:") {
-                        info.reason = line.splitn(2, ':').last().unwrap_or("").trim().to_string();
-                        current_header = Some("diagnostic-code");
-                    } else if lower.starts_with("status:") {
-                        info.status = line.split(':').next_back().unwrap_or("").trim().to_string();
-                    } else if lower.starts_with("action:") {
-                        info.action = line.split(':').next_back().unwrap_or("").trim().to_string();
+                    let mut info = BounceInfo {
+                        recipient: "unknown".to_string(),
+                        reason: "No reason found".to_string(),
+                        status: "5.0.0".to_string(),
+                        action: "failed".to_string(),
+                        parser: "dsn",
+                        ..Default::default()
+                    };
+                    for (name, value) in block {
+                        match name.as_str() {
+                            "original-recipient" => info.recipient = strip_type(&value),
+                            "final-recipient" if info.recipient.eq("unknown") => {
+                                info.recipient = strip_type(&value)
+                            }
+                            "diagnostic-code" => info.reason = value,
+                            "status" => info.status = value,
+                            "action" => info.action = value,
+                            "remote-mta" => info.remote_mta = Some(strip_type(&value)),
+                            "last-attempt-date" => info.last_attempt_date = Some(value),
+                            _ => {}
+                        }
                     }
+                    report.recipients.push(info);
                 }
 
-                return Some(info);
+                return Some(report);
             }
             _ => continue,
         }
     }
 
-    None
+    parse_ndr(email).map(|info| BounceReport {
+        recipients: vec![info],
+        ..Default::default()
+    })
+}
+
+/// Replaces the recipient reported in the bounce with the VERP address, which is more
+/// reliable as intermediate MTAs may rewrite or truncate the reported one. The VERP
+/// address names a single recipient, so reports listing several recipients keep theirs.
+fn apply_verp_recipient(bounce_report: &mut BounceReport, verp_recipient: Option<&str>) {
+    if let (Some(verp_recipient), [info]) =
+        (verp_recipient, bounce_report.recipients.as_mut_slice())
+    {
+        info.reported_recipient = Some(std::mem::replace(
+            &mut info.recipient,
+            verp_recipient.to_string(),
+        ));
+    }
+}
+
+/// Strips the type prefix of typed DSN fields such as `rfc822; user@example.com`
+fn strip_type(value: &str) -> String {
+    value
+        .split(';')
+        .next_back()
+        .unwrap_or("")
+        .trim()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sea_query::{
+        ColumnDef, MysqlQueryBuilder, PostgresQueryBuilder, QueryBuilder, SchemaBuilder,
+        SqliteQueryBuilder, Table,
+    };
+    use sqlx::{AnyConnection, Connection};
+
+    fn route_user_sql(backend: &str) -> String {
+        let mut query = Query::select();
+        query
+            .column(EmailRoute::Id)
+            .from(EmailRoute::Table)
+            .cond_where(route_user_condition(backend, &["jörg"]));
+        match backend {
+            "PostgreSQL" => query.to_string(PostgresQueryBuilder),
+            "MySQL" => query.to_string(MysqlQueryBuilder),
+            _ => query.to_string(SqliteQueryBuilder),
+        }
+    }
+
+    #[test]
+    fn route_user_compares_plainly() {
+        assert_eq!(
+            route_user_sql("PostgreSQL"),
+            r#"SELECT "id" FROM "email_routes" WHERE "user" IS NULL OR "user" = 'jörg'"#
+        );
+        assert_eq!(
+            route_user_sql("SQLite"),
+            r#"SELECT "id" FROM "email_routes" WHERE "user" IS NULL OR "user" = 'jörg'"#
+        );
+    }
+
+    #[test]
+    fn route_user_compares_binary_on_mysql() {
+        assert_eq!(
+            route_user_sql("MySQL"),
+            "SELECT `id` FROM `email_routes` WHERE `user` IS NULL OR CAST(`user` AS binary) = CAST('jörg' AS binary)"
+        );
+    }
+
+    #[test]
+    fn normalize_local_part_folds_any_letters() {
+        assert_eq!(normalize_local_part("JÖRG", false), "jörg");
+        assert_eq!(normalize_local_part("John", false), "john");
+        assert_eq!(normalize_local_part("JÖRG", true), "JÖRG");
+    }
+
+    /// Users of the routes matching the local part, on the database at `url`. Routes are
+    /// stored normalized as `route add` does.
+    async fn matching_route_users(
+        url: &str,
+        case_sensitive: bool,
+        local_part: &str,
+    ) -> Vec<String> {
+        sqlx::any::install_default_drivers();
+        let mut connection = AnyConnection::connect(url).await.unwrap();
+        let backend = connection.backend_name().to_string();
+        let builder: Box<dyn QueryBuilder> = match backend.as_str() {
+            "PostgreSQL" => Box::new(PostgresQueryBuilder),
+            "MySQL" => Box::new(MysqlQueryBuilder),
+            _ => Box::new(SqliteQueryBuilder),
+        };
+        let schema_builder: Box<dyn SchemaBuilder> = match backend.as_str() {
+            "PostgreSQL" => Box::new(PostgresQueryBuilder),
+            "MySQL" => Box::new(MysqlQueryBuilder),
+            _ => Box::new(SqliteQueryBuilder),
+        };
+
+        // A temporary table shadows any email_routes table of the test database
+        let create = Table::create()
+            .table(EmailRoute::Table)
+            .temporary()
+            .col(
+                ColumnDef::new(EmailRoute::Id)
+                    .integer()
+                    .not_null()
+                    .primary_key(),
+            )
+            .col(ColumnDef::new(EmailRoute::User).string().null())
+            .build_any(&*schema_builder);
+        sqlx::query(&create).execute(&mut connection).await.unwrap();
+        for (id, user) in ["john", "John", "jörg", "JÖRG", "jorg"].iter().enumerate() {
+            let (sql, values) = Query::insert()
+                .into_table(EmailRoute::Table)
+                .columns([EmailRoute::Id, EmailRoute::User])
+                .values_panic([
+                    (id as i32).into(),
+                    normalize_local_part(user, case_sensitive).into(),
+                ])
+                .build_any_sqlx(&*builder);
+            sqlx::query_with(&sql, values)
+                .execute(&mut connection)
+                .await
+                .unwrap();
+        }
+
+        let local_part = normalize_local_part(local_part, case_sensitive);
+        let (sql, values) = Query::select()
+            .column(EmailRoute::User)
+            .from(EmailRoute::Table)
+            .cond_where(route_user_condition(&backend, &[&local_part]))
+            .order_by(EmailRoute::Id, Order::Asc)
+            .build_any_sqlx(&*builder);
+        sqlx::query_with(&sql, values)
+            .fetch_all(&mut connection)
+            .await
+            .unwrap()
+            .iter()
+            .map(|row| row.try_get(0).unwrap())
+            .collect()
+    }
+
+    async fn assert_route_users_match(url: &str) {
+        assert_eq!(
+            matching_route_users(url, false, "JOHN").await,
+            ["john", "john"]
+        );
+        assert_eq!(matching_route_users(url, true, "John").await, ["John"]);
+        assert_eq!(
+            matching_route_users(url, false, "JÖRG").await,
+            ["jörg", "jörg"]
+        );
+        // Accents are significant, whatever the collation
+        assert_eq!(matching_route_users(url, true, "jorg").await, ["jorg"]);
+    }
+
+    #[tokio::test]
+    async fn route_user_matches_on_sqlite() {
+        assert_route_users_match("sqlite::memory:").await;
+    }
+
+    /// Runs against a real server if BOUNCE_RELAY_TEST_POSTGRES_URL is set
+    #[tokio::test]
+    async fn route_user_matches_on_postgres() {
+        if let Ok(url) = std::env::var("BOUNCE_RELAY_TEST_POSTGRES_URL") {
+            assert_route_users_match(&url).await;
+        }
+    }
+
+    /// Runs against a real server if BOUNCE_RELAY_TEST_MYSQL_URL is set
+    #[tokio::test]
+    async fn route_user_matches_on_mysql() {
+        if let Ok(url) = std::env::var("BOUNCE_RELAY_TEST_MYSQL_URL") {
+            assert_route_users_match(&url).await;
+        }
+    }
+
+    fn complaint_address(report: &str) -> Option<String> {
+        let message = MessageParser::default().parse(report.as_bytes()).unwrap();
+        let complaint_info = parse_feedback_report(&message).unwrap();
+        let original_info = parse_original_message(&message);
+        complaint_info.address(&original_info).map(str::to_string)
+    }
+
+    const ARF_REPORT: &str = "From: <abusedesk@example.net>\r
+To: <bounces@example.com>\r
+Subject: FW: Earn money\r
+MIME-Version: 1.0\r
+Content-Type: multipart/report; report-type=feedback-report; boundary=\"b\"\r
+\r
+--b\r
+Content-Type: text/plain\r
+\r
+This is an email abuse report.\r
+--b\r
+Content-Type: message/feedback-report\r
+\r
+Feedback-Type: abuse\r
+User-Agent: SomeGenerator/1.0\r
+Version: 1\r
+{rcpt}\r
+--b\r
+Content-Type: message/rfc822\r
+\r
+From: <sender@example.com>\r
+To: {to}\r
+Subject: Newsletter\r
+\r
+Body\r
+--b--\r
+";
+
+    fn arf_report(rcpt: &str, to: &str) -> String {
+        ARF_REPORT.replace("{rcpt}", rcpt).replace("{to}", to)
+    }
+
+    #[test]
+    fn complaint_address_prefers_original_rcpt_to() {
+        let report = arf_report(
+            "Original-Rcpt-To: <user@example.net>",
+            "<other@example.net>",
+        );
+        assert_eq!(
+            complaint_address(&report).as_deref(),
+            Some("user@example.net")
+        );
+        let report = arf_report("Source-IP: 192.0.2.1", "<other@example.net>");
+        assert_eq!(
+            complaint_address(&report).as_deref(),
+            Some("other@example.net")
+        );
+    }
+
+    #[test]
+    fn complaint_without_address_is_not_suppressed() {
+        let report = arf_report("Source-IP: 192.0.2.1", "Undisclosed recipients:;");
+        assert_eq!(complaint_address(&report), None);
+    }
+
+    fn parse_message(raw: &str) -> Message<'_> {
+        MessageParser::default().parse(raw.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn verp_recipient_replaces_single_recipient() {
+        let message = parse_message(include_str!("../tests/fixtures/dsn/postfix_multi.eml"));
+        let mut report = parse_dsn(&message).unwrap();
+        report.recipients.truncate(1);
+
+        apply_verp_recipient(&mut report, Some("alice@example.org"));
+        assert_eq!(report.recipients[0].recipient, "alice@example.org");
+        assert_eq!(
+            report.recipients[0].reported_recipient.as_deref(),
+            Some("A@example.org")
+        );
+    }
+
+    #[test]
+    fn verp_recipient_keeps_multiple_recipients() {
+        let message = parse_message(include_str!("../tests/fixtures/dsn/postfix_multi.eml"));
+        let mut report = parse_dsn(&message).unwrap();
+
+        apply_verp_recipient(&mut report, Some("alice@example.org"));
+        let recipients = report
+            .recipients
+            .iter()
+            .map(|info| (info.recipient.as_str(), info.reported_recipient.as_deref()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            recipients,
+            [
+                ("A@example.org", None),
+                ("b@example.org", None),
+                ("c@example.org", None)
+            ]
+        );
+
+        apply_verp_recipient(&mut report, None);
+        assert_eq!(report.recipients[0].recipient, "A@example.org");
+    }
+
+    #[test]
+    fn parse_multi_recipient_dsn() {
+        let message = parse_message(include_str!("../tests/fixtures/dsn/postfix_multi.eml"));
+        let report = parse_dsn(&message).unwrap();
+        assert_eq!(report.reporting_mta.as_deref(), Some("mx.example.com"));
+        assert_eq!(
+            report.arrival_date.as_deref(),
+            Some("Fri,  8 Mar 2024 14:00:00 +0000 (UTC)")
+        );
+
+        let recipients = report
+            .recipients
+            .iter()
+            .map(|info| {
+                (
+                    info.recipient.as_str(),
+                    info.action.as_str(),
+                    info.status.as_str(),
+                    info.event_type(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            recipients,
+            [
+                ("A@example.org", "failed", "5.1.1", Some("bounce")),
+                ("b@example.org", "delayed", "4.4.1", Some("delay")),
+                ("c@example.org", "failed", "5.2.2", Some("bounce")),
+            ]
+        );
+        // Folded fields are joined
+        assert_eq!(
+            report.recipients[0].reason,
+            "smtp; 550 5.1.1 <a@example.org>: Recipient address rejected: User unknown"
+        );
+        assert_eq!(
+            report.recipients[0].remote_mta.as_deref(),
+            Some("mx.example.org")
+        );
+        assert!(report.recipients.iter().all(|info| info.parser == "dsn"));
+    }
+
+    #[test]
+    fn parse_original_message_headers() {
+        let message = parse_message(include_str!("../tests/fixtures/dsn/postfix_multi.eml"));
+        let info = parse_original_message(&message);
+        assert_eq!(info.from, "app@example.com");
+        assert_eq!(info.to, "a@example.org");
+        assert_eq!(info.subject, "Your invoice");
+        assert_eq!(info.message_id.as_deref(), Some("m1@example.com"));
+        assert_eq!(
+            info.metadata.get("Customer-Id").map(String::as_str),
+            Some("1234")
+        );
+    }
+
+    #[test]
+    fn parse_dsn_falls_back_to_vendor_parsers() {
+        let message = parse_message(include_str!("../tests/fixtures/ndr/qmail.eml"));
+        let report = parse_dsn(&message).unwrap();
+        assert_eq!(report.recipients.len(), 1);
+        assert_eq!(report.recipients[0].recipient, "nobody@example.org");
+        assert_eq!(report.recipients[0].parser, "qmail");
+    }
+
+    #[test]
+    fn domain_patterns_include_parent_wildcards() {
+        assert_eq!(
+            domain_patterns("mail.eu.example.com"),
+            [
+                "mail.eu.example.com",
+                "*.eu.example.com",
+                "*.example.com",
+                "*.com",
+                "*"
+            ]
+        );
+        assert_eq!(domain_patterns("localhost"), ["localhost", "*"]);
+    }
+
+    #[test]
+    fn route_precedence_orders_matching_routes() {
+        let domain = "mail.eu.example.com";
+        let mut routes = vec![
+            ("*", false),
+            ("*.example.com", false),
+            ("*.example.com", true),
+            ("mail.eu.example.com", false),
+            ("*.eu.example.com", false),
+            ("Mail.EU.example.com", true),
+        ];
+        routes.sort_by_key(|(route_domain, has_user)| {
+            route_precedence(domain, route_domain, *has_user)
+        });
+        assert_eq!(
+            routes,
+            [
+                ("Mail.EU.example.com", true),
+                ("mail.eu.example.com", false),
+                ("*.eu.example.com", false),
+                ("*.example.com", true),
+                ("*.example.com", false),
+                ("*", false),
+            ]
+        );
+    }
+
+    fn resolved_address(precedence: &str, recipient: Option<&str>) -> String {
+        let message = parse_message(
+            "X-Original-To: <original@example.com>\r\n\
+             Delivered-To: delivered@example.com\r\n\
+             To: Bounces <to@example.com>\r\n\
+             Subject: Undelivered Mail\r\n\r\nBody\r\n",
+        );
+        let envelope = Envelope {
+            sender: None,
+            recipient: recipient.map(str::to_string),
+        };
+        let mut config = crate::test_config();
+        config.recipient_precedence =
+            RecipientPrecedence::try_from(precedence.to_string()).unwrap();
+        resolve_target_address(&config, &message, &envelope)
+    }
+
+    #[test]
+    fn resolve_target_address_from_each_source() {
+        let envelope = Some("envelope@example.com");
+        assert_eq!(
+            resolved_address("envelope", envelope),
+            "envelope@example.com"
+        );
+        assert_eq!(
+            resolved_address("X-Original-To", envelope),
+            "original@example.com"
+        );
+        assert_eq!(
+            resolved_address("delivered-to", envelope),
+            "delivered@example.com"
+        );
+        assert_eq!(resolved_address("to", envelope), "to@example.com");
+    }
+
+    #[test]
+    fn resolve_target_address_falls_back_in_order() {
+        let default_precedence = "envelope,x-original-to,delivered-to,to";
+        assert_eq!(
+            resolved_address(default_precedence, Some("envelope@example.com")),
+            "envelope@example.com"
+        );
+        // Missing sources and values without an address are skipped
+        assert_eq!(
+            resolved_address(default_precedence, None),
+            "original@example.com"
+        );
+        assert_eq!(
+            resolved_address("envelope, delivered-to", Some("postmaster")),
+            "delivered@example.com"
+        );
+        assert_eq!(resolved_address("envelope", None), "unknown");
+        assert_eq!(
+            resolved_address("", Some("envelope@example.com")),
+            "unknown"
+        );
+    }
+
+    #[test]
+    fn recipient_precedence_rejects_unknown_sources() {
+        assert!(RecipientPrecedence::try_from("envelope,cc".to_string()).is_err());
+    }
+
+    #[test]
+    fn parse_tag_returns_named_captures() {
+        let pattern = r"^c(?P<campaign>\d+)-(?P<message>\w+)(-(?P<variant>\w))?$";
+        assert_eq!(
+            parse_tag(1, pattern, "c42-abc"),
+            Some(serde_json::json!({ "campaign": "42", "message": "abc" }))
+        );
+        assert_eq!(
+            parse_tag(1, pattern, "c42-abc-b"),
+            Some(serde_json::json!({ "campaign": "42", "message": "abc", "variant": "b" }))
+        );
+        // Unnamed groups are not reported
+        assert_eq!(parse_tag(1, r"^(\d+)$", "42"), Some(serde_json::json!({})));
+    }
+
+    #[test]
+    fn parse_tag_ignores_mismatches_and_invalid_patterns() {
+        assert_eq!(parse_tag(1, r"^c(?P<campaign>\d+)$", "newsletter"), None);
+        assert_eq!(parse_tag(1, r"^c(?P<campaign>\d+$", "c42"), None);
+    }
+
+    fn delimiters(value: &str) -> Result<VerpDelimiters> {
+        VerpDelimiters::try_from(value.to_string())
+    }
+
+    fn verp(local_part: &str) -> Option<(String, String)> {
+        decode_verp(local_part, delimiters("+=").unwrap())
+    }
+
+    #[test]
+    fn decode_verp_recovers_recipient() {
+        assert_eq!(
+            verp("bounces+alice=example.org"),
+            Some(("bounces".to_string(), "alice@example.org".to_string()))
+        );
+        // The domain cannot contain the delimiter, the user part can
+        assert_eq!(
+            verp("bounces+first=last=mail.example.org"),
+            Some((
+                "bounces".to_string(),
+                "first=last@mail.example.org".to_string()
+            ))
+        );
+        assert_eq!(
+            decode_verp("bounces-alice=example.org", delimiters("-=").unwrap()),
+            Some(("bounces".to_string(), "alice@example.org".to_string()))
+        );
+    }
+
+    #[test]
+    fn decode_verp_ignores_other_addresses() {
+        assert_eq!(verp("bounces"), None);
+        assert_eq!(verp("bounces+campaign42"), None);
+        assert_eq!(verp("+alice=example.org"), None);
+        assert_eq!(verp("bounces+=example.org"), None);
+        assert_eq!(verp("bounces+alice="), None);
+    }
+
+    #[test]
+    fn verp_delimiters_require_two_characters() {
+        assert!(delimiters("+").is_err());
+        assert!(delimiters("+=-").is_err());
+        assert_eq!(
+            delimiters("-=").unwrap(),
+            VerpDelimiters {
+                prefix: '-',
+                domain: '='
+            }
+        );
+    }
+
+    #[test]
+    fn invalid_verp_delimiters_fail_config_load() {
+        let config = crate::config_defaults()
+            .unwrap()
+            .set_override("database_url", "sqlite::memory:")
+            .unwrap()
+            .set_override("verp_delimiters", "+")
+            .unwrap()
+            .build()
+            .unwrap()
+            .try_deserialize::<AppConfig>();
+        assert!(config.is_err());
+    }
 }
@@ -0,0 +1,95 @@
+use anyhow::{Context, Result, bail};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, UnixListener};
+use tracing::info;
+
+/// Address to listen on, either `tcp:<host>:<port>` (or just `<host>:<port>`) or
+/// `unix:<path>`
+#[derive(Debug, Clone)]
+pub enum ListenAddress {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddress {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            if path.is_empty() {
+                bail!("Missing socket path in listen address {}", s);
+            }
+            return Ok(Self::Unix(PathBuf::from(path)));
+        }
+
+        let address = s.strip_prefix("tcp:").unwrap_or(s);
+        if !address.contains(':') {
+            bail!("Invalid listen address {}, expected <host>:<port>", s);
+        }
+        Ok(Self::Tcp(address.to_string()))
+    }
+}
+
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// Binds the listen address. A stale unix socket file is removed first and, if
+    /// given, `socket_mode` is applied to the new socket.
+    pub async fn bind(address: &ListenAddress, socket_mode: Option<u32>) -> Result<Self> {
+        match address {
+            ListenAddress::Tcp(address) => {
+                let listener = TcpListener::bind(address)
+                    .await
+                    .with_context(|| format!("Failed to listen on {}", address))?;
+                info!(address = address, "Listening on TCP socket");
+                Ok(Self::Tcp(listener))
+            }
+            ListenAddress::Unix(path) => {
+                if path.exists() {
+                    fs::remove_file(path).with_context(|| {
+                        format!("Failed to remove stale socket {}", path.display())
+                    })?;
+                }
+                let listener = UnixListener::bind(path)
+                    .with_context(|| format!("Failed to listen on {}", path.display()))?;
+                if let Some(mode) = socket_mode {
+                    fs::set_permissions(path, fs::Permissions::from_mode(mode)).with_context(
+                        || format!("Failed to set permissions of {}", path.display()),
+                    )?;
+                }
+                info!(path = %path.display(), "Listening on unix socket");
+                Ok(Self::Unix(listener))
+            }
+        }
+    }
+
+    /// Accepts the next connection and returns it with a description of the peer
+    pub async fn accept(&self) -> Result<(Box<dyn Stream>, String)> {
+        match self {
+            Self::Tcp(listener) => {
+                let (stream, peer) = listener.accept().await?;
+                Ok((Box::new(stream), peer.to_string()))
+            }
+            Self::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Box::new(stream), "unix".to_string()))
+            }
+        }
+    }
+}
+
+/// Parses an octal file mode such as `0660`
+pub fn parse_socket_mode(mode: &str) -> Result<u32> {
+    u32::from_str_radix(mode.trim_start_matches("0o"), 8)
+        .with_context(|| format!("Invalid socket mode {}", mode))
+}
@@ -0,0 +1,621 @@
+use crate::AppConfig;
+use crate::classify::Classifier;
+use crate::db::{DBConnection, DBPool};
+use crate::ingest::{Envelope, IngestError, ingest_message};
+use crate::listener::{ListenAddress, Listener, Stream};
+use anyhow::Result;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::signal;
+use tokio::time::timeout;
+use tracing::{debug, error, info, warn};
+
+const MAX_LINE_LENGTH: u64 = 4096;
+
+/// Outcome of reading a command line
+#[derive(Debug, PartialEq)]
+enum CommandLine {
+    Complete,
+    TooLong,
+    Closed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Protocol {
+    Lmtp,
+    Smtp,
+}
+
+struct Server {
+    config: AppConfig,
+    classifier: Classifier,
+    protocol: Protocol,
+    // Long-lived connections shared by all sessions instead of one per message
+    pool: DBPool,
+}
+
+pub async fn execute_serve_lmtp(
+    config: AppConfig,
+    db: DBConnection,
+    address: ListenAddress,
+    socket_mode: Option<u32>,
+    protocol: Protocol,
+) -> Result<()> {
+    let listener = Listener::bind(&address, socket_mode).await?;
+    let server = Arc::new(Server {
+        classifier: Classifier::new(config.classification_rules_file.as_deref())?,
+        pool: DBPool::new(db, config.lmtp_database_connections),
+        config,
+        protocol,
+    });
+    info!(protocol = ?protocol, "Server started");
+
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    error!(error = format!("{:#}", e), "Failed to accept connection");
+                    continue;
+                }
+            },
+            _ = signal::ctrl_c() => {
+                info!("Server shutting down");
+                break;
+            }
+        };
+
+        debug!(peer = peer, "Accepted connection");
+        let server = server.clone();
+        tokio::spawn(async move {
+            if let Err(e) = server.handle_session(stream).await {
+                warn!(peer = peer, error = format!("{:#}", e), "Session failed");
+            }
+            debug!(peer = peer, "Closed connection");
+        });
+    }
+
+    Ok(())
+}
+
+impl Server {
+    async fn handle_session(&self, stream: Box<dyn Stream>) -> Result<()> {
+        let (reader, mut writer) = tokio::io::split(stream);
+        let mut reader = BufReader::new(reader);
+        let protocol_name = match self.protocol {
+            Protocol::Lmtp => "LMTP",
+            Protocol::Smtp => "ESMTP",
+        };
+        let hostname = env!("CARGO_PKG_NAME");
+        let command_timeout = Duration::from_secs(self.config.lmtp_command_timeout_seconds);
+        let data_timeout = Duration::from_secs(self.config.lmtp_data_timeout_seconds);
+
+        reply(
+            &mut writer,
+            &format!("220 {} {} service ready", hostname, protocol_name),
+        )
+        .await?;
+
+        let mut greeted = false;
+        let mut sender: Option<Option<String>> = None;
+        let mut recipients: Vec<String> = Vec::new();
+        let mut line = Vec::new();
+
+        loop {
+            line.clear();
+            let Ok(read) = timeout(command_timeout, read_command(&mut reader, &mut line)).await
+            else {
+                reply(&mut writer, "421 4.4.2 Error: timeout exceeded").await?;
+                break;
+            };
+            match read? {
+                CommandLine::Complete => {}
+                CommandLine::TooLong => {
+                    reply(&mut writer, "500 5.5.2 Error: line too long").await?;
+                    continue;
+                }
+                CommandLine::Closed => break,
+            }
+            let command = String::from_utf8_lossy(&line);
+            let command = command.trim_end();
+            let (verb, args) = command.split_once(' ').unwrap_or((command, ""));
+
+            match (verb.to_ascii_uppercase().as_str(), self.protocol) {
+                ("LHLO", Protocol::Lmtp) | ("EHLO", Protocol::Smtp) => {
+                    greeted = true;
+                    sender = None;
+                    recipients.clear();
+                    let extensions = [
+                        hostname.to_string(),
+                        "PIPELINING".to_string(),
+                        "ENHANCEDSTATUSCODES".to_string(),
+                        "8BITMIME".to_string(),
+                        format!("SIZE {}", self.config.lmtp_max_message_size),
+                    ];
+                    reply_multiline(&mut writer, 250, &extensions).await?;
+                }
+                ("HELO", Protocol::Smtp) => {
+                    greeted = true;
+                    sender = None;
+                    recipients.clear();
+                    reply(&mut writer, &format!("250 {}", hostname)).await?;
+                }
+                ("MAIL", _) => {
+                    if !greeted {
+                        reply(&mut writer, "503 5.5.1 Error: send LHLO/EHLO first").await?;
+                    } else if sender.is_some() {
+                        reply(&mut writer, "503 5.5.1 Error: nested MAIL command").await?;
+                    } else if let Some(path) = parse_path(args, "FROM:") {
+                        // The null reverse-path (<>) is common for bounces
+                        sender = Some(Some(path).filter(|p| !p.is_empty()));
+                        reply(&mut writer, "250 2.1.0 Ok").await?;
+                    } else {
+                        reply(&mut writer, "501 5.5.4 Syntax: MAIL FROM:<address>").await?;
+                    }
+                }
+                ("RCPT", _) => {
+                    if sender.is_none() {
+                        reply(&mut writer, "503 5.5.1 Error: need MAIL command").await?;
+                    } else if self.protocol == Protocol::Smtp && !recipients.is_empty() {
+                        // SMTP has a single reply for all recipients, a temporary failure
+                        // of one would have the client resend the message to all of them
+                        reply(&mut writer, "452 4.5.3 Error: too many recipients").await?;
+                    } else if let Some(path) = parse_path(args, "TO:").filter(|p| !p.is_empty()) {
+                        recipients.push(path);
+                        reply(&mut writer, "250 2.1.5 Ok").await?;
+                    } else {
+                        reply(&mut writer, "501 5.5.4 Syntax: RCPT TO:<address>").await?;
+                    }
+                }
+                ("DATA", _) => {
+                    if recipients.is_empty() {
+                        reply(&mut writer, "503 5.5.1 Error: need RCPT command").await?;
+                        continue;
+                    }
+                    reply(&mut writer, "354 End data with <CR><LF>.<CR><LF>").await?;
+
+                    let max_size = self.config.lmtp_max_message_size;
+                    let Ok(message) = timeout(data_timeout, read_data(&mut reader, max_size)).await
+                    else {
+                        warn!("Timed out waiting for message data");
+                        reply(&mut writer, "421 4.4.2 Error: timeout exceeded").await?;
+                        break;
+                    };
+                    let message = message?;
+                    let envelope_sender = sender.take().flatten();
+                    let replies = match message {
+                        Some(message) => self.deliver(&message, envelope_sender, &recipients).await,
+                        None => {
+                            warn!("Rejected message exceeding maximum size");
+                            vec!["552 5.3.4 Error: message too large".to_string(); recipients.len()]
+                        }
+                    };
+                    recipients.clear();
+
+                    // One reply per recipient, SMTP transactions have a single one
+                    for line in replies {
+                        reply(&mut writer, &line).await?;
+                    }
+                }
+                ("RSET", _) => {
+                    sender = None;
+                    recipients.clear();
+                    reply(&mut writer, "250 2.0.0 Ok").await?;
+                }
+                ("NOOP", _) => reply(&mut writer, "250 2.0.0 Ok").await?,
+                ("VRFY", _) => reply(&mut writer, "252 2.5.0 Cannot VRFY user").await?,
+                ("QUIT", _) => {
+                    reply(&mut writer, "221 2.0.0 Bye").await?;
+                    break;
+                }
+                _ => reply(&mut writer, "500 5.5.2 Error: command not recognized").await?,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Runs the ingest pipeline once per recipient and returns one reply per recipient
+    async fn deliver(
+        &self,
+        message: &[u8],
+        sender: Option<String>,
+        recipients: &[String],
+    ) -> Vec<String> {
+        let mut replies = Vec::with_capacity(recipients.len());
+
+        for recipient in recipients {
+            let envelope = Envelope {
+                sender: sender.clone(),
+                recipient: Some(recipient.clone()),
+            };
+
+            let result = match self.pool.acquire(&self.config).await {
+                Ok(mut db) => {
+                    ingest_message(&self.config, &self.classifier, &mut db, message, &envelope)
+                        .await
+                }
+                Err(e) => Err(IngestError::Temporary(e)),
+            };
+            replies.push(match result {
+                Ok(_) => format!("250 2.0.0 <{}> Ok", recipient),
+                Err(e) => {
+                    error!(recipient = recipient, error = %e, "Failed to ingest message");
+                    match e {
+                        IngestError::Temporary(_) => format!(
+                            "451 4.3.0 <{}> Temporary failure, try again later",
+                            recipient
+                        ),
+                        IngestError::Data(_) => {
+                            format!("554 5.6.0 <{}> Message could not be parsed", recipient)
+                        }
+                    }
+                }
+            });
+        }
+
+        replies
+    }
+}
+
+async fn reply<W: AsyncWriteExt + Unpin>(writer: &mut W, line: &str) -> Result<()> {
+    writer.write_all(line.as_bytes()).await?;
+    writer.write_all(b"\r\n").await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+async fn reply_multiline<W: AsyncWriteExt + Unpin>(
+    writer: &mut W,
+    code: u16,
+    lines: &[String],
+) -> Result<()> {
+    for (index, line) in lines.iter().enumerate() {
+        let separator = if index + 1 == lines.len() { ' ' } else { '-' };
+        writer
+            .write_all(format!("{}{}{}\r\n", code, separator, line).as_bytes())
+            .await?;
+    }
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Reads up to the end of the line, or only the first `MAX_LINE_LENGTH` bytes of a
+/// longer line
+async fn read_line<R: AsyncBufRead + Unpin>(reader: &mut R, buffer: &mut Vec<u8>) -> Result<usize> {
+    Ok((&mut *reader)
+        .take(MAX_LINE_LENGTH)
+        .read_until(b'\n', buffer)
+        .await?)
+}
+
+/// Reads a command line. The remainder of a line longer than `MAX_LINE_LENGTH` is
+/// discarded, so that it is not taken for further commands.
+async fn read_command<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    buffer: &mut Vec<u8>,
+) -> Result<CommandLine> {
+    if read_line(reader, buffer).await? == 0 {
+        return Ok(CommandLine::Closed);
+    }
+    // A shorter line without line ending is the last one before the connection closed
+    if buffer.ends_with(b"\n") || (buffer.len() as u64) < MAX_LINE_LENGTH {
+        return Ok(CommandLine::Complete);
+    }
+
+    let mut rest = Vec::new();
+    loop {
+        rest.clear();
+        if read_line(reader, &mut rest).await? == 0 || rest.ends_with(b"\n") {
+            return Ok(CommandLine::TooLong);
+        }
+    }
+}
+
+/// Reads the message after DATA up to the terminating `.` line, undoing dot-stuffing.
+/// Lines longer than `MAX_LINE_LENGTH` are read in parts, only the start of a line is
+/// checked for the terminator and dot-stuffing. Returns `None` if the message exceeds
+/// `max_size`.
+async fn read_data<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    max_size: usize,
+) -> Result<Option<Vec<u8>>> {
+    let mut message = Vec::new();
+    let mut too_large = false;
+    let mut line = Vec::new();
+    let mut at_line_start = true;
+
+    loop {
+        line.clear();
+        if read_line(reader, &mut line).await? == 0 {
+            anyhow::bail!("Connection closed during DATA");
+        }
+        let line_start = at_line_start;
+        at_line_start = line.ends_with(b"\n");
+        if line_start && (line == b".\r\n" || line == b".\n") {
+            break;
+        }
+        if too_large {
+            continue;
+        }
+
+        let content = if line_start {
+            line.strip_prefix(b".").unwrap_or(&line)
+        } else {
+            &line
+        };
+        if message.len() + content.len() > max_size {
+            too_large = true;
+            message.clear();
+        } else {
+            message.extend_from_slice(content);
+        }
+    }
+
+    Ok(Some(message).filter(|_| !too_large))
+}
+
+/// Extracts the address of `FROM:<address>` or `TO:<address>`, ignoring any
+/// parameters following it
+fn parse_path(args: &str, prefix: &str) -> Option<String> {
+    let args = args.trim_start();
+    if !args
+        .get(..prefix.len())
+        .is_some_and(|start| start.eq_ignore_ascii_case(prefix))
+    {
+        return None;
+    }
+    let path = args[prefix.len()..].trim_start();
+    let path = path.strip_prefix('<')?;
+    let end = path.find('>')?;
+    Some(path[..end].to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{BounceEvent, connect_test_database};
+    use crate::test_config;
+    use sea_query::{Expr, Query};
+    use sea_query_binder::SqlxBinder;
+    use sqlx::Row;
+
+    const BOUNCE: &str = include_str!("../tests/fixtures/dsn/postfix_multi.eml");
+
+    async fn test_server(config: AppConfig, protocol: Protocol) -> Server {
+        // A single connection, further in-memory connections would see an empty database
+        let pool = DBPool::new(connect_test_database().await, 1);
+        Server {
+            config,
+            classifier: Classifier::new(None).unwrap(),
+            protocol,
+            pool,
+        }
+    }
+
+    /// Runs a session with the client sending `input` and returns the reply lines
+    async fn session(protocol: Protocol, input: &str) -> Vec<String> {
+        let server = test_server(test_config(), protocol).await;
+        server_session(&server, input).await
+    }
+
+    async fn server_session(server: &Server, input: &str) -> Vec<String> {
+        let (mut client, stream) = tokio::io::duplex(1 << 20);
+        client.write_all(input.as_bytes()).await.unwrap();
+        client.shutdown().await.unwrap();
+        server.handle_session(Box::new(stream)).await.unwrap();
+
+        let mut output = String::new();
+        client.read_to_string(&mut output).await.unwrap();
+        output.lines().map(str::to_string).collect()
+    }
+
+    async fn data(input: &[u8], max_size: usize) -> (Result<Option<Vec<u8>>>, Vec<u8>) {
+        let mut reader = input;
+        let message = read_data(&mut reader, max_size).await;
+        (message, reader.to_vec())
+    }
+
+    #[tokio::test]
+    async fn session_replies_once_per_recipient() {
+        let input = format!(
+            "LHLO mx.example.com\r\n\
+             MAIL FROM:<>\r\n\
+             RCPT TO:<bounces+campaign42@example.com>\r\n\
+             RCPT TO:<other@example.com>\r\n\
+             DATA\r\n\
+             {}\r\n.\r\n\
+             QUIT\r\n",
+            BOUNCE.replace('\n', "\r\n").trim_end()
+        );
+        let replies = session(Protocol::Lmtp, &input).await;
+
+        assert_eq!(
+            replies,
+            [
+                "220 bounce-relay LMTP service ready",
+                "250-bounce-relay",
+                "250-PIPELINING",
+                "250-ENHANCEDSTATUSCODES",
+                "250-8BITMIME",
+                "250 SIZE 26214400",
+                "250 2.1.0 Ok",
+                "250 2.1.5 Ok",
+                "250 2.1.5 Ok",
+                "354 End data with <CR><LF>.<CR><LF>",
+                "250 2.0.0 <bounces+campaign42@example.com> Ok",
+                "250 2.0.0 <other@example.com> Ok",
+                "221 2.0.0 Bye",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn smtp_session_accepts_one_recipient_per_transaction() {
+        let server = test_server(test_config(), Protocol::Smtp).await;
+        let bounce = BOUNCE.replace('\n', "\r\n");
+        let input = format!(
+            "EHLO mx.example.com\r\n\
+             MAIL FROM:<>\r\n\
+             RCPT TO:<bounces@example.com>\r\n\
+             RCPT TO:<other@example.com>\r\n\
+             DATA\r\n\
+             {0}\r\n.\r\n\
+             MAIL FROM:<>\r\n\
+             RCPT TO:<other@example.com>\r\n\
+             DATA\r\n\
+             {0}\r\n.\r\n\
+             QUIT\r\n",
+            bounce.trim_end()
+        );
+        let replies = server_session(&server, &input).await;
+
+        assert_eq!(
+            replies[6..],
+            [
+                "250 2.1.0 Ok",
+                "250 2.1.5 Ok",
+                "452 4.5.3 Error: too many recipients",
+                "354 End data with <CR><LF>.<CR><LF>",
+                "250 2.0.0 <bounces@example.com> Ok",
+                "250 2.1.0 Ok",
+                "250 2.1.5 Ok",
+                "354 End data with <CR><LF>.<CR><LF>",
+                "250 2.0.0 <other@example.com> Ok",
+                "221 2.0.0 Bye",
+            ]
+        );
+
+        // Each transaction ingested the three bounced recipients once
+        let mut db = server.pool.acquire(&server.config).await.unwrap();
+        let (sql, values) = Query::select()
+            .expr(Expr::col(BounceEvent::Id).count())
+            .from(BounceEvent::Table)
+            .build_any_sqlx(&*db.query_builder);
+        let events: i64 = sqlx::query_with(&sql, values)
+            .fetch_one(&mut db.connection)
+            .await
+            .unwrap()
+            .try_get(0)
+            .unwrap();
+        assert_eq!(events, 6);
+    }
+
+    #[tokio::test]
+    async fn session_rejects_commands_out_of_order() {
+        let input = "MAIL FROM:<>\r\n\
+                     EHLO mx.example.com\r\n\
+                     LHLO mx.example.com\r\n\
+                     RCPT TO:<bounces@example.com>\r\n\
+                     DATA\r\n\
+                     MAIL FROM:<>\r\n\
+                     MAIL FROM:<>\r\n\
+                     RSET\r\n\
+                     RCPT TO:<bounces@example.com>\r\n\
+                     MAIL FROM:<>\r\n\
+                     DATA\r\n";
+        let replies = session(Protocol::Lmtp, input).await;
+
+        assert_eq!(replies[1], "503 5.5.1 Error: send LHLO/EHLO first");
+        assert_eq!(replies[2], "500 5.5.2 Error: command not recognized");
+        assert_eq!(
+            replies[8..],
+            [
+                "503 5.5.1 Error: need MAIL command",
+                "503 5.5.1 Error: need RCPT command",
+                "250 2.1.0 Ok",
+                "503 5.5.1 Error: nested MAIL command",
+                "250 2.0.0 Ok",
+                "503 5.5.1 Error: need MAIL command",
+                "250 2.1.0 Ok",
+                "503 5.5.1 Error: need RCPT command",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn session_discards_long_command_lines() {
+        let input = format!("NOOP {}RSET\r\nNOOP\r\n", "x".repeat(5000));
+        let replies = session(Protocol::Lmtp, &input).await;
+
+        assert_eq!(
+            replies[1..],
+            ["500 5.5.2 Error: line too long", "250 2.0.0 Ok"]
+        );
+    }
+
+    #[tokio::test]
+    async fn session_times_out_waiting_for_commands() {
+        let mut config = test_config();
+        config.lmtp_command_timeout_seconds = 1;
+        let server = test_server(config, Protocol::Lmtp).await;
+        let (mut client, stream) = tokio::io::duplex(1 << 20);
+        client.write_all(b"LHLO mx.example.com\r\n").await.unwrap();
+        server.handle_session(Box::new(stream)).await.unwrap();
+
+        let mut output = String::new();
+        client.read_to_string(&mut output).await.unwrap();
+        assert!(output.ends_with("421 4.4.2 Error: timeout exceeded\r\n"));
+    }
+
+    #[tokio::test]
+    async fn read_data_undoes_dot_stuffing() {
+        let (message, rest) = data(b"Subject: Hi\r\n..leading dot\r\n.\r\nQUIT\r\n", 1024).await;
+
+        assert_eq!(
+            message.unwrap().unwrap(),
+            b"Subject: Hi\r\n.leading dot\r\n"
+        );
+        assert_eq!(rest, b"QUIT\r\n");
+    }
+
+    #[tokio::test]
+    async fn read_data_keeps_long_lines_whole() {
+        // The parts of the long line must not be taken for the terminator or unstuffed
+        let long_line = format!("{}..\r\n{}.\r\n", "a".repeat(4096), "b".repeat(4096));
+        let input = format!("{}.\r\nQUIT\r\n", long_line);
+        let (message, rest) = data(input.as_bytes(), 1 << 20).await;
+
+        assert_eq!(message.unwrap().unwrap(), long_line.as_bytes());
+        assert_eq!(rest, b"QUIT\r\n");
+    }
+
+    #[tokio::test]
+    async fn read_data_rejects_oversize_message() {
+        let (message, rest) = data(b"0123456789\r\nabc\r\n.\r\nQUIT\r\n", 10).await;
+
+        assert_eq!(message.unwrap(), None);
+        assert_eq!(rest, b"QUIT\r\n");
+    }
+
+    #[tokio::test]
+    async fn read_data_fails_on_closed_connection() {
+        let (message, _) = data(b"Subject: Hi\r\n", 1024).await;
+
+        assert!(message.is_err());
+    }
+
+    #[test]
+    fn parse_path_extracts_address() {
+        assert_eq!(
+            parse_path("FROM:<bounce@example.com> SIZE=1024", "FROM:"),
+            Some("bounce@example.com".to_string())
+        );
+        assert_eq!(
+            parse_path(" to: <User@Example.com>", "TO:"),
+            Some("User@Example.com".to_string())
+        );
+        assert_eq!(parse_path("FROM:<>", "FROM:"), Some(String::new()));
+    }
+
+    #[test]
+    fn parse_path_rejects_invalid_arguments() {
+        assert_eq!(parse_path("TO:<user@example.com>", "FROM:"), None);
+        assert_eq!(parse_path("FROM:user@example.com", "FROM:"), None);
+        assert_eq!(parse_path("FROM:<user@example.com", "FROM:"), None);
+        assert_eq!(parse_path("FRO", "FROM:"), None);
+        // The prefix length falls inside a multi-byte character
+        assert_eq!(parse_path("FRéé", "FROM:"), None);
+    }
+}
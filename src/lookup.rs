@@ -0,0 +1,294 @@
+use crate::AppConfig;
+use crate::db::{DBConnection, DBPool};
+use crate::listener::{ListenAddress, Listener, Stream};
+use crate::suppression::{SuppressionReason, find_suppression};
+use anyhow::{Context, Result, bail};
+use std::sync::Arc;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::signal;
+use tracing::{debug, error, info, warn};
+
+/// Maximum length of a lookup request, same as the Postfix socketmap client
+const MAX_REQUEST_LENGTH: u64 = 100000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LookupProtocol {
+    /// Netstring based socketmap protocol
+    Socketmap,
+    /// Line based tcp_table protocol
+    TcpTable,
+}
+
+/// Result of a single lookup
+enum Lookup {
+    Found(String),
+    NotFound,
+    Failed(String),
+}
+
+struct Server {
+    config: AppConfig,
+    protocol: LookupProtocol,
+    reasons: Vec<SuppressionReason>,
+    pool: DBPool,
+}
+
+pub async fn execute_serve_lookup(
+    config: AppConfig,
+    db: DBConnection,
+    address: ListenAddress,
+    socket_mode: Option<u32>,
+    protocol: LookupProtocol,
+) -> Result<()> {
+    let reasons = SuppressionReason::parse_list(&config.lookup_suppression_reasons)
+        .with_context(|| "Invalid lookup_suppression_reasons setting")?;
+    let listener = Listener::bind(&address, socket_mode).await?;
+    let server = Arc::new(Server {
+        pool: DBPool::new(db, config.lookup_database_connections),
+        config,
+        protocol,
+        reasons,
+    });
+    info!(protocol = ?protocol, "Lookup server started");
+
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    error!(error = format!("{:#}", e), "Failed to accept connection");
+                    continue;
+                }
+            },
+            _ = signal::ctrl_c() => {
+                info!("Lookup server shutting down");
+                break;
+            }
+        };
+
+        debug!(peer = peer, "Accepted connection");
+        let server = server.clone();
+        tokio::spawn(async move {
+            if let Err(e) = server.handle_session(stream).await {
+                warn!(peer = peer, error = format!("{:#}", e), "Session failed");
+            }
+            debug!(peer = peer, "Closed connection");
+        });
+    }
+
+    Ok(())
+}
+
+impl Server {
+    /// Postfix keeps connections open and sends any number of requests
+    async fn handle_session(&self, stream: Box<dyn Stream>) -> Result<()> {
+        let (reader, mut writer) = tokio::io::split(stream);
+        let mut reader = BufReader::new(reader);
+
+        match self.protocol {
+            LookupProtocol::Socketmap => {
+                while let Some(request) = read_netstring(&mut reader).await? {
+                    let request = String::from_utf8_lossy(&request);
+                    // Requests are "<map name> <key>", the map name is not used
+                    let key = request.split_once(' ').map(|(_, key)| key).unwrap_or("");
+                    let response = match self.lookup(key).await {
+                        Lookup::Found(value) => format!("OK {}", value),
+                        Lookup::NotFound => "NOTFOUND ".to_string(),
+                        Lookup::Failed(reason) => format!("TEMP {}", reason),
+                    };
+                    writer
+                        .write_all(format!("{}:{},", response.len(), response).as_bytes())
+                        .await?;
+                    writer.flush().await?;
+                }
+            }
+            LookupProtocol::TcpTable => {
+                let mut line = Vec::new();
+                loop {
+                    line.clear();
+                    if (&mut reader)
+                        .take(MAX_REQUEST_LENGTH)
+                        .read_until(b'\n', &mut line)
+                        .await?
+                        == 0
+                    {
+                        break;
+                    }
+                    let request = String::from_utf8_lossy(&line);
+                    let response = match request.trim_end().split_once(' ') {
+                        Some((command, key)) if command.eq_ignore_ascii_case("get") => {
+                            match self.lookup(&url_decode(key)).await {
+                                Lookup::Found(value) => format!("200 {}", url_encode(&value)),
+                                Lookup::NotFound => "500 not found".to_string(),
+                                Lookup::Failed(reason) => format!("400 {}", url_encode(&reason)),
+                            }
+                        }
+                        _ => "500 unsupported request".to_string(),
+                    };
+                    writer.write_all(response.as_bytes()).await?;
+                    writer.write_all(b"\n").await?;
+                    writer.flush().await?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Looks up a recipient address. Postfix also queries partial keys such as the
+    /// bare domain, which never match.
+    async fn lookup(&self, key: &str) -> Lookup {
+        let key = key.trim();
+        if key
+            .split_once('@')
+            .is_none_or(|(user, domain)| user.is_empty() || domain.is_empty())
+        {
+            return Lookup::NotFound;
+        }
+
+        let result = match self.pool.acquire(&self.config).await {
+            Ok(mut db) => find_suppression(&mut db, key, &self.reasons).await,
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(Some(reason)) => {
+                debug!(email = key, reason = reason, "Lookup matched suppression");
+                Lookup::Found(self.config.suppression_action.clone())
+            }
+            Ok(None) => Lookup::NotFound,
+            Err(e) => {
+                error!(email = key, error = format!("{:#}", e), "Lookup failed");
+                Lookup::Failed("database unavailable".to_string())
+            }
+        }
+    }
+}
+
+/// Reads a netstring (`<length>:<data>,`). Returns `None` once the client disconnects.
+async fn read_netstring<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut length = Vec::new();
+    if (&mut *reader)
+        .take(MAX_REQUEST_LENGTH.to_string().len() as u64 + 1)
+        .read_until(b':', &mut length)
+        .await?
+        == 0
+    {
+        return Ok(None);
+    }
+    if length.pop() != Some(b':') {
+        bail!("Invalid netstring length");
+    }
+    let length = String::from_utf8_lossy(&length).parse::<u64>()?;
+    if length > MAX_REQUEST_LENGTH {
+        bail!("Request of {} bytes exceeds the maximum length", length);
+    }
+
+    let mut data = vec![0; length as usize + 1];
+    reader.read_exact(&mut data).await?;
+    if data.pop() != Some(b',') {
+        bail!("Netstring is not terminated by a comma");
+    }
+    Ok(Some(data))
+}
+
+/// Decodes the `%XX` escapes of tcp_table requests
+fn url_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        let escaped = bytes
+            .get(index + 1..index + 3)
+            .filter(|_| bytes[index] == b'%')
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                index += 3;
+            }
+            None => {
+                decoded.push(bytes[index]);
+                index += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+/// Escapes whitespace, `%` and non-printable characters of tcp_table replies
+fn url_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| {
+            if byte.is_ascii_graphic() && byte != b'%' {
+                (byte as char).to_string()
+            } else {
+                format!("%{:02X}", byte)
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn netstrings(mut input: &[u8]) -> Result<Vec<String>> {
+        let mut values = Vec::new();
+        while let Some(value) = read_netstring(&mut input).await? {
+            values.push(String::from_utf8(value)?);
+        }
+        Ok(values)
+    }
+
+    #[tokio::test]
+    async fn read_netstrings() {
+        assert_eq!(
+            netstrings(b"27:suppressed user@example.org,0:,")
+                .await
+                .unwrap(),
+            ["suppressed user@example.org", ""]
+        );
+        assert!(netstrings(b"").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn reject_invalid_netstrings() {
+        for input in [
+            &b"5:hello;"[..],
+            b"5:hel",
+            b"x:hello,",
+            b"-1:,",
+            b"100001:",
+            b"1234567:",
+            b"5hello,",
+        ] {
+            assert!(
+                netstrings(input).await.is_err(),
+                "{}",
+                String::from_utf8_lossy(input)
+            );
+        }
+    }
+
+    #[test]
+    fn url_decode_escapes() {
+        assert_eq!(url_decode("user%40example.org%20x"), "user@example.org x");
+        assert_eq!(url_decode("J%C3%B6rg"), "Jörg");
+        // Incomplete or invalid escapes are kept
+        assert_eq!(url_decode("100%"), "100%");
+        assert_eq!(url_decode("%zz%4"), "%zz%4");
+        assert_eq!(url_decode("%%41"), "%A");
+    }
+
+    #[test]
+    fn url_encode_round_trip() {
+        let reply = "REJECT Recipient address is suppressed (100%)";
+        assert_eq!(
+            url_encode(reply),
+            "REJECT%20Recipient%20address%20is%20suppressed%20(100%25)"
+        );
+        assert_eq!(url_decode(&url_encode(reply)), reply);
+        assert_eq!(url_encode("Jörg"), "J%C3%B6rg");
+    }
+}
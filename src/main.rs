@@ -1,17 +1,56 @@
+mod backoff;
+mod classify;
 mod db;
+mod dead_letter;
 mod ingest;
+mod listener;
+mod lmtp;
+mod lookup;
+mod match_rules;
+mod migrate;
+mod ndr;
+mod output;
+mod queue;
+mod response_policy;
+mod route;
+mod spool;
+mod suppression;
 mod worker;
 
-use crate::db::{connect_database, initialize_database};
-use crate::ingest::execute_ingest;
+use crate::classify::Classifier;
+use crate::db::connect_database;
+use crate::dead_letter::{
+    DeadLetterFilter, DeadLetterSelection, execute_dead_letter_list, execute_dead_letter_purge,
+    execute_dead_letter_redrive, execute_dead_letter_show,
+};
+use crate::ingest::{EX_TEMPFAIL, Envelope, RecipientPrecedence, VerpDelimiters, execute_ingest};
+use crate::listener::{ListenAddress, parse_socket_mode};
+use crate::lmtp::{Protocol, execute_serve_lmtp};
+use crate::lookup::{LookupProtocol, execute_serve_lookup};
+use crate::migrate::{ensure_schema_current, execute_migrate_status, execute_migrate_up};
+use crate::output::OutputFormat;
+use crate::queue::{
+    QueueFilter, QueueStatus, execute_queue_delete, execute_queue_list, execute_queue_purge,
+    execute_queue_retry, execute_queue_show,
+};
+use crate::route::{
+    RouteOptions, execute_route_add, execute_route_delete, execute_route_list,
+    execute_route_set_enabled, execute_route_show, execute_route_update,
+};
+use crate::spool::replay_spool;
+use crate::suppression::{
+    ExportFormat, execute_suppression_add, execute_suppression_export, execute_suppression_list,
+    execute_suppression_remove,
+};
 use crate::worker::execute_worker;
 use anyhow::{Context, Result};
 use clap::{Parser, Subcommand};
-use config::Config;
+use config::builder::DefaultState;
+use config::{Config, ConfigBuilder};
 use serde::Deserialize;
 use std::path::PathBuf;
 use std::process::ExitCode;
-use tracing::{debug, info};
+use tracing::{debug, error, info};
 use tracing_subscriber::EnvFilter;
 
 #[derive(Parser)]
@@ -35,16 +74,290 @@ struct Cli {
 
 #[derive(Subcommand)]
 enum Commands {
-    /// Initialize the database schema
+    /// Initialize the database schema, or upgrade it by applying pending migrations
     Init {
-        /// Print the SQL statements instead of executing them
+        /// Print the SQL statements of pending migrations instead of executing them
         #[arg(long)]
         print: bool,
     },
+    /// Manage database schema migrations
+    Migrate {
+        #[command(subcommand)]
+        command: MigrateCommands,
+    },
     /// Process incoming email from Postfix
-    Ingest,
+    ///
+    /// Reads a single email from stdin. The exit code follows sysexits.h, so the Postfix
+    /// pipe transport can act on it:
+    ///
+    /// 0 (EX_OK): the email was queued, spooled for later replay, or intentionally
+    /// ignored (not a bounce notification or no matching route).
+    ///
+    /// 65 (EX_DATAERR): the email cannot be parsed, retrying will not help.
+    ///
+    /// 75 (EX_TEMPFAIL): transient failure such as an unavailable database or invalid
+    /// settings, and the email could not be spooled either. Postfix defers the email
+    /// and retries later.
+    Ingest {
+        /// SMTP envelope recipient (Postfix ${recipient})
+        #[arg(long, value_name = "ADDRESS")]
+        recipient: Option<String>,
+        /// SMTP envelope sender (Postfix ${sender})
+        #[arg(long, value_name = "ADDRESS")]
+        sender: Option<String>,
+    },
+    /// Accept incoming email from Postfix via LMTP (or SMTP)
+    ServeLmtp {
+        /// Address to listen on (tcp:<host>:<port> or unix:<path>), overrides lmtp_listen
+        #[arg(long, value_name = "ADDRESS")]
+        listen: Option<String>,
+        /// Permissions of the unix socket, in octal (e.g. 0660)
+        #[arg(long, value_name = "MODE")]
+        socket_mode: Option<String>,
+        /// Speak SMTP instead of LMTP
+        #[arg(long)]
+        smtp: bool,
+    },
+    /// Answer Postfix socketmap (or tcp_table) lookups against the suppression list
+    ServeLookup {
+        /// Address to listen on (tcp:<host>:<port> or unix:<path>), overrides lookup_listen
+        #[arg(long, value_name = "ADDRESS")]
+        listen: Option<String>,
+        /// Permissions of the unix socket, in octal (e.g. 0660)
+        #[arg(long, value_name = "MODE")]
+        socket_mode: Option<String>,
+        /// Speak the tcp_table protocol instead of socketmap
+        #[arg(long)]
+        tcp_table: bool,
+    },
     /// Run the background worker
     Worker,
+    /// Manage emails spooled while the database was unavailable
+    Spool {
+        #[command(subcommand)]
+        command: SpoolCommands,
+    },
+    /// Manage addresses suppressed after hard bounces, complaints or repeated soft bounces
+    Suppression {
+        #[command(subcommand)]
+        command: SuppressionCommands,
+    },
+    /// Manage the routes forwarding bounces of a domain to a webhook
+    Route {
+        #[command(subcommand)]
+        command: RouteCommands,
+    },
+    /// Inspect and operate the webhook queue
+    Queue {
+        #[command(subcommand)]
+        command: QueueCommands,
+    },
+    /// Inspect and re-drive webhooks the worker gave up on
+    DeadLetter {
+        #[command(subcommand)]
+        command: DeadLetterCommands,
+    },
+}
+
+#[derive(Subcommand)]
+enum MigrateCommands {
+    /// Apply pending migrations
+    Up,
+    /// List migrations and whether they were applied
+    Status,
+}
+
+#[derive(Subcommand)]
+enum SpoolCommands {
+    /// Feed spooled emails back through the ingest pipeline
+    Replay,
+}
+
+#[derive(Subcommand)]
+enum SuppressionCommands {
+    /// List suppressed addresses
+    List {
+        /// Only list suppressions of this route domain
+        #[arg(long)]
+        domain: Option<String>,
+    },
+    /// Suppress an address manually
+    Add {
+        /// Email address to suppress
+        email: String,
+        /// Route domain the suppression applies to
+        #[arg(long)]
+        domain: String,
+        /// Optional note stored with the suppression
+        #[arg(long)]
+        description: Option<String>,
+    },
+    /// Remove an address from the suppression list
+    Remove {
+        /// Email address to remove
+        email: String,
+        /// Only remove the suppression of this route domain
+        #[arg(long)]
+        domain: Option<String>,
+    },
+    /// Export suppressed addresses
+    Export {
+        /// Only export suppressions of this route domain
+        #[arg(long)]
+        domain: Option<String>,
+        /// Output format
+        #[arg(long, value_enum, default_value = "csv")]
+        format: ExportFormat,
+        /// Action written for each address in the postfix format, overrides
+        /// suppression_action
+        #[arg(long)]
+        action: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum RouteCommands {
+    /// Add a route
+    Add {
+        /// Route domain, *.<domain> for all subdomains or * for all domains
+        #[arg(long)]
+        domain: String,
+        /// Webhook URL (http or https)
+        #[arg(long)]
+        url: String,
+        /// Create the route disabled
+        #[arg(long)]
+        disabled: bool,
+        #[command(flatten)]
+        options: Box<RouteOptions>,
+    },
+    /// List routes, without their secret tokens
+    List {
+        /// Only list routes of this domain
+        #[arg(long)]
+        domain: Option<String>,
+        /// Output format
+        #[arg(long, value_enum, default_value = "table")]
+        format: OutputFormat,
+    },
+    /// Show a route including its secret token
+    Show {
+        /// Route ID
+        id: i32,
+        /// Output format
+        #[arg(long, value_enum, default_value = "table")]
+        format: OutputFormat,
+    },
+    /// Change settings of a route
+    Update {
+        /// Route ID
+        id: i32,
+        /// Route domain, *.<domain> for all subdomains or * for all domains
+        #[arg(long)]
+        domain: Option<String>,
+        /// Webhook URL (http or https)
+        #[arg(long)]
+        url: Option<String>,
+        #[command(flatten)]
+        options: Box<RouteOptions>,
+    },
+    /// Enable a route
+    Enable {
+        /// Route ID
+        id: i32,
+    },
+    /// Disable a route, its webhooks stay queued until it is enabled again
+    Disable {
+        /// Route ID
+        id: i32,
+    },
+    /// Delete a route
+    Delete {
+        /// Route ID
+        id: i32,
+        /// Also delete webhooks queued for the route
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum QueueCommands {
+    /// List queued webhooks
+    List {
+        #[command(flatten)]
+        filter: QueueFilter,
+        /// Only webhooks with this status
+        #[arg(long, value_enum)]
+        status: Option<QueueStatus>,
+        /// Maximum number of webhooks to list
+        #[arg(long, default_value_t = 100)]
+        limit: u64,
+        /// Output format
+        #[arg(long, value_enum, default_value = "table")]
+        format: OutputFormat,
+    },
+    /// Show a queued webhook including its payload and last error
+    Show {
+        /// Webhook ID
+        id: i32,
+        /// Output format
+        #[arg(long, value_enum, default_value = "table")]
+        format: OutputFormat,
+    },
+    /// Deliver webhooks on the next worker iteration, resetting their attempts
+    Retry {
+        /// Webhook IDs
+        #[arg(required = true)]
+        ids: Vec<i32>,
+    },
+    /// Delete webhooks with the status matching the filters
+    Purge {
+        #[command(flatten)]
+        filter: QueueFilter,
+        /// Webhooks with this status
+        #[arg(long, value_enum)]
+        status: QueueStatus,
+    },
+    /// Delete webhooks
+    Delete {
+        /// Webhook IDs
+        #[arg(required = true)]
+        ids: Vec<i32>,
+    },
+}
+
+#[derive(Subcommand)]
+enum DeadLetterCommands {
+    /// List webhooks given up on
+    List {
+        #[command(flatten)]
+        filter: DeadLetterFilter,
+        /// Maximum number of dead letters to list
+        #[arg(long, default_value_t = 100)]
+        limit: u64,
+        /// Output format
+        #[arg(long, value_enum, default_value = "table")]
+        format: OutputFormat,
+    },
+    /// Show a dead letter including its payload and every attempt
+    Show {
+        /// Dead letter ID
+        id: i32,
+        /// Output format
+        #[arg(long, value_enum, default_value = "table")]
+        format: OutputFormat,
+    },
+    /// Queue dead letters for delivery again, e.g. after the receiver was fixed
+    Redrive {
+        #[command(flatten)]
+        selection: DeadLetterSelection,
+    },
+    /// Delete dead letters
+    Purge {
+        #[command(flatten)]
+        selection: DeadLetterSelection,
+    },
 }
 
 #[derive(Debug, Deserialize)]
@@ -53,59 +366,93 @@ pub struct AppConfig {
 
     pub log_level: String,
     pub recipient_delimiter: char,
+    pub case_sensitive_local_part: bool,
+    pub verp_delimiters: Option<VerpDelimiters>,
+    pub recipient_precedence: RecipientPrecedence,
+    pub classification_rules_file: Option<PathBuf>,
+    pub store_raw_message: bool,
+    pub suppression_soft_bounce_threshold: u32,
+    pub suppression_soft_bounce_window_days: u32,
+    pub suppression_action: String,
+
+    pub spool_directory: PathBuf,
+
+    pub lmtp_listen: String,
+    pub lmtp_max_message_size: usize,
+    pub lmtp_database_connections: usize,
+    pub lmtp_command_timeout_seconds: u64,
+    pub lmtp_data_timeout_seconds: u64,
+
+    pub lookup_listen: String,
+    pub lookup_suppression_reasons: String,
+    pub lookup_database_connections: usize,
 
     pub worker_max_retries: i32,
-    pub worker_max_delay_seconds: i64,
+    pub worker_max_delay_seconds: u64,
+    pub worker_retry_base_delay_seconds: u64,
+    pub worker_retry_multiplier: f64,
+    pub worker_retry_jitter: f64,
     pub worker_api_timeout_seconds: u64,
     pub worker_interval_seconds: u64,
     pub worker_items_per_iteration: u64,
+    pub worker_concurrency: usize,
+    pub worker_route_concurrency: usize,
+    pub worker_id: Option<String>,
+    pub worker_lease_seconds: u64,
+
+    pub notification_sendmail_path: PathBuf,
+    pub notification_from: Option<String>,
 }
 
 const LOG_LEVEL_DEFAULT: &str = "info";
 const RECIPIENT_DELIMITER_DEFAULT: char = '+';
+const RECIPIENT_PRECEDENCE_DEFAULT: &str = "envelope,x-original-to,delivered-to,to";
+const SUPPRESSION_SOFT_BOUNCE_THRESHOLD_DEFAULT: u32 = 3;
+const SUPPRESSION_SOFT_BOUNCE_WINDOW_DAYS_DEFAULT: u32 = 7;
+const SUPPRESSION_ACTION_DEFAULT: &str = "REJECT Recipient address is suppressed";
+const SPOOL_DIRECTORY_DEFAULT: &str = "/var/spool/bounce-relay";
+const LMTP_LISTEN_DEFAULT: &str = "tcp:127.0.0.1:2424";
+const LMTP_MAX_MESSAGE_SIZE_DEFAULT: usize = 25 * 1024 * 1024;
+const LMTP_DATABASE_CONNECTIONS_DEFAULT: usize = 4;
+const LMTP_COMMAND_TIMEOUT_SECONDS_DEFAULT: u64 = 60 * 5;
+const LMTP_DATA_TIMEOUT_SECONDS_DEFAULT: u64 = 60 * 10;
+const LOOKUP_LISTEN_DEFAULT: &str = "tcp:127.0.0.1:2425";
+const LOOKUP_SUPPRESSION_REASONS_DEFAULT: &str = "hard_bounce";
+const LOOKUP_DATABASE_CONNECTIONS_DEFAULT: usize = 4;
 const WORKER_MAX_RETRIES_DEFAULT: i32 = 50;
-const WORKER_MAX_DELAY_SECONDS_DEFAULT: i64 = 60 * 30;
+const WORKER_MAX_DELAY_SECONDS_DEFAULT: u64 = 60 * 30;
+const WORKER_RETRY_BASE_DELAY_SECONDS_DEFAULT: u64 = 60;
+const WORKER_RETRY_MULTIPLIER_DEFAULT: f64 = 2.0;
+const WORKER_RETRY_JITTER_DEFAULT: f64 = 0.1;
 const WORKER_API_TIMEOUT_SECONDS_DEFAULT: u64 = 60;
 const WORKER_INTERVAL_SECONDS_DEFAULT: u64 = 5;
 const WORKER_ITEMS_PER_ITERATION_DEFAULT: u64 = 50;
+const WORKER_CONCURRENCY_DEFAULT: usize = 10;
+const WORKER_ROUTE_CONCURRENCY_DEFAULT: usize = 2;
+const WORKER_LEASE_SECONDS_DEFAULT: u64 = 60 * 15;
+const NOTIFICATION_SENDMAIL_PATH_DEFAULT: &str = "/usr/sbin/sendmail";
 
 #[tokio::main]
 async fn main() -> Result<ExitCode> {
     let cli = Cli::parse();
+    // Postfix only retries ingest on EX_TEMPFAIL, any other failure bounces the email
+    let is_ingest = matches!(cli.command, Commands::Ingest { .. });
 
-    let mut config = Config::builder()
-        .set_default("log_level", LOG_LEVEL_DEFAULT)?
-        .set_default(
-            "recipient_delimiter",
-            RECIPIENT_DELIMITER_DEFAULT.to_string(),
-        )?
-        .set_default("worker_max_retries", WORKER_MAX_RETRIES_DEFAULT)?
-        .set_default("worker_max_delay_seconds", WORKER_MAX_DELAY_SECONDS_DEFAULT)?
-        .set_default(
-            "worker_api_timeout_seconds",
-            WORKER_API_TIMEOUT_SECONDS_DEFAULT,
-        )?
-        .set_default("worker_interval_seconds", WORKER_INTERVAL_SECONDS_DEFAULT)?
-        .set_default(
-            "worker_items_per_iteration",
-            WORKER_ITEMS_PER_ITERATION_DEFAULT,
-        )?
-        .add_source(config::File::with_name("./settings.toml").required(false))
-        .add_source(config::File::with_name("/etc/bounce-relay/settings.toml").required(false));
-    if let Some(ref config_path) = cli.config {
-        config = config.add_source(config::File::from(config_path.clone()));
-    }
-    let config = config
-        .add_source(config::Environment::with_prefix("BOUNCE_RELAY"))
-        .build()
-        .with_context(|| "failed to read application settings")?
-        .try_deserialize::<AppConfig>()
-        .with_context(|| "failed to parse application settings")?;
+    let config = match load_config(&cli) {
+        Ok(config) => config,
+        Err(e) if is_ingest => {
+            eprintln!("Error: {:#}", e);
+            return Ok(ExitCode::from(EX_TEMPFAIL));
+        }
+        Err(e) => return Err(e),
+    };
 
     // Resolve log level: CLI takes precedence over config
     let log_level = cli.log_level.as_deref().unwrap_or(&config.log_level);
 
+    // Logs go to stderr, so command output (e.g. suppression export) can be redirected
     tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
         .with_ansi(!cli.no_color)
         .with_env_filter(
             EnvFilter::from_default_env()
@@ -113,27 +460,314 @@ async fn main() -> Result<ExitCode> {
         )
         .init();
 
-    let db = connect_database(&config).await?;
-
     match cli.command {
         Commands::Init { print } => {
             if !print {
                 debug!("Executing init subcommand");
             }
-            initialize_database(db, print).await?;
+            let mut db = connect_database(&config).await?;
+            execute_migrate_up(&mut db, print).await?;
             if !print {
                 info!("Database schema initialized successfully");
             }
         }
-        Commands::Ingest => {
+        Commands::Migrate { command } => {
+            debug!("Executing migrate subcommand");
+            let mut db = connect_database(&config).await?;
+            match command {
+                MigrateCommands::Up => execute_migrate_up(&mut db, false).await?,
+                MigrateCommands::Status => execute_migrate_status(&mut db).await?,
+            }
+        }
+        Commands::Ingest { recipient, sender } => {
             debug!("Executing ingest subcommand");
-            execute_ingest(config, db).await?;
+            // Postfix passes an empty sender for the null reverse-path
+            let envelope = Envelope {
+                recipient: recipient.filter(|r| !r.is_empty()),
+                sender: sender.filter(|s| !s.is_empty()),
+            };
+            if let Err(e) = execute_ingest(config, envelope).await {
+                error!(error = %e, "Failed to ingest email");
+                return Ok(ExitCode::from(e.exit_code()));
+            }
+        }
+        Commands::ServeLmtp {
+            listen,
+            socket_mode,
+            smtp,
+        } => {
+            debug!("Executing serve-lmtp subcommand");
+            let address = listen
+                .as_deref()
+                .unwrap_or(&config.lmtp_listen)
+                .parse::<ListenAddress>()?;
+            let socket_mode = socket_mode.as_deref().map(parse_socket_mode).transpose()?;
+            let protocol = if smtp { Protocol::Smtp } else { Protocol::Lmtp };
+            let mut db = connect_database(&config).await?;
+            ensure_schema_current(&mut db).await?;
+            execute_serve_lmtp(config, db, address, socket_mode, protocol).await?;
+        }
+        Commands::ServeLookup {
+            listen,
+            socket_mode,
+            tcp_table,
+        } => {
+            debug!("Executing serve-lookup subcommand");
+            let address = listen
+                .as_deref()
+                .unwrap_or(&config.lookup_listen)
+                .parse::<ListenAddress>()?;
+            let socket_mode = socket_mode.as_deref().map(parse_socket_mode).transpose()?;
+            let protocol = if tcp_table {
+                LookupProtocol::TcpTable
+            } else {
+                LookupProtocol::Socketmap
+            };
+            let mut db = connect_database(&config).await?;
+            ensure_schema_current(&mut db).await?;
+            execute_serve_lookup(config, db, address, socket_mode, protocol).await?;
         }
         Commands::Worker => {
             debug!("Executing worker subcommand");
+            let mut db = connect_database(&config).await?;
+            ensure_schema_current(&mut db).await?;
             execute_worker(config, db).await?;
         }
+        Commands::Spool {
+            command: SpoolCommands::Replay,
+        } => {
+            debug!("Executing spool replay subcommand");
+            let mut db = connect_database(&config).await?;
+            ensure_schema_current(&mut db).await?;
+            let classifier = Classifier::new(config.classification_rules_file.as_deref())?;
+            let replayed = replay_spool(&config, &classifier, &mut db).await?;
+            info!(count = replayed, "Spool replay finished");
+        }
+        Commands::Suppression { command } => {
+            debug!("Executing suppression subcommand");
+            let mut db = connect_database(&config).await?;
+            match command {
+                SuppressionCommands::List { domain } => {
+                    execute_suppression_list(&mut db, domain.as_deref()).await?
+                }
+                SuppressionCommands::Add {
+                    email,
+                    domain,
+                    description,
+                } => {
+                    execute_suppression_add(&mut db, &domain, &email, description.as_deref())
+                        .await?
+                }
+                SuppressionCommands::Remove { email, domain } => {
+                    execute_suppression_remove(&mut db, domain.as_deref(), &email).await?
+                }
+                SuppressionCommands::Export {
+                    domain,
+                    format,
+                    action,
+                } => {
+                    let action = action.as_deref().unwrap_or(&config.suppression_action);
+                    execute_suppression_export(&mut db, domain.as_deref(), format, action).await?
+                }
+            }
+        }
+        Commands::Route { command } => {
+            debug!("Executing route subcommand");
+            let mut db = connect_database(&config).await?;
+            match command {
+                RouteCommands::Add {
+                    domain,
+                    url,
+                    disabled,
+                    options,
+                } => {
+                    execute_route_add(
+                        &mut db,
+                        &domain,
+                        &url,
+                        disabled,
+                        *options,
+                        config.case_sensitive_local_part,
+                    )
+                    .await?
+                }
+                RouteCommands::List { domain, format } => {
+                    execute_route_list(&mut db, domain.as_deref(), format).await?
+                }
+                RouteCommands::Show { id, format } => {
+                    execute_route_show(&mut db, id, format).await?
+                }
+                RouteCommands::Update {
+                    id,
+                    domain,
+                    url,
+                    options,
+                } => {
+                    execute_route_update(
+                        &mut db,
+                        id,
+                        domain.as_deref(),
+                        url.as_deref(),
+                        *options,
+                        config.case_sensitive_local_part,
+                    )
+                    .await?
+                }
+                RouteCommands::Enable { id } => {
+                    execute_route_set_enabled(&mut db, id, true).await?
+                }
+                RouteCommands::Disable { id } => {
+                    execute_route_set_enabled(&mut db, id, false).await?
+                }
+                RouteCommands::Delete { id, force } => {
+                    execute_route_delete(&mut db, id, force).await?
+                }
+            }
+        }
+        Commands::Queue { command } => {
+            debug!("Executing queue subcommand");
+            let mut db = connect_database(&config).await?;
+            match command {
+                QueueCommands::List {
+                    filter,
+                    status,
+                    limit,
+                    format,
+                } => execute_queue_list(&mut db, &filter, status, limit, format).await?,
+                QueueCommands::Show { id, format } => {
+                    execute_queue_show(&mut db, id, format).await?
+                }
+                QueueCommands::Retry { ids } => execute_queue_retry(&mut db, &ids).await?,
+                QueueCommands::Purge { filter, status } => {
+                    execute_queue_purge(&mut db, &filter, status).await?
+                }
+                QueueCommands::Delete { ids } => execute_queue_delete(&mut db, &ids).await?,
+            }
+        }
+        Commands::DeadLetter { command } => {
+            debug!("Executing dead-letter subcommand");
+            let mut db = connect_database(&config).await?;
+            match command {
+                DeadLetterCommands::List {
+                    filter,
+                    limit,
+                    format,
+                } => execute_dead_letter_list(&mut db, &filter, limit, format).await?,
+                DeadLetterCommands::Show { id, format } => {
+                    execute_dead_letter_show(&mut db, id, format).await?
+                }
+                DeadLetterCommands::Redrive { selection } => {
+                    execute_dead_letter_redrive(&mut db, &selection).await?
+                }
+                DeadLetterCommands::Purge { selection } => {
+                    execute_dead_letter_purge(&mut db, &selection).await?
+                }
+            }
+        }
     }
 
     Ok(ExitCode::SUCCESS)
 }
+
+fn load_config(cli: &Cli) -> Result<AppConfig> {
+    let mut config = config_defaults()?
+        .add_source(config::File::with_name("./settings.toml").required(false))
+        .add_source(config::File::with_name("/etc/bounce-relay/settings.toml").required(false));
+    if let Some(ref config_path) = cli.config {
+        config = config.add_source(config::File::from(config_path.clone()));
+    }
+    config
+        .add_source(config::Environment::with_prefix("BOUNCE_RELAY"))
+        .build()
+        .with_context(|| "failed to read application settings")?
+        .try_deserialize::<AppConfig>()
+        .with_context(|| "failed to parse application settings")
+}
+
+fn config_defaults() -> Result<ConfigBuilder<DefaultState>> {
+    Ok(Config::builder()
+        .set_default("log_level", LOG_LEVEL_DEFAULT)?
+        .set_default(
+            "recipient_delimiter",
+            RECIPIENT_DELIMITER_DEFAULT.to_string(),
+        )?
+        .set_default("case_sensitive_local_part", false)?
+        .set_default("recipient_precedence", RECIPIENT_PRECEDENCE_DEFAULT)?
+        .set_default("store_raw_message", false)?
+        .set_default(
+            "suppression_soft_bounce_threshold",
+            SUPPRESSION_SOFT_BOUNCE_THRESHOLD_DEFAULT,
+        )?
+        .set_default(
+            "suppression_soft_bounce_window_days",
+            SUPPRESSION_SOFT_BOUNCE_WINDOW_DAYS_DEFAULT,
+        )?
+        .set_default("suppression_action", SUPPRESSION_ACTION_DEFAULT)?
+        .set_default("spool_directory", SPOOL_DIRECTORY_DEFAULT)?
+        .set_default("lmtp_listen", LMTP_LISTEN_DEFAULT)?
+        .set_default(
+            "lmtp_max_message_size",
+            LMTP_MAX_MESSAGE_SIZE_DEFAULT as u64,
+        )?
+        .set_default(
+            "lmtp_database_connections",
+            LMTP_DATABASE_CONNECTIONS_DEFAULT as u64,
+        )?
+        .set_default(
+            "lmtp_command_timeout_seconds",
+            LMTP_COMMAND_TIMEOUT_SECONDS_DEFAULT,
+        )?
+        .set_default(
+            "lmtp_data_timeout_seconds",
+            LMTP_DATA_TIMEOUT_SECONDS_DEFAULT,
+        )?
+        .set_default("lookup_listen", LOOKUP_LISTEN_DEFAULT)?
+        .set_default(
+            "lookup_suppression_reasons",
+            LOOKUP_SUPPRESSION_REASONS_DEFAULT,
+        )?
+        .set_default(
+            "lookup_database_connections",
+            LOOKUP_DATABASE_CONNECTIONS_DEFAULT as u64,
+        )?
+        .set_default("worker_max_retries", WORKER_MAX_RETRIES_DEFAULT)?
+        .set_default("worker_max_delay_seconds", WORKER_MAX_DELAY_SECONDS_DEFAULT)?
+        .set_default(
+            "worker_retry_base_delay_seconds",
+            WORKER_RETRY_BASE_DELAY_SECONDS_DEFAULT,
+        )?
+        .set_default("worker_retry_multiplier", WORKER_RETRY_MULTIPLIER_DEFAULT)?
+        .set_default("worker_retry_jitter", WORKER_RETRY_JITTER_DEFAULT)?
+        .set_default(
+            "worker_api_timeout_seconds",
+            WORKER_API_TIMEOUT_SECONDS_DEFAULT,
+        )?
+        .set_default("worker_interval_seconds", WORKER_INTERVAL_SECONDS_DEFAULT)?
+        .set_default(
+            "worker_items_per_iteration",
+            WORKER_ITEMS_PER_ITERATION_DEFAULT,
+        )?
+        .set_default("worker_concurrency", WORKER_CONCURRENCY_DEFAULT as u64)?
+        .set_default(
+            "worker_route_concurrency",
+            WORKER_ROUTE_CONCURRENCY_DEFAULT as u64,
+        )?
+        .set_default("worker_lease_seconds", WORKER_LEASE_SECONDS_DEFAULT)?
+        .set_default(
+            "notification_sendmail_path",
+            NOTIFICATION_SENDMAIL_PATH_DEFAULT,
+        )?)
+}
+
+/// Default settings with an in-memory SQLite database, for tests
+#[cfg(test)]
+pub fn test_config() -> AppConfig {
+    config_defaults()
+        .unwrap()
+        .set_override("database_url", "sqlite::memory:")
+        .unwrap()
+        .build()
+        .unwrap()
+        .try_deserialize::<AppConfig>()
+        .unwrap()
+}
@@ -0,0 +1,230 @@
+use anyhow::{Context, Result};
+use regex::{Regex, RegexBuilder};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Conditions of the `match_rules` column of a route, as written in the database
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MatchRulesDefinition {
+    from: Option<String>,
+    subject: Option<String>,
+    #[serde(default)]
+    headers: HashMap<String, String>,
+    category: Option<Vec<String>>,
+    status: Option<String>,
+}
+
+/// Additional conditions a route places on the original message and the bounce. All
+/// given conditions must match.
+#[derive(Debug)]
+pub struct MatchRules {
+    from: Option<Regex>,
+    subject: Option<Regex>,
+    /// Header names without the `X-` prefix, in lowercase
+    headers: Vec<(String, Regex)>,
+    category: Option<Vec<String>>,
+    status: Option<Regex>,
+}
+
+impl MatchRules {
+    pub fn parse(json: &str) -> Result<Self> {
+        let definition = serde_json::from_str::<MatchRulesDefinition>(json)
+            .with_context(|| "Invalid match rules")?;
+
+        Ok(Self {
+            from: definition.from.as_deref().map(build_regex).transpose()?,
+            subject: definition.subject.as_deref().map(build_regex).transpose()?,
+            headers: definition
+                .headers
+                .iter()
+                .map(|(name, pattern)| {
+                    let name = name.to_lowercase();
+                    let name = name.strip_prefix("x-").unwrap_or(&name).to_string();
+                    Ok((name, build_regex(pattern)?))
+                })
+                .collect::<Result<_>>()?,
+            category: definition
+                .category
+                .map(|categories| categories.iter().map(|c| c.to_lowercase()).collect()),
+            status: definition.status.as_deref().map(build_regex).transpose()?,
+        })
+    }
+
+    /// Checks the conditions against a webhook payload. Category and status conditions
+    /// match batch payloads if any of their recipients matches, and never match
+    /// complaints.
+    pub fn matches(&self, payload: &Value) -> bool {
+        let text = |field: &str| payload[field].as_str().unwrap_or_default();
+
+        if self
+            .from
+            .as_ref()
+            .is_some_and(|from| !from.is_match(text("from")))
+        {
+            return false;
+        }
+        if self
+            .subject
+            .as_ref()
+            .is_some_and(|subject| !subject.is_match(text("subject")))
+        {
+            return false;
+        }
+
+        let metadata = payload["metadata"].as_object();
+        for (name, pattern) in &self.headers {
+            let value = metadata.and_then(|metadata| {
+                metadata
+                    .iter()
+                    .find(|(key, _)| key.eq_ignore_ascii_case(name))
+                    .and_then(|(_, value)| value.as_str())
+            });
+            if !value.is_some_and(|value| pattern.is_match(value)) {
+                return false;
+            }
+        }
+
+        if self.category.is_none() && self.status.is_none() {
+            return true;
+        }
+        let recipients = match payload["recipients"].as_array() {
+            Some(recipients) => recipients.iter().collect::<Vec<_>>(),
+            None => vec![payload],
+        };
+        recipients.into_iter().any(|recipient| {
+            let category_matches = self.category.as_ref().is_none_or(|categories| {
+                recipient["category"]
+                    .as_str()
+                    .is_some_and(|category| categories.iter().any(|c| c == category))
+            });
+            let status_matches = self.status.as_ref().is_none_or(|status| {
+                recipient["status"]
+                    .as_str()
+                    .is_some_and(|value| status.is_match(value))
+            });
+            category_matches && status_matches
+        })
+    }
+}
+
+fn build_regex(pattern: &str) -> Result<Regex> {
+    RegexBuilder::new(pattern)
+        .case_insensitive(true)
+        .build()
+        .with_context(|| format!("Invalid match pattern {}", pattern))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn bounce(category: &str, status: &str) -> Value {
+        json!({
+            "event": "bounce",
+            "from": "Newsletter <news@example.com>",
+            "subject": "Your Weekly Digest",
+            "metadata": { "Campaign-Id": "spring-42" },
+            "category": category,
+            "status": status,
+        })
+    }
+
+    fn batch(recipients: &[(&str, &str)]) -> Value {
+        let mut payload = bounce("", "");
+        payload.as_object_mut().unwrap().remove("category");
+        payload.as_object_mut().unwrap().remove("status");
+        payload["recipients"] = recipients
+            .iter()
+            .map(|(category, status)| json!({ "category": category, "status": status }))
+            .collect();
+        payload
+    }
+
+    fn matches(rules: &str, payload: &Value) -> bool {
+        MatchRules::parse(rules).unwrap().matches(payload)
+    }
+
+    #[test]
+    fn matches_from_and_subject_case_insensitively() {
+        let payload = bounce("hard", "5.1.1");
+        assert!(matches(r#"{"from": "NEWS@example\\.com"}"#, &payload));
+        assert!(!matches(r#"{"from": "^billing@"}"#, &payload));
+        assert!(matches(r#"{"subject": "weekly digest"}"#, &payload));
+        assert!(!matches(r#"{"subject": "^Invoice"}"#, &payload));
+        // All conditions must match
+        assert!(!matches(
+            r#"{"from": "news@", "subject": "^Invoice"}"#,
+            &payload
+        ));
+        assert!(matches("{}", &payload));
+    }
+
+    #[test]
+    fn matches_headers_with_or_without_prefix() {
+        let payload = bounce("hard", "5.1.1");
+        assert!(matches(
+            r#"{"headers": {"X-Campaign-Id": "^spring"}}"#,
+            &payload
+        ));
+        assert!(matches(
+            r#"{"headers": {"campaign-id": "SPRING-42"}}"#,
+            &payload
+        ));
+        assert!(!matches(
+            r#"{"headers": {"X-Campaign-Id": "^autumn"}}"#,
+            &payload
+        ));
+        // A missing header never matches
+        assert!(!matches(
+            r#"{"headers": {"X-Customer-Id": ".*"}}"#,
+            &payload
+        ));
+    }
+
+    #[test]
+    fn matches_category_and_status() {
+        let payload = bounce("soft", "4.2.2");
+        assert!(matches(r#"{"category": ["hard", "Soft"]}"#, &payload));
+        assert!(!matches(r#"{"category": ["hard"]}"#, &payload));
+        assert!(matches(r#"{"status": "^4\\."}"#, &payload));
+        assert!(!matches(r#"{"status": "^5\\."}"#, &payload));
+    }
+
+    #[test]
+    fn matches_batch_if_any_recipient_matches() {
+        let payload = batch(&[("soft", "4.2.2"), ("hard", "5.1.1")]);
+        assert!(matches(r#"{"category": ["hard"]}"#, &payload));
+        assert!(matches(r#"{"status": "^4\\."}"#, &payload));
+        // Both conditions must match the same recipient
+        assert!(!matches(
+            r#"{"category": ["hard"], "status": "^4\\."}"#,
+            &payload
+        ));
+        assert!(!matches(r#"{"category": ["block"]}"#, &payload));
+    }
+
+    #[test]
+    fn category_and_status_never_match_complaints() {
+        let payload = json!({
+            "event": "complaint",
+            "from": "news@example.com",
+            "subject": "Your Weekly Digest",
+            "metadata": {},
+        });
+        assert!(matches(r#"{"subject": "digest"}"#, &payload));
+        assert!(!matches(r#"{"category": ["hard", "soft"]}"#, &payload));
+        assert!(!matches(r#"{"status": ".*"}"#, &payload));
+    }
+
+    #[test]
+    fn parse_rejects_invalid_rules() {
+        assert!(MatchRules::parse("{").is_err());
+        assert!(MatchRules::parse(r#"{"subjects": "digest"}"#).is_err());
+        assert!(MatchRules::parse(r#"{"category": "hard"}"#).is_err());
+        assert!(MatchRules::parse(r#"{"subject": "("}"#).is_err());
+        assert!(MatchRules::parse(r#"{"headers": {"X-Campaign-Id": "["}}"#).is_err());
+    }
+}
@@ -0,0 +1,783 @@
+use crate::db::{
+    BounceEvent, DBConnection, DeadLetter, EmailRoute, SchemaMigration, Suppression, WebhookQueue,
+    timestamp_as_text,
+};
+use crate::dead_letter::DeadLetterReason;
+use crate::output::print_table;
+use anyhow::{Context, Result, bail};
+use sea_query::{
+    ColumnDef, Expr, ForeignKey, ForeignKeyAction, Index, InsertStatement, IntoTableRef,
+    MysqlQueryBuilder, Order, PostgresQueryBuilder, Query, QueryStatementWriter,
+    SqliteQueryBuilder, Table, TableAlterStatement,
+};
+use sea_query_binder::SqlxBinder;
+use sqlx::{Connection, Row};
+use std::collections::BTreeMap;
+use tracing::{debug, info};
+
+/// A schema change, applied once in order of its version. Released migrations must
+/// never be changed, add a new one instead.
+struct Migration {
+    version: i32,
+    name: &'static str,
+    statements: fn(&DBConnection) -> Vec<String>,
+}
+
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "baseline",
+        statements: baseline,
+    },
+    Migration {
+        version: 2,
+        name: "route_options",
+        statements: route_options,
+    },
+    Migration {
+        version: 3,
+        name: "bounce_events",
+        statements: bounce_events,
+    },
+    Migration {
+        version: 4,
+        name: "suppressions",
+        statements: suppressions,
+    },
+    Migration {
+        version: 5,
+        name: "route_delivery_options",
+        statements: route_delivery_options,
+    },
+    Migration {
+        version: 6,
+        name: "queue_leases",
+        statements: queue_leases,
+    },
+    Migration {
+        version: 7,
+        name: "route_retry_policy",
+        statements: route_retry_policy,
+    },
+    Migration {
+        version: 8,
+        name: "response_classification",
+        statements: response_classification,
+    },
+    Migration {
+        version: 9,
+        name: "dead_letters",
+        statements: dead_letters,
+    },
+];
+
+/// Applies all pending migrations, or prints their SQL statements instead
+pub async fn execute_migrate_up(db: &mut DBConnection, print_only: bool) -> Result<()> {
+    let create_table = create_migrations_table(db);
+    let applied = if print_only {
+        // Nothing has been applied if the table does not exist yet
+        load_applied(db).await.unwrap_or_default()
+    } else {
+        sqlx::query(&create_table)
+            .execute(&mut db.connection)
+            .await
+            .with_context(|| "Failed to create schema_migrations table")?;
+        load_applied(db).await?
+    };
+
+    let pending = MIGRATIONS
+        .iter()
+        .filter(|migration| !applied.contains_key(&migration.version))
+        .collect::<Vec<_>>();
+    if pending.is_empty() {
+        if !print_only {
+            info!("Database schema is up to date");
+        }
+        return Ok(());
+    }
+
+    if print_only {
+        for line in script_lines(db, &create_table, &pending) {
+            println!("{}", line);
+        }
+        return Ok(());
+    }
+
+    for migration in pending {
+        info!(
+            version = migration.version,
+            name = migration.name,
+            "Applying migration"
+        );
+        let statements = (migration.statements)(db);
+        let record = record_migration(db, migration);
+
+        // MySQL commits schema changes implicitly, a failed migration has to be fixed
+        // up manually there
+        let mut transaction = db.connection.begin().await?;
+        for statement in statements {
+            debug!(statement = statement, "Executing migration statement");
+            sqlx::query(&statement)
+                .execute(&mut *transaction)
+                .await
+                .with_context(|| format!("Migration {} failed", migration.version))?;
+        }
+        sqlx::query(&record).execute(&mut *transaction).await?;
+        transaction.commit().await?;
+    }
+    Ok(())
+}
+
+pub async fn execute_migrate_status(db: &mut DBConnection) -> Result<()> {
+    let rows = status_rows(db).await;
+    print_table(&["VERSION", "NAME", "STATUS", "APPLIED AT"], &rows);
+    Ok(())
+}
+
+/// Refuses to run long running services against a database with pending migrations,
+/// e.g. after a package upgrade
+pub async fn ensure_schema_current(db: &mut DBConnection) -> Result<()> {
+    let applied = load_applied(db).await.unwrap_or_default();
+    let pending = MIGRATIONS
+        .iter()
+        .filter(|migration| !applied.contains_key(&migration.version))
+        .count();
+    if pending > 0 {
+        bail!(
+            "Database schema is outdated ({} pending migrations), run `bounce-relay migrate up`",
+            pending
+        );
+    }
+    Ok(())
+}
+
+/// Returns the applied migration versions with the time they were applied
+async fn load_applied(db: &mut DBConnection) -> Result<BTreeMap<i32, String>> {
+    let query_builder = &*db.query_builder;
+    let (sql, values) = Query::select()
+        .column(SchemaMigration::Version)
+        .expr_as(
+            timestamp_as_text(db, SchemaMigration::AppliedAt),
+            SchemaMigration::AppliedAt,
+        )
+        .from(SchemaMigration::Table)
+        .order_by(SchemaMigration::Version, Order::Asc)
+        .build_any_sqlx(query_builder);
+    let rows = sqlx::query_with(&sql, values)
+        .fetch_all(&mut db.connection)
+        .await
+        .with_context(|| "Failed to load applied migrations")?;
+
+    rows.iter()
+        .map(|row| Ok((row.try_get(0)?, row.try_get(1)?)))
+        .collect()
+}
+
+/// Version, name, status and time applied of every migration
+async fn status_rows(db: &mut DBConnection) -> Vec<Vec<String>> {
+    let applied = load_applied(db).await.unwrap_or_default();
+    MIGRATIONS
+        .iter()
+        .map(|migration| {
+            let applied_at = applied.get(&migration.version);
+            vec![
+                migration.version.to_string(),
+                migration.name.to_string(),
+                if applied_at.is_some() {
+                    "applied"
+                } else {
+                    "pending"
+                }
+                .to_string(),
+                applied_at.cloned().unwrap_or_default(),
+            ]
+        })
+        .collect()
+}
+
+/// SQL script applying the pending migrations, for databases managed by hand
+fn script_lines(db: &DBConnection, create_table: &str, pending: &[&Migration]) -> Vec<String> {
+    let mut lines = vec![format!("{};", create_table)];
+    for migration in pending {
+        lines.push(format!("-- {} {}", migration.version, migration.name));
+        for statement in (migration.statements)(db) {
+            lines.push(format!("{};", statement));
+        }
+        lines.push(format!("{};", record_migration(db, migration)));
+    }
+    lines
+}
+
+fn create_migrations_table(db: &DBConnection) -> String {
+    Table::create()
+        .table(SchemaMigration::Table)
+        .if_not_exists()
+        .col(
+            ColumnDef::new(SchemaMigration::Version)
+                .integer()
+                .not_null()
+                .primary_key(),
+        )
+        .col(ColumnDef::new(SchemaMigration::Name).string().not_null())
+        .col(
+            ColumnDef::new(SchemaMigration::AppliedAt)
+                .timestamp_with_time_zone()
+                .not_null()
+                .default(Expr::current_timestamp()),
+        )
+        .build_any(&*db.schema_builder)
+}
+
+/// Builds the insert into schema_migrations
+fn record_migration(db: &DBConnection, migration: &Migration) -> String {
+    let mut statement = InsertStatement::new();
+    statement
+        .into_table(SchemaMigration::Table)
+        .columns([SchemaMigration::Version, SchemaMigration::Name])
+        .values_panic([migration.version.into(), migration.name.into()]);
+    inline_values(db, &statement)
+}
+
+/// Builds a statement with inlined values, so it can be printed
+fn inline_values(db: &DBConnection, statement: &impl QueryStatementWriter) -> String {
+    match db.connection.backend_name() {
+        "PostgreSQL" => statement.to_string(PostgresQueryBuilder),
+        "MySQL" => statement.to_string(MysqlQueryBuilder),
+        _ => statement.to_string(SqliteQueryBuilder),
+    }
+}
+
+/// SQLite supports a single change per ALTER TABLE statement, so every column is added
+/// separately
+fn add_column(db: &DBConnection, table: impl IntoTableRef, column: &mut ColumnDef) -> String {
+    TableAlterStatement::new()
+        .table(table)
+        .add_column(column)
+        .build_any(&*db.schema_builder)
+}
+
+/// The schema created by `init` before migrations were introduced. Existing databases
+/// have these tables already, so they are only created if missing.
+fn baseline(db: &DBConnection) -> Vec<String> {
+    let schema_builder = &*db.schema_builder;
+    vec![
+        Table::create()
+            .table(EmailRoute::Table)
+            .if_not_exists()
+            .col(
+                ColumnDef::new(EmailRoute::Id)
+                    .integer()
+                    .not_null()
+                    .auto_increment()
+                    .primary_key(),
+            )
+            .col(ColumnDef::new(EmailRoute::Domain).string().not_null())
+            .col(ColumnDef::new(EmailRoute::User).string().null())
+            .col(ColumnDef::new(EmailRoute::Url).string().not_null())
+            .col(ColumnDef::new(EmailRoute::SecretToken).string().not_null())
+            .col(
+                ColumnDef::new(EmailRoute::IsEnabled)
+                    .boolean()
+                    .not_null()
+                    .default(true),
+            )
+            .build_any(schema_builder),
+        Index::create()
+            .name("idx_route_lookup")
+            .if_not_exists()
+            .table(EmailRoute::Table)
+            .col(EmailRoute::Domain)
+            .col(EmailRoute::User)
+            .col(EmailRoute::IsEnabled)
+            .build_any(schema_builder),
+        Index::create()
+            .name("idx_route_enabled_lookup")
+            .if_not_exists()
+            .table(EmailRoute::Table)
+            .col(EmailRoute::IsEnabled)
+            .build_any(schema_builder),
+        Table::create()
+            .table(WebhookQueue::Table)
+            .if_not_exists()
+            .col(
+                ColumnDef::new(WebhookQueue::Id)
+                    .integer()
+                    .not_null()
+                    .auto_increment()
+                    .primary_key(),
+            )
+            .col(
+                ColumnDef::new(WebhookQueue::EmailRouteId)
+                    .integer()
+                    .not_null(),
+            )
+            .col(ColumnDef::new(WebhookQueue::Payload).text().not_null())
+            .col(
+                ColumnDef::new(WebhookQueue::Attempts)
+                    .unsigned()
+                    .integer()
+                    .not_null()
+                    .default(0),
+            )
+            .col(
+                ColumnDef::new(WebhookQueue::NextRetryAt)
+                    .timestamp_with_time_zone()
+                    .not_null()
+                    .default(Expr::current_timestamp()),
+            )
+            .col(ColumnDef::new(WebhookQueue::LastError).text().null())
+            .col(
+                ColumnDef::new(WebhookQueue::IsExpired)
+                    .boolean()
+                    .not_null()
+                    .default(false),
+            )
+            .col(
+                ColumnDef::new(WebhookQueue::CreatedAt)
+                    .timestamp_with_time_zone()
+                    .not_null()
+                    .default(Expr::current_timestamp()),
+            )
+            .foreign_key(
+                ForeignKey::create()
+                    .name("fk_queue_to_route")
+                    .from(WebhookQueue::Table, WebhookQueue::EmailRouteId)
+                    .to(EmailRoute::Table, EmailRoute::Id),
+            )
+            .build_any(schema_builder),
+        Index::create()
+            .name("idx_queue_processing")
+            .if_not_exists()
+            .table(WebhookQueue::Table)
+            .col(WebhookQueue::NextRetryAt)
+            .col(WebhookQueue::IsExpired)
+            .build_any(schema_builder),
+    ]
+}
+
+/// Batching, event type filters, address tags, stop_processing and match rules
+fn route_options(db: &DBConnection) -> Vec<String> {
+    vec![
+        add_column(
+            db,
+            EmailRoute::Table,
+            ColumnDef::new(EmailRoute::BatchRecipients)
+                .boolean()
+                .not_null()
+                .default(false),
+        ),
+        add_column(
+            db,
+            EmailRoute::Table,
+            ColumnDef::new(EmailRoute::EventTypes).string().null(),
+        ),
+        add_column(
+            db,
+            EmailRoute::Table,
+            ColumnDef::new(EmailRoute::TagPattern).string().null(),
+        ),
+        add_column(
+            db,
+            EmailRoute::Table,
+            ColumnDef::new(EmailRoute::StopProcessing)
+                .boolean()
+                .not_null()
+                .default(false),
+        ),
+        add_column(
+            db,
+            EmailRoute::Table,
+            ColumnDef::new(EmailRoute::MatchRules).text().null(),
+        ),
+    ]
+}
+
+/// Bounce history, referenced by queued webhooks
+fn bounce_events(db: &DBConnection) -> Vec<String> {
+    let schema_builder = &*db.schema_builder;
+    let mut statements = vec![
+        Table::create()
+            .table(BounceEvent::Table)
+            .col(
+                ColumnDef::new(BounceEvent::Id)
+                    .integer()
+                    .not_null()
+                    .auto_increment()
+                    .primary_key(),
+            )
+            .col(ColumnDef::new(BounceEvent::EventType).string().not_null())
+            .col(ColumnDef::new(BounceEvent::Domain).string().not_null())
+            .col(ColumnDef::new(BounceEvent::Recipient).string().not_null())
+            .col(ColumnDef::new(BounceEvent::Status).string().null())
+            .col(ColumnDef::new(BounceEvent::Category).string().null())
+            .col(ColumnDef::new(BounceEvent::SubCategory).string().null())
+            .col(ColumnDef::new(BounceEvent::Reason).text().null())
+            .col(ColumnDef::new(BounceEvent::MessageId).string().null())
+            .col(ColumnDef::new(BounceEvent::RawMessage).blob().null())
+            .col(
+                ColumnDef::new(BounceEvent::RawMessageEventId)
+                    .integer()
+                    .null(),
+            )
+            .col(
+                ColumnDef::new(BounceEvent::ReceivedAt)
+                    .timestamp_with_time_zone()
+                    .not_null()
+                    .default(Expr::current_timestamp()),
+            )
+            .foreign_key(
+                ForeignKey::create()
+                    .name("fk_bounce_event_raw_message")
+                    .from(BounceEvent::Table, BounceEvent::RawMessageEventId)
+                    .to(BounceEvent::Table, BounceEvent::Id)
+                    .on_delete(ForeignKeyAction::SetNull),
+            )
+            .build_any(schema_builder),
+        Index::create()
+            .name("idx_bounce_events_recipient")
+            .table(BounceEvent::Table)
+            .col(BounceEvent::Domain)
+            .col(BounceEvent::Recipient)
+            .col(BounceEvent::ReceivedAt)
+            .build_any(schema_builder),
+    ];
+
+    // SQLite cannot add foreign keys to existing tables, but accepts a reference on
+    // the new column
+    let mut bounce_event_id = ColumnDef::new(WebhookQueue::BounceEventId);
+    bounce_event_id.integer().null();
+    if db.connection.backend_name() == "SQLite" {
+        bounce_event_id.extra("REFERENCES \"bounce_events\" (\"id\") ON DELETE SET NULL");
+        statements.push(add_column(db, WebhookQueue::Table, &mut bounce_event_id));
+    } else {
+        statements.push(add_column(db, WebhookQueue::Table, &mut bounce_event_id));
+        statements.push(
+            ForeignKey::create()
+                .name("fk_queue_to_bounce_event")
+                .from(WebhookQueue::Table, WebhookQueue::BounceEventId)
+                .to(BounceEvent::Table, BounceEvent::Id)
+                .on_delete(ForeignKeyAction::SetNull)
+                .build_any(schema_builder),
+        );
+    }
+    statements
+}
+
+/// Addresses suppressed after hard bounces, complaints or repeated soft bounces
+fn suppressions(db: &DBConnection) -> Vec<String> {
+    let schema_builder = &*db.schema_builder;
+    vec![
+        Table::create()
+            .table(Suppression::Table)
+            .col(
+                ColumnDef::new(Suppression::Id)
+                    .integer()
+                    .not_null()
+                    .auto_increment()
+                    .primary_key(),
+            )
+            .col(ColumnDef::new(Suppression::Domain).string().not_null())
+            .col(ColumnDef::new(Suppression::Email).string().not_null())
+            .col(ColumnDef::new(Suppression::Reason).string().not_null())
+            .col(ColumnDef::new(Suppression::Description).text().null())
+            .col(ColumnDef::new(Suppression::BounceEventId).integer().null())
+            .col(
+                ColumnDef::new(Suppression::CreatedAt)
+                    .timestamp_with_time_zone()
+                    .not_null()
+                    .default(Expr::current_timestamp()),
+            )
+            .foreign_key(
+                ForeignKey::create()
+                    .name("fk_suppression_to_bounce_event")
+                    .from(Suppression::Table, Suppression::BounceEventId)
+                    .to(BounceEvent::Table, BounceEvent::Id)
+                    .on_delete(ForeignKeyAction::SetNull),
+            )
+            .build_any(schema_builder),
+        Index::create()
+            .name("idx_suppression_lookup")
+            .unique()
+            .table(Suppression::Table)
+            .col(Suppression::Domain)
+            .col(Suppression::Email)
+            .build_any(schema_builder),
+    ]
+}
+
+/// Per-route concurrency limit and ordered delivery
+fn route_delivery_options(db: &DBConnection) -> Vec<String> {
+    vec![
+        add_column(
+            db,
+            EmailRoute::Table,
+            ColumnDef::new(EmailRoute::MaxConcurrency).integer().null(),
+        ),
+        add_column(
+            db,
+            EmailRoute::Table,
+            ColumnDef::new(EmailRoute::OrderedDelivery)
+                .boolean()
+                .not_null()
+                .default(false),
+        ),
+    ]
+}
+
+/// Leases of webhooks claimed by a worker, so several workers can share the queue
+fn queue_leases(db: &DBConnection) -> Vec<String> {
+    vec![
+        add_column(
+            db,
+            WebhookQueue::Table,
+            ColumnDef::new(WebhookQueue::LockedBy).string().null(),
+        ),
+        add_column(
+            db,
+            WebhookQueue::Table,
+            ColumnDef::new(WebhookQueue::LockedUntil)
+                .timestamp_with_time_zone()
+                .null(),
+        ),
+    ]
+}
+
+/// Per-route overrides of the retry backoff
+fn route_retry_policy(db: &DBConnection) -> Vec<String> {
+    vec![
+        add_column(
+            db,
+            EmailRoute::Table,
+            ColumnDef::new(EmailRoute::RetryBaseDelaySeconds)
+                .integer()
+                .null(),
+        ),
+        add_column(
+            db,
+            EmailRoute::Table,
+            ColumnDef::new(EmailRoute::RetryMultiplier).double().null(),
+        ),
+        add_column(
+            db,
+            EmailRoute::Table,
+            ColumnDef::new(EmailRoute::RetryMaxDelaySeconds)
+                .integer()
+                .null(),
+        ),
+    ]
+}
+
+/// Status code of the last failed delivery and per-route classification of responses
+fn response_classification(db: &DBConnection) -> Vec<String> {
+    vec![
+        add_column(
+            db,
+            WebhookQueue::Table,
+            ColumnDef::new(WebhookQueue::LastStatusCode)
+                .integer()
+                .null(),
+        ),
+        add_column(
+            db,
+            EmailRoute::Table,
+            ColumnDef::new(EmailRoute::RetryStatusCodes).string().null(),
+        ),
+        add_column(
+            db,
+            EmailRoute::Table,
+            ColumnDef::new(EmailRoute::PermanentStatusCodes)
+                .string()
+                .null(),
+        ),
+        add_column(
+            db,
+            EmailRoute::Table,
+            ColumnDef::new(EmailRoute::DisableAfterGone)
+                .integer()
+                .null(),
+        ),
+        add_column(
+            db,
+            EmailRoute::Table,
+            ColumnDef::new(EmailRoute::ConsecutiveGone)
+                .integer()
+                .not_null()
+                .default(0),
+        ),
+    ]
+}
+
+/// Webhooks given up on, moved out of the queue together with their attempts. Expired
+/// webhooks left in the queue by earlier versions are moved as well.
+fn dead_letters(db: &DBConnection) -> Vec<String> {
+    let schema_builder = &*db.schema_builder;
+    let columns = [
+        DeadLetter::WebhookId,
+        DeadLetter::EmailRouteId,
+        DeadLetter::BounceEventId,
+        DeadLetter::Payload,
+        DeadLetter::Attempts,
+        DeadLetter::LastError,
+        DeadLetter::LastStatusCode,
+        DeadLetter::Reason,
+        DeadLetter::CreatedAt,
+    ];
+    let mut move_expired = InsertStatement::new();
+    move_expired
+        .into_table(DeadLetter::Table)
+        .columns(columns)
+        .select_from(
+            Query::select()
+                .columns([
+                    WebhookQueue::Id,
+                    WebhookQueue::EmailRouteId,
+                    WebhookQueue::BounceEventId,
+                    WebhookQueue::Payload,
+                    WebhookQueue::Attempts,
+                    WebhookQueue::LastError,
+                    WebhookQueue::LastStatusCode,
+                ])
+                .expr(Expr::val(DeadLetterReason::MaxRetries.as_str()))
+                .column(WebhookQueue::CreatedAt)
+                .from(WebhookQueue::Table)
+                .and_where(Expr::col(WebhookQueue::IsExpired).eq(true))
+                .to_owned(),
+        )
+        .expect("columns match the select");
+    let delete_expired = Query::delete()
+        .from_table(WebhookQueue::Table)
+        .and_where(Expr::col(WebhookQueue::IsExpired).eq(true))
+        .to_owned();
+
+    vec![
+        Table::create()
+            .table(DeadLetter::Table)
+            .col(
+                ColumnDef::new(DeadLetter::Id)
+                    .integer()
+                    .not_null()
+                    .auto_increment()
+                    .primary_key(),
+            )
+            .col(ColumnDef::new(DeadLetter::WebhookId).integer().not_null())
+            .col(
+                ColumnDef::new(DeadLetter::EmailRouteId)
+                    .integer()
+                    .not_null(),
+            )
+            .col(ColumnDef::new(DeadLetter::BounceEventId).integer().null())
+            .col(ColumnDef::new(DeadLetter::Payload).text().not_null())
+            .col(ColumnDef::new(DeadLetter::Attempts).integer().not_null())
+            .col(ColumnDef::new(DeadLetter::AttemptLog).text().null())
+            .col(ColumnDef::new(DeadLetter::LastError).text().null())
+            .col(ColumnDef::new(DeadLetter::LastStatusCode).integer().null())
+            .col(ColumnDef::new(DeadLetter::Reason).string().not_null())
+            .col(
+                ColumnDef::new(DeadLetter::CreatedAt)
+                    .timestamp_with_time_zone()
+                    .not_null(),
+            )
+            .col(
+                ColumnDef::new(DeadLetter::DeadLetteredAt)
+                    .timestamp_with_time_zone()
+                    .not_null()
+                    .default(Expr::current_timestamp()),
+            )
+            .foreign_key(
+                ForeignKey::create()
+                    .name("fk_dead_letter_to_route")
+                    .from(DeadLetter::Table, DeadLetter::EmailRouteId)
+                    .to(EmailRoute::Table, EmailRoute::Id),
+            )
+            .foreign_key(
+                ForeignKey::create()
+                    .name("fk_dead_letter_to_bounce_event")
+                    .from(DeadLetter::Table, DeadLetter::BounceEventId)
+                    .to(BounceEvent::Table, BounceEvent::Id)
+                    .on_delete(ForeignKeyAction::SetNull),
+            )
+            .build_any(schema_builder),
+        Index::create()
+            .name("idx_dead_letter_route")
+            .table(DeadLetter::Table)
+            .col(DeadLetter::EmailRouteId)
+            .col(DeadLetter::DeadLetteredAt)
+            .build_any(schema_builder),
+        add_column(
+            db,
+            WebhookQueue::Table,
+            ColumnDef::new(WebhookQueue::AttemptLog).text().null(),
+        ),
+        add_column(
+            db,
+            EmailRoute::Table,
+            ColumnDef::new(EmailRoute::NotifyUrl).string().null(),
+        ),
+        add_column(
+            db,
+            EmailRoute::Table,
+            ColumnDef::new(EmailRoute::NotifyEmail).string().null(),
+        ),
+        inline_values(db, &move_expired),
+        inline_values(db, &delete_expired),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::connect_database;
+
+    async fn empty_database() -> DBConnection {
+        connect_database(&crate::test_config()).await.unwrap()
+    }
+
+    fn statuses(rows: &[Vec<String>]) -> Vec<&str> {
+        rows.iter().map(|row| row[2].as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn up_applies_all_migrations_once() {
+        let mut db = empty_database().await;
+        assert!(ensure_schema_current(&mut db).await.is_err());
+        assert_eq!(
+            statuses(&status_rows(&mut db).await),
+            ["pending"; MIGRATIONS.len()]
+        );
+
+        execute_migrate_up(&mut db, false).await.unwrap();
+        ensure_schema_current(&mut db).await.unwrap();
+        let rows = status_rows(&mut db).await;
+        assert_eq!(statuses(&rows), ["applied"; MIGRATIONS.len()]);
+        assert_eq!(rows[0][..2], ["1", "baseline"]);
+        assert!(rows.iter().all(|row| !row[3].is_empty()));
+
+        // A second run finds nothing to apply and keeps the recorded migrations
+        execute_migrate_up(&mut db, false).await.unwrap();
+        assert_eq!(status_rows(&mut db).await, rows);
+    }
+
+    #[tokio::test]
+    async fn printed_script_creates_current_schema() {
+        let mut db = empty_database().await;
+        let create_table = create_migrations_table(&db);
+        let pending = MIGRATIONS.iter().collect::<Vec<_>>();
+        let lines = script_lines(&db, &create_table, &pending);
+        assert_eq!(lines[0], format!("{};", create_table));
+        assert!(lines.contains(&"-- 1 baseline".to_string()));
+        let last = MIGRATIONS.last().unwrap();
+        assert!(lines.contains(&format!("-- {} {}", last.version, last.name)));
+
+        // Printing leaves the database alone
+        execute_migrate_up(&mut db, true).await.unwrap();
+        assert!(load_applied(&mut db).await.is_err());
+
+        for line in lines.iter().filter(|line| !line.starts_with("--")) {
+            sqlx::query(line.trim_end_matches(';'))
+                .execute(&mut db.connection)
+                .await
+                .unwrap();
+        }
+        ensure_schema_current(&mut db).await.unwrap();
+    }
+}
@@ -0,0 +1,351 @@
+use crate::ingest::BounceInfo;
+use mail_parser::Message;
+use regex::Regex;
+use std::sync::LazyLock;
+
+static EMAIL_ADDRESS: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"[A-Za-z0-9._%+=\-]+@[A-Za-z0-9\-]+(?:\.[A-Za-z0-9\-]+)+").unwrap()
+});
+// Guarded against matching parts of IP addresses such as 10.5.0.12
+static ENHANCED_STATUS: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?:^|[^\d.])([245]\.\d{1,3}\.\d{1,3})\.?(?:[^\d.]|$)").unwrap());
+static SMTP_CODE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\b([45])\d\d\b").unwrap());
+
+const DELAY_MARKERS: [&str; 4] = [
+    "has been delayed",
+    "delivery is delayed",
+    "delivery delayed",
+    "delivery has been delayed",
+];
+
+/// Recipient and diagnostic text extracted by a vendor specific parser
+struct Ndr {
+    recipient: String,
+    diagnostic: Option<String>,
+}
+
+type ParseFn = fn(&str, &str) -> Option<Ndr>;
+
+/// Vendor parsers in the order they are tried. The name ends up in the `parser`
+/// field of the webhook payload.
+const PARSERS: [(&str, ParseFn); 4] = [
+    ("qmail", parse_qmail),
+    ("exchange", parse_exchange),
+    ("gmail", parse_gmail),
+    ("yahoo", parse_yahoo),
+];
+
+/// Tries to recognize a plain-text non-delivery report (NDR) from one of the
+/// common vendor formats.
+pub fn parse_ndr(email: &Message) -> Option<BounceInfo> {
+    let body = (0..)
+        .map_while(|i| email.body_text(i))
+        .collect::<Vec<_>>()
+        .join("\n");
+    if body.is_empty() {
+        return None;
+    }
+    // ASCII lowercasing keeps byte offsets identical to the original body
+    let lower = body.to_ascii_lowercase();
+
+    PARSERS.iter().find_map(|(name, parse)| {
+        let ndr = parse(&body, &lower)?;
+        let is_delay = DELAY_MARKERS.iter().any(|m| lower.contains(m));
+
+        let status = ndr
+            .diagnostic
+            .as_deref()
+            .and_then(extract_status)
+            .or_else(|| extract_status(&body))
+            .unwrap_or_else(|| if is_delay { "4.0.0" } else { "5.0.0" }.to_string());
+
+        Some(BounceInfo {
+            recipient: ndr.recipient,
+            reason: ndr
+                .diagnostic
+                .unwrap_or_else(|| "No reason found".to_string()),
+            status,
+            action: if is_delay { "delayed" } else { "failed" }.to_string(),
+            parser: name,
+            ..Default::default()
+        })
+    })
+}
+
+fn parse_qmail(body: &str, lower: &str) -> Option<Ndr> {
+    let start = lower.find("this is the qmail-send program")?;
+    parse_angle_recipient_block(&body[start..], None)
+}
+
+fn parse_exchange(body: &str, lower: &str) -> Option<Ndr> {
+    let start = [
+        "delivery has failed to these recipients or groups",
+        "the following recipient(s) cannot be reached",
+        "your message did not reach some or all of the intended recipients",
+    ]
+    .iter()
+    .find_map(|m| lower.find(m))?;
+    let section = &body[start..];
+    let recipient = first_address(section)?;
+
+    // Newer Exchange versions: "Remote Server returned '550 5.1.1 ...'"
+    // Older versions and diagnostic blocks: "#550 5.1.1 RESOLVER.ADR.RecipNotFound ##"
+    // or "<mx.example.com #5.1.1 smtp;550 5.1.1 User unknown>"
+    let diagnostic = section.lines().find_map(|line| {
+        let line = line.trim();
+        if let Some(idx) = line.to_ascii_lowercase().find("remote server returned") {
+            let returned = line[idx + "remote server returned".len()..].trim();
+            return Some(returned.trim_matches(|c| c == '\'' || c == '"').to_string());
+        }
+        if let Some(idx) = line.find("smtp;") {
+            return Some(
+                line[idx + "smtp;".len()..]
+                    .trim_end_matches('>')
+                    .to_string(),
+            );
+        }
+        if line.starts_with('#') && ENHANCED_STATUS.is_match(line) {
+            return Some(line.trim_matches('#').trim().to_string());
+        }
+        None
+    });
+
+    Some(Ndr {
+        recipient,
+        diagnostic,
+    })
+}
+
+fn parse_gmail(body: &str, lower: &str) -> Option<Ndr> {
+    let start = [
+        "your message wasn't delivered to",
+        "delivery to the following recipient",
+    ]
+    .iter()
+    .find_map(|m| lower.find(m))?;
+    let recipient = first_address(&body[start..])?;
+
+    let diagnostic = [
+        "the response from the remote server was:",
+        "the response was:",
+        "technical details of permanent failure:",
+        "technical details of temporary failure:",
+        "the error that the other server returned was:",
+    ]
+    .iter()
+    .find_map(|m| lower.find(m).map(|idx| idx + m.len()))
+    .and_then(|idx| paragraph(&body[idx..]));
+
+    Some(Ndr {
+        recipient,
+        diagnostic,
+    })
+}
+
+fn parse_yahoo(body: &str, lower: &str) -> Option<Ndr> {
+    let start = lower.find("unable to deliver your message to the following address")?;
+    parse_angle_recipient_block(&body[start..], Some("remote host said:"))
+}
+
+/// Parses the `<recipient>:` line followed by a diagnostic paragraph, as used by
+/// qmail and Yahoo. If `diagnostic_prefix` is given, only the text following it
+/// is used as diagnostic.
+fn parse_angle_recipient_block(section: &str, diagnostic_prefix: Option<&str>) -> Option<Ndr> {
+    let mut lines = section.lines();
+    let recipient = lines.by_ref().find_map(|line| {
+        line.trim()
+            .strip_prefix('<')
+            .and_then(|l| l.strip_suffix(">:"))
+            .filter(|addr| addr.contains('@'))
+            .map(|addr| addr.to_string())
+    })?;
+
+    let mut diagnostic = lines
+        .take_while(|line| !line.trim().is_empty() && !line.starts_with("---"))
+        .map(str::trim)
+        .collect::<Vec<_>>()
+        .join(" ");
+    if let Some(prefix) = diagnostic_prefix
+        && let Some(idx) = diagnostic.to_ascii_lowercase().find(prefix)
+    {
+        diagnostic = diagnostic[idx + prefix.len()..].trim().to_string();
+    }
+
+    Some(Ndr {
+        recipient,
+        diagnostic: Some(diagnostic).filter(|d| !d.is_empty()),
+    })
+}
+
+fn first_address(text: &str) -> Option<String> {
+    EMAIL_ADDRESS.find(text).map(|m| m.as_str().to_string())
+}
+
+/// Returns the first non-empty paragraph of `text` joined into a single line
+fn paragraph(text: &str) -> Option<String> {
+    let paragraph = text
+        .lines()
+        .map(str::trim)
+        .skip_while(|line| line.is_empty())
+        .take_while(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ");
+    Some(paragraph).filter(|p| !p.is_empty())
+}
+
+fn extract_status(text: &str) -> Option<String> {
+    if let Some(captures) = ENHANCED_STATUS.captures(text) {
+        return Some(captures[1].to_string());
+    }
+    SMTP_CODE
+        .captures(text)
+        .map(|captures| format!("{}.0.0", &captures[1]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mail_parser::MessageParser;
+
+    fn parse(raw: &str) -> Option<BounceInfo> {
+        let message = MessageParser::default().parse(raw.as_bytes()).unwrap();
+        parse_ndr(&message)
+    }
+
+    fn parse_fixture(raw: &str) -> BounceInfo {
+        parse(raw).expect("NDR not recognized")
+    }
+
+    #[test]
+    fn qmail() {
+        let info = parse_fixture(include_str!("../tests/fixtures/ndr/qmail.eml"));
+        assert_eq!(info.parser, "qmail");
+        assert_eq!(info.recipient, "nobody@example.org");
+        assert_eq!(info.status, "5.1.1");
+        assert_eq!(info.action, "failed");
+        assert!(
+            info.reason
+                .starts_with("192.0.2.25 does not like recipient. Remote host said: 550 5.1.1")
+        );
+        assert!(info.reason.ends_with("Giving up on 192.0.2.25."));
+    }
+
+    #[test]
+    fn exchange_2010() {
+        let info = parse_fixture(include_str!("../tests/fixtures/ndr/exchange_2010.eml"));
+        assert_eq!(info.parser, "exchange");
+        assert_eq!(info.recipient, "john.doe@example.org");
+        assert_eq!(info.status, "5.1.1");
+        assert_eq!(info.action, "failed");
+        assert_eq!(
+            info.reason,
+            "550 5.1.1 RESOLVER.ADR.RecipNotFound; not found"
+        );
+    }
+
+    #[test]
+    fn exchange_2007_remote_server_returned() {
+        let info = parse_fixture(include_str!("../tests/fixtures/ndr/exchange_2007.eml"));
+        assert_eq!(info.parser, "exchange");
+        assert_eq!(info.recipient, "mailbox@example.org");
+        assert_eq!(info.status, "5.2.2");
+        assert_eq!(info.reason, "552 5.2.2 Mailbox full");
+    }
+
+    #[test]
+    fn exchange_2003_smtp_diagnostic() {
+        let info = parse_fixture(include_str!("../tests/fixtures/ndr/exchange_2003.eml"));
+        assert_eq!(info.parser, "exchange");
+        assert_eq!(info.recipient, "user@example.org");
+        assert_eq!(info.status, "5.1.1");
+        assert_eq!(info.reason, "550 5.1.1 <user@example.org>... User unknown");
+    }
+
+    #[test]
+    fn gmail() {
+        let info = parse_fixture(include_str!("../tests/fixtures/ndr/gmail.eml"));
+        assert_eq!(info.parser, "gmail");
+        assert_eq!(info.recipient, "nobody@example.org");
+        assert_eq!(info.status, "5.1.1");
+        assert_eq!(info.action, "failed");
+        assert!(
+            info.reason
+                .starts_with("550 5.1.1 The email account that you tried to reach does not exist. Please try double-checking")
+        );
+    }
+
+    #[test]
+    fn gmail_delayed() {
+        let info = parse_fixture(include_str!("../tests/fixtures/ndr/gmail_delayed.eml"));
+        assert_eq!(info.parser, "gmail");
+        assert_eq!(info.recipient, "slow@example.org");
+        assert_eq!(info.action, "delayed");
+        // No status code in the report, delays default to 4.0.0
+        assert_eq!(info.status, "4.0.0");
+        assert!(
+            info.reason
+                .ends_with("[mx.example.org. (10): Connection timed out]")
+        );
+    }
+
+    #[test]
+    fn yahoo() {
+        let info = parse_fixture(include_str!("../tests/fixtures/ndr/yahoo.eml"));
+        assert_eq!(info.parser, "yahoo");
+        assert_eq!(info.recipient, "nobody@example.org");
+        // Only a basic SMTP code is given
+        assert_eq!(info.status, "5.0.0");
+        assert!(
+            info.reason
+                .starts_with("554 delivery error: dd This user doesn't have")
+        );
+    }
+
+    #[test]
+    fn ignores_other_messages() {
+        let raw = "From: alice@example.com\r\nTo: bob@example.org\r\nSubject: Lunch\r\n\r\nSee you at noon.\r\n";
+        assert!(parse(raw).is_none());
+    }
+
+    #[test]
+    fn qmail_requires_recipient_line() {
+        let raw = "From: MAILER-DAEMON@example.net\r\nSubject: failure notice\r\n\r\nHi. This is the qmail-send program at example.net.\r\nSomething went wrong.\r\n";
+        assert!(parse(raw).is_none());
+    }
+
+    #[test]
+    fn extract_status_prefers_enhanced_codes() {
+        assert_eq!(
+            extract_status("550 5.7.1 Blocked").as_deref(),
+            Some("5.7.1")
+        );
+        assert_eq!(
+            extract_status("550-5.7.1 Blocked").as_deref(),
+            Some("5.7.1")
+        );
+        assert_eq!(extract_status("#4.4.7 expired").as_deref(), Some("4.4.7"));
+        assert_eq!(
+            extract_status("421 Try again later").as_deref(),
+            Some("4.0.0")
+        );
+        assert_eq!(extract_status("Mailbox unavailable"), None);
+    }
+
+    #[test]
+    fn extract_status_ignores_ip_addresses() {
+        assert_eq!(
+            extract_status("Connected to 10.5.0.12 but got 421").as_deref(),
+            Some("4.0.0")
+        );
+        assert_eq!(extract_status("host 192.0.2.25 said no"), None);
+    }
+
+    #[test]
+    fn paragraph_joins_lines() {
+        assert_eq!(
+            paragraph("\n\n  first line\n second line\n\nnext").as_deref(),
+            Some("first line second line")
+        );
+        assert_eq!(paragraph("\n \n"), None);
+    }
+}
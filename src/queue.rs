@@ -0,0 +1,579 @@
+use crate::db::{DBConnection, WebhookQueue, timestamp_as_text, timestamp_value};
+use crate::output::{OutputFormat, print_table};
+use crate::worker::lease_available;
+use anyhow::{Context, Result, bail};
+use clap::{Args, ValueEnum};
+use sea_query::{Cond, Expr, Order, Query};
+use sea_query_binder::SqlxBinder;
+use serde::{Deserialize, Serialize};
+use time::{Duration, OffsetDateTime};
+use tracing::info;
+
+/// Length of the error shown by `queue list`, `queue show` prints it in full
+const LIST_ERROR_LENGTH: usize = 60;
+/// Attempts kept in the attempt log, older ones are dropped with unlimited retries
+const ATTEMPT_LOG_LENGTH: usize = 100;
+/// Length of the error kept per attempt, receivers may answer with whole HTML pages
+const ATTEMPT_ERROR_LENGTH: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum QueueStatus {
+    /// Waiting for the first delivery attempt
+    Pending,
+    /// Failed at least once and will be retried
+    Failing,
+}
+
+/// A failed delivery attempt, the attempt log of a webhook is a JSON array of them
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Attempt {
+    pub attempt: i32,
+    pub attempted_at: String,
+    pub status_code: Option<u16>,
+    pub error: String,
+}
+
+/// Filters shared by the queue subcommands
+#[derive(Debug, Args)]
+pub struct QueueFilter {
+    /// Only webhooks of this route
+    #[arg(long, value_name = "ID")]
+    route: Option<i32>,
+    /// Only webhooks created longer ago than this (e.g. 30m, 12h, 7d)
+    #[arg(long, value_name = "AGE", value_parser = parse_age)]
+    older_than: Option<Duration>,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct QueueEntry {
+    id: i32,
+    email_route_id: i32,
+    payload: String,
+    attempts: i32,
+    next_retry_at: String,
+    last_error: Option<String>,
+    last_status_code: Option<i32>,
+    attempt_log: Option<String>,
+    created_at: String,
+    bounce_event_id: Option<i32>,
+    locked_by: Option<String>,
+    locked_until: Option<String>,
+}
+
+impl QueueEntry {
+    fn status(&self) -> QueueStatus {
+        if self.attempts > 0 {
+            QueueStatus::Failing
+        } else {
+            QueueStatus::Pending
+        }
+    }
+
+    fn event(&self) -> String {
+        serde_json::from_str::<serde_json::Value>(&self.payload)
+            .ok()
+            .and_then(|payload| payload["event"].as_str().map(str::to_string))
+            .unwrap_or_default()
+    }
+
+    fn to_json(&self, with_payload: bool) -> serde_json::Value {
+        let mut json = serde_json::json!({
+            "id": self.id,
+            "route_id": self.email_route_id,
+            "event": self.event(),
+            "status": status_name(self.status()),
+            "attempts": self.attempts,
+            "next_retry_at": self.next_retry_at,
+            "last_error": self.last_error,
+            "last_status_code": self.last_status_code,
+            "attempt_log": parse_attempt_log(self.attempt_log.as_deref()),
+            "created_at": self.created_at,
+            "bounce_event_id": self.bounce_event_id,
+            "locked_by": self.locked_by,
+            "locked_until": self.locked_until,
+        });
+        if with_payload {
+            json["payload"] = serde_json::from_str(&self.payload)
+                .unwrap_or_else(|_| self.payload.as_str().into());
+        }
+        json
+    }
+}
+
+pub async fn execute_queue_list(
+    db: &mut DBConnection,
+    filter: &QueueFilter,
+    status: Option<QueueStatus>,
+    limit: u64,
+    format: OutputFormat,
+) -> Result<()> {
+    let condition = filter_condition(db, filter, status);
+    let entries = load_entries(db, condition, Some(limit)).await?;
+
+    match format {
+        OutputFormat::Json => {
+            let entries = entries
+                .iter()
+                .map(|entry| entry.to_json(false))
+                .collect::<Vec<_>>();
+            println!("{}", serde_json::to_string_pretty(&entries)?);
+        }
+        OutputFormat::Table => {
+            let rows = entries
+                .iter()
+                .map(|entry| {
+                    vec![
+                        entry.id.to_string(),
+                        entry.email_route_id.to_string(),
+                        entry.event(),
+                        status_name(entry.status()).to_string(),
+                        entry.attempts.to_string(),
+                        entry.next_retry_at.clone(),
+                        entry.created_at.clone(),
+                        summarize_error(entry.last_error.as_deref()),
+                    ]
+                })
+                .collect::<Vec<_>>();
+            print_table(
+                &[
+                    "ID",
+                    "ROUTE",
+                    "EVENT",
+                    "STATUS",
+                    "ATTEMPTS",
+                    "NEXT RETRY AT",
+                    "CREATED AT",
+                    "LAST ERROR",
+                ],
+                &rows,
+            );
+        }
+    }
+    Ok(())
+}
+
+pub async fn execute_queue_show(
+    db: &mut DBConnection,
+    id: i32,
+    format: OutputFormat,
+) -> Result<()> {
+    let entry = load_entries(
+        db,
+        Cond::all().add(Expr::col(WebhookQueue::Id).eq(id)),
+        None,
+    )
+    .await?
+    .pop()
+    .with_context(|| format!("Webhook {} not found", id))?;
+
+    match format {
+        OutputFormat::Json => {
+            println!("{}", serde_json::to_string_pretty(&entry.to_json(true))?);
+        }
+        OutputFormat::Table => {
+            let fields = [
+                ("ID", entry.id.to_string()),
+                ("Route", entry.email_route_id.to_string()),
+                ("Event", entry.event()),
+                ("Status", status_name(entry.status()).to_string()),
+                ("Attempts", entry.attempts.to_string()),
+                ("Next retry at", entry.next_retry_at.clone()),
+                ("Created at", entry.created_at.clone()),
+                (
+                    "Bounce event",
+                    entry
+                        .bounce_event_id
+                        .map(|id| id.to_string())
+                        .unwrap_or_default(),
+                ),
+                ("Locked by", entry.locked_by.clone().unwrap_or_default()),
+                (
+                    "Locked until",
+                    entry.locked_until.clone().unwrap_or_default(),
+                ),
+                (
+                    "Last status",
+                    entry
+                        .last_status_code
+                        .map(|code| code.to_string())
+                        .unwrap_or_default(),
+                ),
+                ("Last error", entry.last_error.clone().unwrap_or_default()),
+            ];
+            for (name, value) in fields {
+                println!("{:<14} {}", format!("{}:", name), value);
+            }
+            print_attempt_log(entry.attempt_log.as_deref());
+            let payload = serde_json::from_str::<serde_json::Value>(&entry.payload)
+                .and_then(|payload| serde_json::to_string_pretty(&payload))
+                .unwrap_or(entry.payload);
+            println!("Payload:\n{}", payload);
+        }
+    }
+    Ok(())
+}
+
+/// Re-arms the given webhooks for immediate delivery, whatever their status
+pub async fn execute_queue_retry(db: &mut DBConnection, ids: &[i32]) -> Result<()> {
+    let count = rearm(
+        db,
+        Cond::all().add(Expr::col(WebhookQueue::Id).is_in(ids.iter().copied())),
+    )
+    .await?;
+    if count < ids.len() as u64 {
+        bail!(
+            "Re-armed {} of {} webhooks, the others do not exist",
+            count,
+            ids.len()
+        );
+    }
+    info!(count = count, "Re-armed webhooks");
+    Ok(())
+}
+
+/// Deletes webhooks matching the filter and status, skipping those being delivered
+pub async fn execute_queue_purge(
+    db: &mut DBConnection,
+    filter: &QueueFilter,
+    status: QueueStatus,
+) -> Result<()> {
+    let condition = filter_condition(db, filter, Some(status));
+    let count = delete_entries(db, condition).await?;
+    info!(count = count, "Purged webhooks");
+    Ok(())
+}
+
+pub async fn execute_queue_delete(db: &mut DBConnection, ids: &[i32]) -> Result<()> {
+    let count = delete_entries(
+        db,
+        Cond::all().add(Expr::col(WebhookQueue::Id).is_in(ids.iter().copied())),
+    )
+    .await?;
+    if count < ids.len() as u64 {
+        bail!(
+            "Deleted {} of {} webhooks, the others do not exist or are being delivered",
+            count,
+            ids.len()
+        );
+    }
+    info!(count = count, "Deleted webhooks");
+    Ok(())
+}
+
+/// Appends a failed attempt to the attempt log of a webhook
+pub fn append_attempt(attempt_log: Option<&str>, attempt: &Attempt) -> String {
+    let mut attempts = parse_attempt_log(attempt_log);
+    let mut attempt = attempt.clone();
+    if attempt.error.chars().count() > ATTEMPT_ERROR_LENGTH {
+        attempt.error = attempt.error.chars().take(ATTEMPT_ERROR_LENGTH).collect();
+    }
+    attempts.push(attempt);
+    let dropped = attempts.len().saturating_sub(ATTEMPT_LOG_LENGTH);
+    attempts.drain(..dropped);
+    serde_json::to_string(&attempts).unwrap_or_default()
+}
+
+pub fn parse_attempt_log(attempt_log: Option<&str>) -> Vec<Attempt> {
+    attempt_log
+        .and_then(|log| serde_json::from_str(log).ok())
+        .unwrap_or_default()
+}
+
+pub fn print_attempt_log(attempt_log: Option<&str>) {
+    let attempts = parse_attempt_log(attempt_log);
+    if attempts.is_empty() {
+        return;
+    }
+    println!("Attempt log:");
+    for attempt in attempts {
+        println!(
+            "  {:>3}  {}  {:>3}  {}",
+            attempt.attempt,
+            attempt.attempted_at,
+            attempt
+                .status_code
+                .map(|code| code.to_string())
+                .unwrap_or("-".to_string()),
+            attempt.error.lines().next().unwrap_or_default()
+        );
+    }
+}
+
+/// First line of an error, shortened for tables
+pub fn summarize_error(error: Option<&str>) -> String {
+    let mut error = error
+        .unwrap_or_default()
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .to_string();
+    if error.chars().count() > LIST_ERROR_LENGTH {
+        error = error.chars().take(LIST_ERROR_LENGTH - 3).collect();
+        error.push_str("...");
+    }
+    error
+}
+
+/// Parses an age such as `90s`, `30m`, `12h`, `7d` or `2w`
+pub fn parse_age(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (amount, unit) = value.split_at(split);
+    let amount = amount
+        .parse::<i64>()
+        .map_err(|_| format!("invalid age {}, expected e.g. 12h or 7d", value))?;
+    match unit {
+        "s" => Ok(Duration::seconds(amount)),
+        "m" => Ok(Duration::minutes(amount)),
+        "h" => Ok(Duration::hours(amount)),
+        "d" => Ok(Duration::days(amount)),
+        "w" => Ok(Duration::weeks(amount)),
+        _ => Err(format!(
+            "invalid age unit in {}, expected s, m, h, d or w",
+            value
+        )),
+    }
+}
+
+fn status_name(status: QueueStatus) -> &'static str {
+    match status {
+        QueueStatus::Pending => "pending",
+        QueueStatus::Failing => "failing",
+    }
+}
+
+fn filter_condition(db: &DBConnection, filter: &QueueFilter, status: Option<QueueStatus>) -> Cond {
+    let mut condition = Cond::all();
+    if let Some(route) = filter.route {
+        condition = condition.add(Expr::col(WebhookQueue::EmailRouteId).eq(route));
+    }
+    if let Some(age) = filter.older_than {
+        let before = OffsetDateTime::now_utc() - age;
+        condition =
+            condition.add(Expr::col(WebhookQueue::CreatedAt).lt(timestamp_value(db, before)));
+    }
+    // Webhooks that are given up on are in the dead letters, not in the queue
+    match status {
+        Some(QueueStatus::Pending) => {
+            condition = condition.add(Expr::col(WebhookQueue::Attempts).eq(0));
+        }
+        Some(QueueStatus::Failing) => {
+            condition = condition.add(Expr::col(WebhookQueue::Attempts).gt(0));
+        }
+        None => {}
+    }
+    condition
+}
+
+async fn rearm(db: &mut DBConnection, condition: Cond) -> Result<u64> {
+    let query_builder = &*db.query_builder;
+    let (sql, values) = Query::update()
+        .table(WebhookQueue::Table)
+        .values([
+            (WebhookQueue::Attempts, 0.into()),
+            (WebhookQueue::NextRetryAt, Expr::current_timestamp().into()),
+        ])
+        .cond_where(condition)
+        .build_any_sqlx(query_builder);
+    let result = sqlx::query_with(&sql, values)
+        .execute(&mut db.connection)
+        .await
+        .with_context(|| "Failed to re-arm webhooks")?;
+    Ok(result.rows_affected())
+}
+
+/// Deletes matching webhooks unless a worker holds a lease on them, the worker would
+/// deliver them anyway
+async fn delete_entries(db: &mut DBConnection, condition: Cond) -> Result<u64> {
+    let query_builder = &*db.query_builder;
+    let (sql, values) = Query::delete()
+        .from_table(WebhookQueue::Table)
+        .cond_where(condition.add(lease_available()))
+        .build_any_sqlx(query_builder);
+    let result = sqlx::query_with(&sql, values)
+        .execute(&mut db.connection)
+        .await
+        .with_context(|| "Failed to delete webhooks")?;
+    Ok(result.rows_affected())
+}
+
+async fn load_entries(
+    db: &mut DBConnection,
+    condition: Cond,
+    limit: Option<u64>,
+) -> Result<Vec<QueueEntry>> {
+    let query_builder = &*db.query_builder;
+    let mut query = Query::select();
+    query
+        .columns([
+            WebhookQueue::Id,
+            WebhookQueue::EmailRouteId,
+            WebhookQueue::Payload,
+            WebhookQueue::Attempts,
+            WebhookQueue::LastError,
+            WebhookQueue::LastStatusCode,
+            WebhookQueue::AttemptLog,
+            WebhookQueue::BounceEventId,
+            WebhookQueue::LockedBy,
+        ])
+        .expr_as(
+            timestamp_as_text(db, WebhookQueue::NextRetryAt),
+            WebhookQueue::NextRetryAt,
+        )
+        .expr_as(
+            timestamp_as_text(db, WebhookQueue::CreatedAt),
+            WebhookQueue::CreatedAt,
+        )
+        .expr_as(
+            timestamp_as_text(db, WebhookQueue::LockedUntil),
+            WebhookQueue::LockedUntil,
+        )
+        .from(WebhookQueue::Table)
+        .cond_where(condition)
+        .order_by(WebhookQueue::Id, Order::Asc);
+    if let Some(limit) = limit {
+        query.limit(limit);
+    }
+    let (sql, values) = query.build_any_sqlx(query_builder);
+
+    sqlx::query_as_with(&sql, values)
+        .fetch_all(&mut db.connection)
+        .await
+        .with_context(|| "Failed to load queue entries")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{EmailRoute, connect_test_database};
+
+    const NO_FILTER: QueueFilter = QueueFilter {
+        route: None,
+        older_than: None,
+    };
+
+    fn attempt(attempt: i32, error: &str) -> Attempt {
+        Attempt {
+            attempt,
+            attempted_at: "2026-01-01 00:00:00".to_string(),
+            status_code: Some(500),
+            error: error.to_string(),
+        }
+    }
+
+    async fn queue_webhook(db: &mut DBConnection, attempts: i32, leased: bool) {
+        let locked_until = if leased {
+            timestamp_value(db, OffsetDateTime::now_utc() + Duration::hours(1))
+        } else {
+            Expr::val(Option::<String>::None).into()
+        };
+        let (sql, values) = Query::insert()
+            .into_table(WebhookQueue::Table)
+            .columns([
+                WebhookQueue::EmailRouteId,
+                WebhookQueue::Payload,
+                WebhookQueue::Attempts,
+                WebhookQueue::LockedUntil,
+            ])
+            .values_panic([1.into(), "{}".into(), attempts.into(), locked_until])
+            .build_any_sqlx(&*db.query_builder);
+        sqlx::query_with(&sql, values)
+            .execute(&mut db.connection)
+            .await
+            .unwrap();
+    }
+
+    async fn queue_test_webhooks() -> DBConnection {
+        let mut db = connect_test_database().await;
+        let (sql, values) = Query::insert()
+            .into_table(EmailRoute::Table)
+            .columns([EmailRoute::Domain, EmailRoute::Url, EmailRoute::SecretToken])
+            .values_panic([
+                "example.com".into(),
+                "https://hooks.example.com/".into(),
+                "secret".into(),
+            ])
+            .build_any_sqlx(&*db.query_builder);
+        sqlx::query_with(&sql, values)
+            .execute(&mut db.connection)
+            .await
+            .unwrap();
+        // 1 pending, 2 failing, 3 pending and leased by a worker
+        queue_webhook(&mut db, 0, false).await;
+        queue_webhook(&mut db, 2, false).await;
+        queue_webhook(&mut db, 0, true).await;
+        db
+    }
+
+    async fn queued_ids(db: &mut DBConnection, status: Option<QueueStatus>) -> Vec<i32> {
+        let condition = filter_condition(db, &NO_FILTER, status);
+        load_entries(db, condition, None)
+            .await
+            .unwrap()
+            .iter()
+            .map(|entry| entry.id)
+            .collect()
+    }
+
+    #[test]
+    fn parses_ages() {
+        assert_eq!(parse_age("90s"), Ok(Duration::seconds(90)));
+        assert_eq!(parse_age("30m"), Ok(Duration::minutes(30)));
+        assert_eq!(parse_age("12h"), Ok(Duration::hours(12)));
+        assert_eq!(parse_age(" 7d "), Ok(Duration::days(7)));
+        assert_eq!(parse_age("2w"), Ok(Duration::weeks(2)));
+        for age in ["", "7", "d", "-1d", "1.5h", "7y", "7 d", "7D"] {
+            assert!(parse_age(age).is_err(), "{}", age);
+        }
+    }
+
+    #[test]
+    fn append_attempt_truncates_errors() {
+        let error = "é".repeat(ATTEMPT_ERROR_LENGTH + 10);
+        let log = append_attempt(None, &attempt(1, &error));
+        let attempts = parse_attempt_log(Some(&log));
+        assert_eq!(attempts.len(), 1);
+        assert_eq!(attempts[0].error.chars().count(), ATTEMPT_ERROR_LENGTH);
+        assert_eq!(attempts[0].status_code, Some(500));
+    }
+
+    #[test]
+    fn append_attempt_keeps_latest_attempts() {
+        let mut log = append_attempt(Some("not json"), &attempt(1, "failed"));
+        for i in 2..=ATTEMPT_LOG_LENGTH as i32 + 5 {
+            log = append_attempt(Some(&log), &attempt(i, "failed"));
+        }
+        let attempts = parse_attempt_log(Some(&log));
+        assert_eq!(attempts.len(), ATTEMPT_LOG_LENGTH);
+        assert_eq!(attempts[0].attempt, 6);
+        assert_eq!(
+            attempts.last().unwrap().attempt,
+            ATTEMPT_LOG_LENGTH as i32 + 5
+        );
+    }
+
+    #[tokio::test]
+    async fn filters_by_status() {
+        let mut db = queue_test_webhooks().await;
+        assert_eq!(queued_ids(&mut db, None).await, [1, 2, 3]);
+        assert_eq!(
+            queued_ids(&mut db, Some(QueueStatus::Pending)).await,
+            [1, 3]
+        );
+        assert_eq!(queued_ids(&mut db, Some(QueueStatus::Failing)).await, [2]);
+    }
+
+    #[tokio::test]
+    async fn purge_and_delete_skip_leased_webhooks() {
+        let mut db = queue_test_webhooks().await;
+        execute_queue_purge(&mut db, &NO_FILTER, QueueStatus::Pending)
+            .await
+            .unwrap();
+        assert_eq!(queued_ids(&mut db, None).await, [2, 3]);
+
+        assert!(execute_queue_delete(&mut db, &[2, 3]).await.is_err());
+        assert_eq!(queued_ids(&mut db, None).await, [3]);
+    }
+}
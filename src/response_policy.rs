@@ -0,0 +1,171 @@
+use anyhow::{Result, bail};
+use reqwest::StatusCode;
+use std::fmt;
+use std::ops::RangeInclusive;
+
+/// Client errors that are worth retrying: Request Timeout, Too Early and Too Many
+/// Requests
+const RETRYABLE_CLIENT_ERRORS: &[u16] = &[408, 425, 429];
+
+/// Status codes and ranges such as `404,409,500-504`
+#[derive(Debug, Clone, Default)]
+pub struct StatusCodes(Vec<RangeInclusive<u16>>);
+
+impl StatusCodes {
+    pub fn parse(value: &str) -> Result<Self> {
+        let ranges = value
+            .split(',')
+            .map(str::trim)
+            .filter(|part| !part.is_empty())
+            .map(|part| {
+                let (start, end) = part.split_once('-').unwrap_or((part, part));
+                match (start.trim().parse::<u16>(), end.trim().parse::<u16>()) {
+                    (Ok(start), Ok(end))
+                        if (100..=599).contains(&start) && (start..=599).contains(&end) =>
+                    {
+                        Ok(start..=end)
+                    }
+                    _ => bail!("Invalid status code {}, expected e.g. 404 or 500-504", part),
+                }
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self(ranges))
+    }
+
+    pub fn contains(&self, status: StatusCode) -> bool {
+        self.0.iter().any(|range| range.contains(&status.as_u16()))
+    }
+
+    fn overlaps(&self, other: &StatusCodes) -> Option<u16> {
+        self.0.iter().find_map(|range| {
+            other
+                .0
+                .iter()
+                .find(|other| range.start() <= other.end() && other.start() <= range.end())
+                .map(|other| *range.start().max(other.start()))
+        })
+    }
+}
+
+impl fmt::Display for StatusCodes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ranges = self
+            .0
+            .iter()
+            .map(|range| {
+                if range.start() == range.end() {
+                    range.start().to_string()
+                } else {
+                    format!("{}-{}", range.start(), range.end())
+                }
+            })
+            .collect::<Vec<_>>();
+        write!(f, "{}", ranges.join(","))
+    }
+}
+
+/// Decides whether a failed delivery is retried. Network errors, server errors and
+/// 408, 425 and 429 are retried, other client errors will not succeed by retrying.
+/// Routes can retry or give up on further status codes.
+#[derive(Debug, Clone, Default)]
+pub struct ResponsePolicy {
+    retry: StatusCodes,
+    permanent: StatusCodes,
+}
+
+impl ResponsePolicy {
+    pub fn new(retry: Option<&str>, permanent: Option<&str>) -> Result<Self> {
+        let retry = retry
+            .map(StatusCodes::parse)
+            .transpose()?
+            .unwrap_or_default();
+        let permanent = permanent
+            .map(StatusCodes::parse)
+            .transpose()?
+            .unwrap_or_default();
+        if let Some(status) = retry.overlaps(&permanent) {
+            bail!(
+                "Status code {} cannot be both retried and permanent",
+                status
+            );
+        }
+        Ok(Self { retry, permanent })
+    }
+
+    /// Whether a delivery that failed with the status, or without response, must not
+    /// be retried
+    pub fn is_permanent(&self, status: Option<StatusCode>) -> bool {
+        let Some(status) = status else {
+            return false;
+        };
+        if self.retry.contains(status) {
+            false
+        } else if self.permanent.contains(status) {
+            true
+        } else {
+            status.is_client_error() && !RETRYABLE_CLIENT_ERRORS.contains(&status.as_u16())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(code: u16) -> Option<StatusCode> {
+        Some(StatusCode::from_u16(code).unwrap())
+    }
+
+    #[test]
+    fn parse_status_codes() {
+        let codes = StatusCodes::parse(" 404, 409,500-504 ,").unwrap();
+        assert_eq!(codes.to_string(), "404,409,500-504");
+        assert!(codes.contains(StatusCode::NOT_FOUND));
+        assert!(codes.contains(StatusCode::BAD_GATEWAY));
+        assert!(!codes.contains(StatusCode::HTTP_VERSION_NOT_SUPPORTED));
+        assert_eq!(StatusCodes::parse("").unwrap().to_string(), "");
+    }
+
+    #[test]
+    fn invalid_status_codes_are_rejected() {
+        for value in ["abc", "99", "600", "504-500", "500-", "4xx"] {
+            assert!(StatusCodes::parse(value).is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn default_policy() {
+        let policy = ResponsePolicy::default();
+        // Network errors without response
+        assert!(!policy.is_permanent(None));
+        assert!(!policy.is_permanent(status(500)));
+        assert!(!policy.is_permanent(status(503)));
+        for code in [408, 425, 429] {
+            assert!(!policy.is_permanent(status(code)), "{}", code);
+        }
+        for code in [400, 401, 404, 410, 422] {
+            assert!(policy.is_permanent(status(code)), "{}", code);
+        }
+    }
+
+    #[test]
+    fn route_overrides() {
+        let policy = ResponsePolicy::new(Some("404"), Some("501,505-599")).unwrap();
+        assert!(!policy.is_permanent(status(404)));
+        assert!(policy.is_permanent(status(501)));
+        assert!(policy.is_permanent(status(520)));
+        assert!(!policy.is_permanent(status(502)));
+        assert!(policy.is_permanent(status(410)));
+    }
+
+    #[test]
+    fn overlapping_overrides_are_rejected() {
+        let error = ResponsePolicy::new(Some("400-410"), Some("404")).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Status code 404 cannot be both retried and permanent"
+        );
+        assert!(ResponsePolicy::new(Some("500-503"), Some("503-504")).is_err());
+        assert!(ResponsePolicy::new(Some("500-502"), Some("503-504")).is_ok());
+    }
+}
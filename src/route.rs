@@ -0,0 +1,951 @@
+use crate::db::{
+    DBConnection, DeadLetter, EmailRoute, WebhookQueue, bool_as_int, insert_returning_id,
+};
+use crate::ingest::{normalize_domain, normalize_local_part};
+use crate::match_rules::MatchRules;
+use crate::output::{OutputFormat, print_table};
+use crate::response_policy::{ResponsePolicy, StatusCodes};
+use anyhow::{Context, Result, bail};
+use clap::Args;
+use rand::Rng;
+use rand::distr::Alphanumeric;
+use regex::Regex;
+use reqwest::Url;
+use sea_query::{Expr, Order, Query, SimpleExpr};
+use sea_query_binder::SqlxBinder;
+use sqlx::Row;
+use tracing::{info, warn};
+
+const SECRET_TOKEN_LENGTH: usize = 48;
+const EVENT_TYPES: &[&str] = &["bounce", "delay", "delivery", "complaint"];
+
+/// Optional route settings shared by `route add` and `route update`. Pass an empty
+/// value to clear a setting.
+#[derive(Debug, Args)]
+pub struct RouteOptions {
+    /// Only match this user (local part without extension), all users if not set
+    #[arg(long)]
+    user: Option<String>,
+    /// Secret for signing payloads, generated if not set on add
+    #[arg(long)]
+    secret_token: Option<String>,
+    /// Send one webhook per bounce message with all recipients
+    #[arg(long, value_name = "BOOL")]
+    batch_recipients: Option<bool>,
+    /// Comma separated event types to send (bounce, delay, delivery, complaint), all if
+    /// not set
+    #[arg(long)]
+    event_types: Option<String>,
+    /// Regular expression with named groups parsing the address extension
+    #[arg(long)]
+    tag_pattern: Option<String>,
+    /// Do not process routes of lower precedence after this route
+    #[arg(long, value_name = "BOOL")]
+    stop_processing: Option<bool>,
+    /// JSON conditions on the original message and bounce
+    #[arg(long, value_name = "JSON")]
+    match_rules: Option<String>,
+    /// Maximum webhooks delivered to the route at the same time, worker_route_concurrency
+    /// if not set
+    #[arg(long, value_name = "N")]
+    max_concurrency: Option<String>,
+    /// Deliver webhooks one at a time in queue order, a failed webhook holds back later
+    /// ones
+    #[arg(long, value_name = "BOOL")]
+    ordered_delivery: Option<bool>,
+    /// Seconds before the first retry, worker_retry_base_delay_seconds if not set
+    #[arg(long, value_name = "SECONDS")]
+    retry_base_delay: Option<String>,
+    /// Factor each further retry waits longer, worker_retry_multiplier if not set
+    #[arg(long, value_name = "FACTOR")]
+    retry_multiplier: Option<String>,
+    /// Maximum seconds between retries, worker_max_delay_seconds if not set
+    #[arg(long, value_name = "SECONDS")]
+    retry_max_delay: Option<String>,
+    /// Status codes to retry although they are client errors, e.g. 404 while the
+    /// receiver is deployed
+    #[arg(long, value_name = "CODES")]
+    retry_status_codes: Option<String>,
+    /// Status codes not to retry although they are server errors, e.g. 501
+    #[arg(long, value_name = "CODES")]
+    permanent_status_codes: Option<String>,
+    /// Disable the route after this many 410 Gone responses in a row
+    #[arg(long, value_name = "N")]
+    disable_after_gone: Option<String>,
+    /// URL receiving a signed notification when webhooks are given up on
+    #[arg(long, value_name = "URL")]
+    notify_url: Option<String>,
+    /// Address receiving an email when webhooks are given up on
+    #[arg(long, value_name = "EMAIL")]
+    notify_email: Option<String>,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct RouteEntry {
+    id: i32,
+    domain: String,
+    user: Option<String>,
+    url: String,
+    secret_token: String,
+    is_enabled: i32,
+    batch_recipients: i32,
+    event_types: Option<String>,
+    tag_pattern: Option<String>,
+    stop_processing: i32,
+    match_rules: Option<String>,
+    max_concurrency: Option<i32>,
+    ordered_delivery: i32,
+    retry_base_delay_seconds: Option<i32>,
+    retry_multiplier: Option<f64>,
+    retry_max_delay_seconds: Option<i32>,
+    retry_status_codes: Option<String>,
+    permanent_status_codes: Option<String>,
+    disable_after_gone: Option<i32>,
+    consecutive_gone: i32,
+    notify_url: Option<String>,
+    notify_email: Option<String>,
+}
+
+impl RouteEntry {
+    fn to_json(&self, with_secret: bool) -> serde_json::Value {
+        let mut json = serde_json::json!({
+            "id": self.id,
+            "domain": self.domain,
+            "user": self.user,
+            "url": self.url,
+            "is_enabled": self.is_enabled != 0,
+            "batch_recipients": self.batch_recipients != 0,
+            "event_types": self.event_types,
+            "tag_pattern": self.tag_pattern,
+            "stop_processing": self.stop_processing != 0,
+            "match_rules": self
+                .match_rules
+                .as_deref()
+                .map(|rules| serde_json::from_str::<serde_json::Value>(rules).unwrap_or(rules.into())),
+            "max_concurrency": self.max_concurrency,
+            "ordered_delivery": self.ordered_delivery != 0,
+            "retry_base_delay_seconds": self.retry_base_delay_seconds,
+            "retry_multiplier": self.retry_multiplier,
+            "retry_max_delay_seconds": self.retry_max_delay_seconds,
+            "retry_status_codes": self.retry_status_codes,
+            "permanent_status_codes": self.permanent_status_codes,
+            "disable_after_gone": self.disable_after_gone,
+            "consecutive_gone": self.consecutive_gone,
+            "notify_url": self.notify_url,
+            "notify_email": self.notify_email,
+        });
+        if with_secret {
+            json["secret_token"] = self.secret_token.as_str().into();
+        }
+        json
+    }
+}
+
+pub async fn execute_route_add(
+    db: &mut DBConnection,
+    domain: &str,
+    url: &str,
+    disabled: bool,
+    options: RouteOptions,
+    case_sensitive_local_part: bool,
+) -> Result<()> {
+    let mut values = vec![
+        (EmailRoute::Domain, validate_domain(domain)?.into()),
+        (EmailRoute::Url, validate_url(url)?.into()),
+        (EmailRoute::IsEnabled, (!disabled).into()),
+    ];
+    if options.secret_token.is_none() {
+        let secret_token = rand::rng()
+            .sample_iter(Alphanumeric)
+            .take(SECRET_TOKEN_LENGTH)
+            .map(char::from)
+            .collect::<String>();
+        values.push((EmailRoute::SecretToken, secret_token.into()));
+    }
+    ResponsePolicy::new(
+        options.retry_status_codes.as_deref(),
+        options.permanent_status_codes.as_deref(),
+    )?;
+    values.extend(option_values(options, case_sensitive_local_part)?);
+    if !values
+        .iter()
+        .any(|(column, _)| matches!(column, EmailRoute::SecretToken))
+    {
+        bail!("Secret token must not be empty");
+    }
+
+    let (columns, values): (Vec<_>, Vec<_>) = values.into_iter().unzip();
+    let mut statement = Query::insert();
+    statement
+        .into_table(EmailRoute::Table)
+        .columns(columns)
+        .values(values)?;
+    let id = insert_returning_id(db, statement, EmailRoute::Id)
+        .await
+        .with_context(|| "Failed to create route")?;
+    info!(id = id, "Created route");
+
+    let route = load_route(db, id as i32).await?;
+    print_route(&route, OutputFormat::Table);
+    Ok(())
+}
+
+pub async fn execute_route_list(
+    db: &mut DBConnection,
+    domain: Option<&str>,
+    format: OutputFormat,
+) -> Result<()> {
+    let condition = domain
+        .map(|domain| -> Result<SimpleExpr> {
+            Ok(Expr::col(EmailRoute::Domain).eq(validate_domain(domain)?))
+        })
+        .transpose()?;
+    let routes = load_routes(db, condition).await?;
+
+    match format {
+        OutputFormat::Json => {
+            let routes = routes
+                .iter()
+                .map(|route| route.to_json(false))
+                .collect::<Vec<_>>();
+            println!("{}", serde_json::to_string_pretty(&routes)?);
+        }
+        OutputFormat::Table => {
+            let rows = routes
+                .iter()
+                .map(|route| {
+                    vec![
+                        route.id.to_string(),
+                        route.domain.clone(),
+                        route.user.clone().unwrap_or("*".to_string()),
+                        route.url.clone(),
+                        yes_no(route.is_enabled),
+                        route.event_types.clone().unwrap_or("all".to_string()),
+                        yes_no(route.batch_recipients),
+                        yes_no(route.stop_processing),
+                    ]
+                })
+                .collect::<Vec<_>>();
+            print_table(
+                &[
+                    "ID", "DOMAIN", "USER", "URL", "ENABLED", "EVENTS", "BATCH", "STOP",
+                ],
+                &rows,
+            );
+        }
+    }
+    Ok(())
+}
+
+pub async fn execute_route_show(
+    db: &mut DBConnection,
+    id: i32,
+    format: OutputFormat,
+) -> Result<()> {
+    let route = load_route(db, id).await?;
+    print_route(&route, format);
+    Ok(())
+}
+
+pub async fn execute_route_update(
+    db: &mut DBConnection,
+    id: i32,
+    domain: Option<&str>,
+    url: Option<&str>,
+    options: RouteOptions,
+    case_sensitive_local_part: bool,
+) -> Result<()> {
+    let mut values = Vec::new();
+    if let Some(domain) = domain {
+        values.push((EmailRoute::Domain, validate_domain(domain)?.into()));
+    }
+    if let Some(url) = url {
+        values.push((EmailRoute::Url, validate_url(url)?.into()));
+    }
+    if options
+        .secret_token
+        .as_deref()
+        .is_some_and(|token| token.is_empty())
+    {
+        bail!("Secret token must not be empty");
+    }
+    if options.retry_status_codes.is_some() || options.permanent_status_codes.is_some() {
+        let route = load_route(db, id).await?;
+        ResponsePolicy::new(
+            options
+                .retry_status_codes
+                .as_deref()
+                .or(route.retry_status_codes.as_deref()),
+            options
+                .permanent_status_codes
+                .as_deref()
+                .or(route.permanent_status_codes.as_deref()),
+        )?;
+    }
+    values.extend(option_values(options, case_sensitive_local_part)?);
+    if values.is_empty() {
+        bail!("Nothing to update");
+    }
+
+    update_route(db, id, values).await?;
+    info!(id = id, "Updated route");
+    Ok(())
+}
+
+pub async fn execute_route_set_enabled(
+    db: &mut DBConnection,
+    id: i32,
+    enabled: bool,
+) -> Result<()> {
+    // Count 410 Gone responses afresh after the route was disabled because of them
+    update_route(
+        db,
+        id,
+        vec![
+            (EmailRoute::IsEnabled, enabled.into()),
+            (EmailRoute::ConsecutiveGone, 0.into()),
+        ],
+    )
+    .await?;
+    info!(
+        id = id,
+        "{} route",
+        if enabled { "Enabled" } else { "Disabled" }
+    );
+    Ok(())
+}
+
+/// Deletes a route. Routes with queued webhooks or dead letters are only deleted with
+/// `force`, which deletes them as well.
+pub async fn execute_route_delete(db: &mut DBConnection, id: i32, force: bool) -> Result<()> {
+    load_route(db, id).await?;
+
+    // Webhooks queued meanwhile must not be left behind without their route
+    db.begin().await?;
+    let result = delete_route(db, id, force).await;
+    match &result {
+        Ok(_) => db.commit().await?,
+        Err(_) => {
+            if let Err(e) = db.rollback().await {
+                warn!(
+                    error = format!("{:#}", e),
+                    "Failed to roll back route delete"
+                );
+            }
+        }
+    }
+    let (queued, dead_letters) = result?;
+    info!(
+        id = id,
+        queued = queued,
+        dead_letters = dead_letters,
+        "Deleted route"
+    );
+    Ok(())
+}
+
+/// Deletes the route, with its queued webhooks and dead letters if `force` is set.
+/// Returns the number of deleted webhooks and dead letters.
+async fn delete_route(db: &mut DBConnection, id: i32, force: bool) -> Result<(i64, i64)> {
+    let query_builder = &*db.query_builder;
+    let (sql, values) = Query::select()
+        .expr(Expr::col(WebhookQueue::Id).count())
+        .from(WebhookQueue::Table)
+        .and_where(Expr::col(WebhookQueue::EmailRouteId).eq(id))
+        .build_any_sqlx(query_builder);
+    let queued: i64 = sqlx::query_with(&sql, values)
+        .fetch_one(&mut db.connection)
+        .await?
+        .try_get(0)?;
+    let (sql, values) = Query::select()
+        .expr(Expr::col(DeadLetter::Id).count())
+        .from(DeadLetter::Table)
+        .and_where(Expr::col(DeadLetter::EmailRouteId).eq(id))
+        .build_any_sqlx(query_builder);
+    let dead_letters: i64 = sqlx::query_with(&sql, values)
+        .fetch_one(&mut db.connection)
+        .await?
+        .try_get(0)?;
+    if queued > 0 || dead_letters > 0 {
+        if !force {
+            bail!(
+                "Route {} has {} queued webhooks and {} dead letters, use --force to delete them as well",
+                id,
+                queued,
+                dead_letters
+            );
+        }
+        let (sql, values) = Query::delete()
+            .from_table(WebhookQueue::Table)
+            .and_where(Expr::col(WebhookQueue::EmailRouteId).eq(id))
+            .build_any_sqlx(query_builder);
+        sqlx::query_with(&sql, values)
+            .execute(&mut db.connection)
+            .await?;
+        let (sql, values) = Query::delete()
+            .from_table(DeadLetter::Table)
+            .and_where(Expr::col(DeadLetter::EmailRouteId).eq(id))
+            .build_any_sqlx(query_builder);
+        sqlx::query_with(&sql, values)
+            .execute(&mut db.connection)
+            .await?;
+    }
+
+    let (sql, values) = Query::delete()
+        .from_table(EmailRoute::Table)
+        .and_where(Expr::col(EmailRoute::Id).eq(id))
+        .build_any_sqlx(query_builder);
+    sqlx::query_with(&sql, values)
+        .execute(&mut db.connection)
+        .await?;
+    Ok((queued, dead_letters))
+}
+
+/// Validates the optional settings and converts them to column values, empty values
+/// clear the column. Users are stored normalized, the way ingest looks them up.
+fn option_values(
+    options: RouteOptions,
+    case_sensitive_local_part: bool,
+) -> Result<Vec<(EmailRoute, SimpleExpr)>> {
+    let mut values = Vec::new();
+    let nullable = |value: String| Some(value).filter(|v| !v.is_empty());
+
+    if let Some(user) = options.user.map(nullable) {
+        if user.as_deref().is_some_and(|u| u.contains('@')) {
+            bail!("User must be the local part without domain");
+        }
+        let user = user.map(|user| normalize_local_part(&user, case_sensitive_local_part));
+        values.push((EmailRoute::User, user.into()));
+    }
+    if let Some(secret_token) = options.secret_token.filter(|t| !t.is_empty()) {
+        values.push((EmailRoute::SecretToken, secret_token.into()));
+    }
+    if let Some(batch_recipients) = options.batch_recipients {
+        values.push((EmailRoute::BatchRecipients, batch_recipients.into()));
+    }
+    if let Some(event_types) = options.event_types.map(nullable) {
+        let event_types = event_types
+            .map(|types| {
+                types
+                    .split(',')
+                    .map(|t| {
+                        let t = t.trim().to_lowercase();
+                        if !EVENT_TYPES.contains(&t.as_str()) {
+                            bail!(
+                                "Unknown event type {}, expected one of {}",
+                                t,
+                                EVENT_TYPES.join(", ")
+                            );
+                        }
+                        Ok(t)
+                    })
+                    .collect::<Result<Vec<_>>>()
+                    .map(|types| types.join(","))
+            })
+            .transpose()?;
+        values.push((EmailRoute::EventTypes, event_types.into()));
+    }
+    if let Some(tag_pattern) = options.tag_pattern.map(nullable) {
+        if let Some(pattern) = &tag_pattern {
+            Regex::new(pattern).with_context(|| format!("Invalid tag pattern {}", pattern))?;
+        }
+        values.push((EmailRoute::TagPattern, tag_pattern.into()));
+    }
+    if let Some(stop_processing) = options.stop_processing {
+        values.push((EmailRoute::StopProcessing, stop_processing.into()));
+    }
+    if let Some(match_rules) = options.match_rules.map(nullable) {
+        if let Some(rules) = &match_rules {
+            MatchRules::parse(rules)?;
+        }
+        values.push((EmailRoute::MatchRules, match_rules.into()));
+    }
+    if let Some(max_concurrency) = options.max_concurrency.map(nullable) {
+        let max_concurrency = max_concurrency
+            .map(|value| match value.parse::<i32>() {
+                Ok(limit) if limit > 0 => Ok(limit),
+                _ => bail!(
+                    "Invalid max concurrency {}, expected a positive number",
+                    value
+                ),
+            })
+            .transpose()?;
+        values.push((EmailRoute::MaxConcurrency, max_concurrency.into()));
+    }
+    if let Some(ordered_delivery) = options.ordered_delivery {
+        values.push((EmailRoute::OrderedDelivery, ordered_delivery.into()));
+    }
+    if let Some(base_delay) = options.retry_base_delay.map(nullable) {
+        let base_delay = base_delay
+            .map(|value| match value.parse::<i32>() {
+                Ok(seconds) if seconds >= 0 => Ok(seconds),
+                _ => bail!("Invalid retry base delay {}, expected seconds", value),
+            })
+            .transpose()?;
+        values.push((EmailRoute::RetryBaseDelaySeconds, base_delay.into()));
+    }
+    if let Some(multiplier) = options.retry_multiplier.map(nullable) {
+        let multiplier = multiplier
+            .map(|value| match value.parse::<f64>() {
+                Ok(factor) if factor >= 1.0 => Ok(factor),
+                _ => bail!("Invalid retry multiplier {}, expected at least 1", value),
+            })
+            .transpose()?;
+        values.push((EmailRoute::RetryMultiplier, multiplier.into()));
+    }
+    if let Some(max_delay) = options.retry_max_delay.map(nullable) {
+        let max_delay = max_delay
+            .map(|value| match value.parse::<i32>() {
+                Ok(seconds) if seconds > 0 => Ok(seconds),
+                _ => bail!("Invalid retry max delay {}, expected seconds", value),
+            })
+            .transpose()?;
+        values.push((EmailRoute::RetryMaxDelaySeconds, max_delay.into()));
+    }
+    if let Some(codes) = options.retry_status_codes.map(nullable) {
+        let codes = codes
+            .map(|codes| StatusCodes::parse(&codes).map(|codes| codes.to_string()))
+            .transpose()?;
+        values.push((EmailRoute::RetryStatusCodes, codes.into()));
+    }
+    if let Some(codes) = options.permanent_status_codes.map(nullable) {
+        let codes = codes
+            .map(|codes| StatusCodes::parse(&codes).map(|codes| codes.to_string()))
+            .transpose()?;
+        values.push((EmailRoute::PermanentStatusCodes, codes.into()));
+    }
+    if let Some(limit) = options.disable_after_gone.map(nullable) {
+        let limit = limit
+            .map(|value| match value.parse::<i32>() {
+                Ok(limit) if limit > 0 => Ok(limit),
+                _ => bail!(
+                    "Invalid disable after gone {}, expected a positive number",
+                    value
+                ),
+            })
+            .transpose()?;
+        values.push((EmailRoute::DisableAfterGone, limit.into()));
+    }
+    if let Some(notify_url) = options.notify_url.map(nullable) {
+        let notify_url = notify_url.map(|url| validate_url(&url)).transpose()?;
+        values.push((EmailRoute::NotifyUrl, notify_url.into()));
+    }
+    if let Some(notify_email) = options.notify_email.map(nullable) {
+        let notify_email = notify_email
+            .map(|email| {
+                let email = email.trim().to_string();
+                // Ends up in a mail header and on the sendmail command line
+                let valid = email.split_once('@').is_some_and(|(local, domain)| {
+                    !local.is_empty() && !local.starts_with('-') && domain.contains('.')
+                }) && !email
+                    .chars()
+                    .any(|c| c.is_whitespace() || c.is_control() || "<>,;\"".contains(c));
+                if !valid {
+                    bail!("Invalid notify email {}", email);
+                }
+                Ok(email)
+            })
+            .transpose()?;
+        values.push((EmailRoute::NotifyEmail, notify_email.into()));
+    }
+
+    Ok(values)
+}
+
+/// Normalizes the route domain. Besides domain names, `*.<domain>` wildcards and the
+/// global `*` are allowed.
+fn validate_domain(domain: &str) -> Result<String> {
+    let domain = domain.trim();
+    if domain == "*" {
+        return Ok(domain.to_string());
+    }
+    let (wildcard, name) = match domain.strip_prefix("*.") {
+        Some(name) => ("*.", name),
+        None => ("", domain),
+    };
+
+    let name = normalize_domain(name);
+    let valid = !name.is_empty()
+        && name.len() <= 253
+        && name.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        });
+    if !valid {
+        bail!("Invalid domain {}", domain);
+    }
+    Ok(format!("{}{}", wildcard, name))
+}
+
+fn validate_url(url: &str) -> Result<String> {
+    let parsed = Url::parse(url).with_context(|| format!("Invalid URL {}", url))?;
+    if !matches!(parsed.scheme(), "http" | "https") {
+        bail!("Invalid URL {}, only http and https are supported", url);
+    }
+    if parsed.host_str().is_none_or(|host| host.is_empty()) {
+        bail!("Invalid URL {}, missing host", url);
+    }
+    Ok(url.to_string())
+}
+
+async fn update_route(
+    db: &mut DBConnection,
+    id: i32,
+    values: Vec<(EmailRoute, SimpleExpr)>,
+) -> Result<()> {
+    let query_builder = &*db.query_builder;
+    let (sql, values) = Query::update()
+        .table(EmailRoute::Table)
+        .values(values)
+        .and_where(Expr::col(EmailRoute::Id).eq(id))
+        .build_any_sqlx(query_builder);
+    let result = sqlx::query_with(&sql, values)
+        .execute(&mut db.connection)
+        .await
+        .with_context(|| format!("Failed to update route {}", id))?;
+    if result.rows_affected() == 0 {
+        bail!("Route {} not found", id);
+    }
+    Ok(())
+}
+
+async fn load_route(db: &mut DBConnection, id: i32) -> Result<RouteEntry> {
+    load_routes(db, Some(Expr::col(EmailRoute::Id).eq(id)))
+        .await?
+        .pop()
+        .with_context(|| format!("Route {} not found", id))
+}
+
+async fn load_routes(
+    db: &mut DBConnection,
+    condition: Option<SimpleExpr>,
+) -> Result<Vec<RouteEntry>> {
+    let query_builder = &*db.query_builder;
+    let mut query = Query::select();
+    query
+        .columns([
+            EmailRoute::Id,
+            EmailRoute::Domain,
+            EmailRoute::User,
+            EmailRoute::Url,
+            EmailRoute::SecretToken,
+            EmailRoute::EventTypes,
+            EmailRoute::TagPattern,
+            EmailRoute::MatchRules,
+            EmailRoute::MaxConcurrency,
+            EmailRoute::RetryBaseDelaySeconds,
+            EmailRoute::RetryMultiplier,
+            EmailRoute::RetryMaxDelaySeconds,
+            EmailRoute::RetryStatusCodes,
+            EmailRoute::PermanentStatusCodes,
+            EmailRoute::DisableAfterGone,
+            EmailRoute::ConsecutiveGone,
+            EmailRoute::NotifyUrl,
+            EmailRoute::NotifyEmail,
+        ])
+        .expr_as(bool_as_int(EmailRoute::IsEnabled), EmailRoute::IsEnabled)
+        .expr_as(
+            bool_as_int(EmailRoute::BatchRecipients),
+            EmailRoute::BatchRecipients,
+        )
+        .expr_as(
+            bool_as_int(EmailRoute::StopProcessing),
+            EmailRoute::StopProcessing,
+        )
+        .expr_as(
+            bool_as_int(EmailRoute::OrderedDelivery),
+            EmailRoute::OrderedDelivery,
+        )
+        .from(EmailRoute::Table)
+        .order_by(EmailRoute::Domain, Order::Asc)
+        .order_by(EmailRoute::Id, Order::Asc);
+    if let Some(condition) = condition {
+        query.and_where(condition);
+    }
+    let (sql, values) = query.build_any_sqlx(query_builder);
+
+    sqlx::query_as_with(&sql, values)
+        .fetch_all(&mut db.connection)
+        .await
+        .with_context(|| "Failed to load routes")
+}
+
+fn print_route(route: &RouteEntry, format: OutputFormat) {
+    match format {
+        OutputFormat::Json => {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&route.to_json(true)).unwrap_or_default()
+            );
+        }
+        OutputFormat::Table => {
+            let fields = [
+                ("ID", route.id.to_string()),
+                ("Domain", route.domain.clone()),
+                ("User", route.user.clone().unwrap_or("*".to_string())),
+                ("URL", route.url.clone()),
+                ("Secret token", route.secret_token.clone()),
+                ("Enabled", yes_no(route.is_enabled)),
+                (
+                    "Event types",
+                    route.event_types.clone().unwrap_or("all".to_string()),
+                ),
+                ("Batch recipients", yes_no(route.batch_recipients)),
+                ("Tag pattern", route.tag_pattern.clone().unwrap_or_default()),
+                ("Stop processing", yes_no(route.stop_processing)),
+                ("Match rules", route.match_rules.clone().unwrap_or_default()),
+                (
+                    "Max concurrency",
+                    route
+                        .max_concurrency
+                        .map(|limit| limit.to_string())
+                        .unwrap_or("default".to_string()),
+                ),
+                ("Ordered delivery", yes_no(route.ordered_delivery)),
+                (
+                    "Retry backoff",
+                    format!(
+                        "base {}, multiplier {}, max {}",
+                        route
+                            .retry_base_delay_seconds
+                            .map(|seconds| format!("{}s", seconds))
+                            .unwrap_or("default".to_string()),
+                        route
+                            .retry_multiplier
+                            .map(|factor| factor.to_string())
+                            .unwrap_or("default".to_string()),
+                        route
+                            .retry_max_delay_seconds
+                            .map(|seconds| format!("{}s", seconds))
+                            .unwrap_or("default".to_string()),
+                    ),
+                ),
+                (
+                    "Retry codes",
+                    route
+                        .retry_status_codes
+                        .clone()
+                        .unwrap_or("default".to_string()),
+                ),
+                (
+                    "Permanent codes",
+                    route
+                        .permanent_status_codes
+                        .clone()
+                        .unwrap_or("default".to_string()),
+                ),
+                (
+                    "Disable on 410",
+                    route
+                        .disable_after_gone
+                        .map(|limit| {
+                            format!(
+                                "after {} in a row ({} so far)",
+                                limit, route.consecutive_gone
+                            )
+                        })
+                        .unwrap_or("never".to_string()),
+                ),
+                (
+                    "Notify",
+                    [route.notify_url.clone(), route.notify_email.clone()]
+                        .into_iter()
+                        .flatten()
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                ),
+            ];
+            for (name, value) in fields {
+                println!("{:<17} {}", format!("{}:", name), value);
+            }
+        }
+    }
+}
+
+fn yes_no(value: i32) -> String {
+    if value != 0 { "yes" } else { "no" }.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::connect_test_database;
+    use clap::Parser;
+    use sea_query::IntoTableRef;
+
+    #[derive(Parser)]
+    struct TestCli {
+        #[command(flatten)]
+        options: RouteOptions,
+    }
+
+    fn options(args: &[&str]) -> RouteOptions {
+        TestCli::parse_from(std::iter::once("route").chain(args.iter().copied())).options
+    }
+
+    async fn add_route(db: &mut DBConnection, args: &[&str]) -> Result<()> {
+        execute_route_add(
+            db,
+            "example.com",
+            "https://hooks.example.com/bounces",
+            false,
+            options(args),
+            false,
+        )
+        .await
+    }
+
+    async fn count_rows(db: &mut DBConnection, table: impl IntoTableRef) -> i64 {
+        let (sql, values) = Query::select()
+            .expr(Expr::cust("COUNT(*)"))
+            .from(table)
+            .build_any_sqlx(&*db.query_builder);
+        sqlx::query_with(&sql, values)
+            .fetch_one(&mut db.connection)
+            .await
+            .unwrap()
+            .try_get(0)
+            .unwrap()
+    }
+
+    #[test]
+    fn validate_domain_normalizes_names() {
+        assert_eq!(validate_domain(" Example.COM ").unwrap(), "example.com");
+        assert_eq!(
+            validate_domain("bücher.example").unwrap(),
+            "xn--bcher-kva.example"
+        );
+        assert_eq!(
+            validate_domain("*.Bücher.example").unwrap(),
+            "*.xn--bcher-kva.example"
+        );
+        assert_eq!(validate_domain("*").unwrap(), "*");
+    }
+
+    #[test]
+    fn validate_domain_rejects_invalid_names() {
+        for domain in [
+            "",
+            "*.",
+            "mail.*.example.com",
+            "exa mple.com",
+            "example..com",
+            "-example.com",
+            "example-.com",
+            "user@example.com",
+            &format!("{}.com", "a".repeat(64)),
+        ] {
+            assert!(validate_domain(domain).is_err(), "{}", domain);
+        }
+    }
+
+    #[test]
+    fn validate_url_requires_http_with_host() {
+        assert!(validate_url("https://hooks.example.com/bounces").is_ok());
+        assert!(validate_url("http://127.0.0.1:8080/").is_ok());
+        assert!(validate_url("ftp://hooks.example.com/").is_err());
+        assert!(validate_url("hooks.example.com/bounces").is_err());
+        assert!(validate_url("https://").is_err());
+        assert!(validate_url("file:///tmp/hook").is_err());
+    }
+
+    #[tokio::test]
+    async fn empty_values_clear_options() {
+        let mut db = connect_test_database().await;
+        add_route(
+            &mut db,
+            &[
+                "--user",
+                "John",
+                "--tag-pattern",
+                "^(?P<id>.+)$",
+                "--event-types",
+                "bounce",
+            ],
+        )
+        .await
+        .unwrap();
+        let route = load_route(&mut db, 1).await.unwrap();
+        assert_eq!(route.user.as_deref(), Some("john"));
+        assert_eq!(route.tag_pattern.as_deref(), Some("^(?P<id>.+)$"));
+
+        execute_route_update(
+            &mut db,
+            1,
+            None,
+            None,
+            options(&["--user", "", "--tag-pattern", "", "--event-types", ""]),
+            false,
+        )
+        .await
+        .unwrap();
+        let route = load_route(&mut db, 1).await.unwrap();
+        assert_eq!(route.user, None);
+        assert_eq!(route.tag_pattern, None);
+        assert_eq!(route.event_types, None);
+
+        // The secret token cannot be cleared
+        let update = execute_route_update(
+            &mut db,
+            1,
+            None,
+            None,
+            options(&["--secret-token", ""]),
+            false,
+        )
+        .await;
+        assert!(update.is_err());
+    }
+
+    #[tokio::test]
+    async fn delete_keeps_queued_webhooks_without_force() {
+        let mut db = connect_test_database().await;
+        add_route(&mut db, &[]).await.unwrap();
+        let (sql, values) = Query::insert()
+            .into_table(WebhookQueue::Table)
+            .columns([WebhookQueue::EmailRouteId, WebhookQueue::Payload])
+            .values_panic([1.into(), "{}".into()])
+            .build_any_sqlx(&*db.query_builder);
+        sqlx::query_with(&sql, values)
+            .execute(&mut db.connection)
+            .await
+            .unwrap();
+
+        assert!(execute_route_delete(&mut db, 1, false).await.is_err());
+        assert_eq!(count_rows(&mut db, EmailRoute::Table).await, 1);
+        assert_eq!(count_rows(&mut db, WebhookQueue::Table).await, 1);
+
+        execute_route_delete(&mut db, 1, true).await.unwrap();
+        assert_eq!(count_rows(&mut db, EmailRoute::Table).await, 0);
+        assert_eq!(count_rows(&mut db, WebhookQueue::Table).await, 0);
+    }
+
+    #[tokio::test]
+    async fn add_and_update_reject_invalid_match_rules() {
+        let mut db = connect_test_database().await;
+        for rules in ["{", r#"{"subjects": "x"}"#, r#"{"subject": "("}"#] {
+            assert!(add_route(&mut db, &["--match-rules", rules]).await.is_err());
+        }
+        add_route(&mut db, &["--match-rules", r#"{"subject": "digest"}"#])
+            .await
+            .unwrap();
+
+        let update = execute_route_update(
+            &mut db,
+            1,
+            None,
+            None,
+            options(&["--match-rules", r#"{"status": "["}"#]),
+            false,
+        )
+        .await;
+        assert!(update.is_err());
+        assert_eq!(
+            load_route(&mut db, 1).await.unwrap().match_rules.as_deref(),
+            Some(r#"{"subject": "digest"}"#)
+        );
+    }
+}
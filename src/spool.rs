@@ -0,0 +1,375 @@
+use crate::AppConfig;
+use crate::classify::Classifier;
+use crate::db::DBConnection;
+use crate::ingest::{Envelope, IngestError, ingest_message};
+use anyhow::{Context, Result};
+use base64::Engine;
+use base64::prelude::BASE64_STANDARD;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, SystemTime};
+use time::UtcDateTime;
+use time::format_description::well_known::Rfc3339;
+use tracing::{debug, error, info, warn};
+
+const SPOOL_EXTENSION: &str = "json";
+/// Sub directory for spooled messages that cannot be parsed and would never replay
+const FAILED_DIRECTORY: &str = "failed";
+/// Sub directory for spooled messages claimed by a running replay
+const PROCESSING_DIRECTORY: &str = "processing";
+/// Claims older than this were left behind by an aborted replay and are released again
+const STALE_CLAIM_AGE: Duration = Duration::from_secs(60 * 60);
+
+static SEQUENCE: AtomicU32 = AtomicU32::new(0);
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SpooledMessage {
+    sender: Option<String>,
+    recipient: Option<String>,
+    spooled_at: String,
+    /// Base64 encoded raw message
+    message: String,
+}
+
+/// Writes the raw message into the spool directory. The file is written under a
+/// temporary name first and renamed once complete, so replay never sees partial files.
+pub fn spool_message(directory: &Path, raw_message: &[u8], envelope: &Envelope) -> Result<PathBuf> {
+    fs::create_dir_all(directory)
+        .with_context(|| format!("Failed to create spool directory {}", directory.display()))?;
+
+    let spooled = SpooledMessage {
+        sender: envelope.sender.clone(),
+        recipient: envelope.recipient.clone(),
+        spooled_at: UtcDateTime::now().format(&Rfc3339)?,
+        message: BASE64_STANDARD.encode(raw_message),
+    };
+
+    // Names sort by spool time, so replay keeps the arrival order
+    let name = format!(
+        "{}-{}-{}.{}",
+        UtcDateTime::now().unix_timestamp_nanos(),
+        process::id(),
+        SEQUENCE.fetch_add(1, Ordering::Relaxed),
+        SPOOL_EXTENSION
+    );
+    let temporary_path = directory.join(format!(".{}.tmp", name));
+    let path = directory.join(name);
+
+    let mut file = fs::File::create(&temporary_path)
+        .with_context(|| format!("Failed to create {}", temporary_path.display()))?;
+    file.write_all(&serde_json::to_vec(&spooled)?)?;
+    file.sync_all()?;
+    fs::rename(&temporary_path, &path)
+        .with_context(|| format!("Failed to move spool file to {}", path.display()))?;
+
+    Ok(path)
+}
+
+/// Feeds all spooled messages back through the ingest pipeline, oldest first, and
+/// removes them on success. Each message is claimed by moving it into the processing
+/// directory first, so concurrent replays (worker and `spool replay`) never ingest it
+/// twice. Stops at the first temporary failure and puts that message back. Returns the
+/// number of replayed messages.
+pub async fn replay_spool(
+    config: &AppConfig,
+    classifier: &Classifier,
+    db: &mut DBConnection,
+) -> Result<usize> {
+    let directory = &config.spool_directory;
+    if !directory.is_dir() {
+        return Ok(0);
+    }
+    let processing_directory = directory.join(PROCESSING_DIRECTORY);
+    release_stale_claims(directory, &processing_directory)?;
+
+    let mut paths = spool_files(directory)?;
+    if paths.is_empty() {
+        return Ok(0);
+    }
+    paths.sort();
+    info!(count = paths.len(), "Replaying spooled messages");
+
+    fs::create_dir_all(&processing_directory)
+        .with_context(|| format!("Failed to create {}", processing_directory.display()))?;
+    let mut replayed = 0;
+    for spooled_path in paths {
+        let Some(path) = claim(&processing_directory, &spooled_path)? else {
+            continue;
+        };
+        let spooled = match read_spooled_message(&path) {
+            Ok(spooled) => spooled,
+            Err(e) => {
+                error!(
+                    path = %path.display(),
+                    error = format!("{:#}", e),
+                    "Invalid spool file"
+                );
+                move_to_failed(directory, &path)?;
+                continue;
+            }
+        };
+        let envelope = Envelope {
+            sender: spooled.sender,
+            recipient: spooled.recipient,
+        };
+
+        match ingest_message(config, classifier, db, &spooled.raw_message, &envelope).await {
+            Ok(_) => {
+                fs::remove_file(&path)
+                    .with_context(|| format!("Failed to remove {}", path.display()))?;
+                debug!(path = %path.display(), "Replayed spooled message");
+                replayed += 1;
+            }
+            Err(IngestError::Data(e)) => {
+                error!(
+                    path = %path.display(),
+                    error = format!("{:#}", e),
+                    "Spooled message cannot be parsed"
+                );
+                move_to_failed(directory, &path)?;
+            }
+            Err(IngestError::Temporary(e)) => {
+                warn!(
+                    path = %spooled_path.display(),
+                    error = format!("{:#}", e),
+                    "Spool replay failed, retrying later"
+                );
+                fs::rename(&path, &spooled_path).with_context(|| {
+                    format!(
+                        "Failed to move spool file back to {}",
+                        spooled_path.display()
+                    )
+                })?;
+                break;
+            }
+        }
+    }
+
+    if replayed > 0 {
+        info!(count = replayed, "Replayed spooled messages");
+    }
+    Ok(replayed)
+}
+
+fn spool_files(directory: &Path) -> Result<Vec<PathBuf>> {
+    Ok(fs::read_dir(directory)
+        .with_context(|| format!("Failed to read spool directory {}", directory.display()))?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.is_file() && path.extension().is_some_and(|ext| ext == SPOOL_EXTENSION))
+        .collect())
+}
+
+/// Moves the spool file into the processing directory. Returns `None` if another
+/// replay claimed it first.
+fn claim(processing_directory: &Path, path: &Path) -> Result<Option<PathBuf>> {
+    let target = processing_directory.join(path.file_name().unwrap_or_default());
+    match fs::rename(path, &target) {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => {
+            return Err(e)
+                .with_context(|| format!("Failed to claim spool file {}", path.display()));
+        }
+    }
+    // Renaming keeps the spool time, the claim time tells stale claims apart
+    fs::File::options()
+        .write(true)
+        .open(&target)
+        .and_then(|file| file.set_modified(SystemTime::now()))
+        .with_context(|| format!("Failed to update {}", target.display()))?;
+    Ok(Some(target))
+}
+
+/// Puts back messages left in the processing directory by a replay that was aborted
+/// while ingesting them
+fn release_stale_claims(directory: &Path, processing_directory: &Path) -> Result<()> {
+    if !processing_directory.is_dir() {
+        return Ok(());
+    }
+    for path in spool_files(processing_directory)? {
+        let stale = fs::metadata(&path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .and_then(|modified| modified.elapsed().ok())
+            .is_some_and(|age| age > STALE_CLAIM_AGE);
+        if stale {
+            let target = directory.join(path.file_name().unwrap_or_default());
+            fs::rename(&path, &target)
+                .with_context(|| format!("Failed to release spool file {}", path.display()))?;
+            warn!(path = %target.display(), "Released stale spool file claim");
+        }
+    }
+    Ok(())
+}
+
+struct ReplayMessage {
+    sender: Option<String>,
+    recipient: Option<String>,
+    raw_message: Vec<u8>,
+}
+
+fn read_spooled_message(path: &Path) -> Result<ReplayMessage> {
+    let content = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let spooled = serde_json::from_slice::<SpooledMessage>(&content)?;
+    Ok(ReplayMessage {
+        sender: spooled.sender,
+        recipient: spooled.recipient,
+        raw_message: BASE64_STANDARD.decode(spooled.message)?,
+    })
+}
+
+fn move_to_failed(directory: &Path, path: &Path) -> Result<()> {
+    let failed_directory = directory.join(FAILED_DIRECTORY);
+    fs::create_dir_all(&failed_directory)?;
+    let target = failed_directory.join(path.file_name().unwrap_or_default());
+    fs::rename(path, &target)
+        .with_context(|| format!("Failed to move spool file to {}", target.display()))?;
+    warn!(path = %target.display(), "Moved spool file aside");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{connect_database, connect_test_database};
+    use crate::test_config;
+
+    const BOUNCE: &[u8] = include_bytes!("../tests/fixtures/dsn/postfix_multi.eml");
+
+    fn spool_config(name: &str) -> AppConfig {
+        let directory =
+            std::env::temp_dir().join(format!("bounce-relay-test-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&directory);
+        let mut config = test_config();
+        config.spool_directory = directory;
+        config
+    }
+
+    fn envelope() -> Envelope {
+        Envelope {
+            sender: None,
+            recipient: Some("bounces@example.com".to_string()),
+        }
+    }
+
+    fn file_names(directory: &Path) -> Vec<String> {
+        let mut names = fs::read_dir(directory)
+            .map(|entries| {
+                entries
+                    .filter_map(|entry| entry.ok())
+                    .filter(|entry| entry.path().is_file())
+                    .map(|entry| entry.file_name().to_string_lossy().to_string())
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        names.sort();
+        names
+    }
+
+    #[tokio::test]
+    async fn replay_removes_ingested_messages() {
+        let config = spool_config("replay");
+        let directory = &config.spool_directory;
+        let path = spool_message(directory, BOUNCE, &envelope()).unwrap();
+
+        // Only the complete file is left behind
+        assert_eq!(
+            file_names(directory),
+            [path.file_name().unwrap().to_str().unwrap()]
+        );
+        let spooled = read_spooled_message(&path).unwrap();
+        assert_eq!(spooled.raw_message, BOUNCE);
+        assert_eq!(spooled.recipient.as_deref(), Some("bounces@example.com"));
+
+        let mut db = connect_test_database().await;
+        let classifier = Classifier::new(None).unwrap();
+        assert_eq!(
+            replay_spool(&config, &classifier, &mut db).await.unwrap(),
+            1
+        );
+        assert!(file_names(directory).is_empty());
+        assert!(file_names(&directory.join(PROCESSING_DIRECTORY)).is_empty());
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn replay_moves_invalid_files_to_failed() {
+        let config = spool_config("invalid");
+        let directory = &config.spool_directory;
+        fs::create_dir_all(directory).unwrap();
+        fs::write(directory.join("1-1-0.json"), b"not json").unwrap();
+
+        let mut db = connect_test_database().await;
+        let classifier = Classifier::new(None).unwrap();
+        assert_eq!(
+            replay_spool(&config, &classifier, &mut db).await.unwrap(),
+            0
+        );
+        assert!(file_names(directory).is_empty());
+        assert_eq!(
+            file_names(&directory.join(FAILED_DIRECTORY)),
+            ["1-1-0.json"]
+        );
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn replay_keeps_messages_on_temporary_failure() {
+        let config = spool_config("temporary");
+        let directory = &config.spool_directory;
+        let first = spool_message(directory, BOUNCE, &envelope()).unwrap();
+        let second = spool_message(directory, BOUNCE, &envelope()).unwrap();
+
+        // Without migrations the route lookup fails
+        let mut db = connect_database(&config).await.unwrap();
+        let classifier = Classifier::new(None).unwrap();
+        assert_eq!(
+            replay_spool(&config, &classifier, &mut db).await.unwrap(),
+            0
+        );
+        assert!(first.is_file());
+        assert!(second.is_file());
+        assert!(file_names(&directory.join(PROCESSING_DIRECTORY)).is_empty());
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn replay_skips_claimed_messages_until_stale() {
+        let config = spool_config("claimed");
+        let directory = &config.spool_directory;
+        let path = spool_message(directory, BOUNCE, &envelope()).unwrap();
+        let processing_directory = directory.join(PROCESSING_DIRECTORY);
+        fs::create_dir_all(&processing_directory).unwrap();
+        let claimed = claim(&processing_directory, &path).unwrap().unwrap();
+        assert_eq!(claim(&processing_directory, &path).unwrap(), None);
+
+        let mut db = connect_test_database().await;
+        let classifier = Classifier::new(None).unwrap();
+        assert_eq!(
+            replay_spool(&config, &classifier, &mut db).await.unwrap(),
+            0
+        );
+        assert!(claimed.is_file());
+
+        fs::File::options()
+            .write(true)
+            .open(&claimed)
+            .unwrap()
+            .set_modified(SystemTime::now() - STALE_CLAIM_AGE * 2)
+            .unwrap();
+        assert_eq!(
+            replay_spool(&config, &classifier, &mut db).await.unwrap(),
+            1
+        );
+        assert!(!claimed.exists());
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,559 @@
+use crate::db::{
+    BounceEvent, DBConnection, Suppression, insert_if_absent, timestamp_as_text, timestamp_value,
+};
+use anyhow::{Context, Result, bail};
+use clap::ValueEnum;
+use sea_query::{Expr, Iden, Order, Query};
+use sea_query_binder::SqlxBinder;
+use sqlx::Row;
+use std::collections::BTreeSet;
+use std::str::FromStr;
+use time::{Duration, OffsetDateTime};
+use tracing::info;
+
+/// Why an address was suppressed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SuppressionReason {
+    HardBounce,
+    SoftBounce,
+    Complaint,
+    Manual,
+}
+
+impl SuppressionReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::HardBounce => "hard_bounce",
+            Self::SoftBounce => "soft_bounce",
+            Self::Complaint => "complaint",
+            Self::Manual => "manual",
+        }
+    }
+
+    /// Parses a comma separated list of reasons such as `hard_bounce,complaint`
+    pub fn parse_list(value: &str) -> Result<Vec<Self>> {
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(str::parse)
+            .collect()
+    }
+}
+
+impl FromStr for SuppressionReason {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        Ok(match value {
+            "hard_bounce" => Self::HardBounce,
+            "soft_bounce" => Self::SoftBounce,
+            "complaint" => Self::Complaint,
+            "manual" => Self::Manual,
+            _ => bail!("Unknown suppression reason {}", value),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum ExportFormat {
+    /// Comma separated values with a header line
+    Csv,
+    /// Postfix access table, usable with check_recipient_access
+    Postfix,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct SuppressionEntry {
+    domain: String,
+    email: String,
+    reason: String,
+    description: Option<String>,
+    created_at: String,
+}
+
+/// Adds the address to the suppression list of the route domain. Returns `false` if
+/// it was suppressed already.
+pub async fn suppress(
+    db: &mut DBConnection,
+    domain: &str,
+    email: &str,
+    reason: SuppressionReason,
+    description: Option<&str>,
+    bounce_event_id: Option<i64>,
+) -> Result<bool> {
+    let domain = domain.to_lowercase();
+    let email = email.to_lowercase();
+
+    // A concurrent ingest may suppress the same address, so rely on the unique index
+    // rather than checking for an existing row first
+    let statement = Query::insert()
+        .into_table(Suppression::Table)
+        .columns([
+            Suppression::Domain,
+            Suppression::Email,
+            Suppression::Reason,
+            Suppression::Description,
+            Suppression::BounceEventId,
+        ])
+        .values_panic([
+            domain.as_str().into(),
+            email.as_str().into(),
+            reason.as_str().into(),
+            description.into(),
+            bounce_event_id.into(),
+        ])
+        .to_owned();
+    if !insert_if_absent(db, statement, [Suppression::Domain, Suppression::Email]).await? {
+        return Ok(false);
+    }
+
+    info!(
+        domain = domain,
+        email = email,
+        reason = reason.as_str(),
+        "Suppressed address"
+    );
+    Ok(true)
+}
+
+/// Returns the reason of the first suppression of the address in any route domain,
+/// considering only suppressions for one of `reasons`
+pub async fn find_suppression(
+    db: &mut DBConnection,
+    email: &str,
+    reasons: &[SuppressionReason],
+) -> Result<Option<String>> {
+    let query_builder = &*db.query_builder;
+    let (sql, values) = Query::select()
+        .column(Suppression::Reason)
+        .from(Suppression::Table)
+        .and_where(Expr::col(Suppression::Email).eq(email.to_lowercase()))
+        .and_where(Expr::col(Suppression::Reason).is_in(reasons.iter().map(|r| r.as_str())))
+        .limit(1)
+        .build_any_sqlx(query_builder);
+    let row = sqlx::query_with(&sql, values)
+        .fetch_optional(&mut db.connection)
+        .await?;
+    Ok(row
+        .map(|row| row.try_get(Suppression::Reason.to_string().as_str()))
+        .transpose()?)
+}
+
+/// Counts the soft bounces of an address for the route domain within the last `days`
+pub async fn count_soft_bounces(
+    db: &mut DBConnection,
+    domain: &str,
+    email: &str,
+    days: u32,
+) -> Result<i64> {
+    let since = OffsetDateTime::now_utc() - Duration::days(days.into());
+
+    let query_builder = &*db.query_builder;
+    let (sql, values) = Query::select()
+        .expr(Expr::col(BounceEvent::Id).count())
+        .from(BounceEvent::Table)
+        .and_where(Expr::col(BounceEvent::Domain).eq(domain.to_lowercase()))
+        .and_where(Expr::col(BounceEvent::Recipient).eq(email.to_lowercase()))
+        .and_where(Expr::col(BounceEvent::EventType).eq("bounce"))
+        .and_where(Expr::col(BounceEvent::Category).eq("soft"))
+        .and_where(Expr::col(BounceEvent::ReceivedAt).gte(timestamp_value(db, since)))
+        .build_any_sqlx(query_builder);
+    let row = sqlx::query_with(&sql, values)
+        .fetch_one(&mut db.connection)
+        .await?;
+    Ok(row.try_get(0)?)
+}
+
+pub async fn execute_suppression_list(db: &mut DBConnection, domain: Option<&str>) -> Result<()> {
+    let entries = load_suppressions(db, domain).await?;
+    if entries.is_empty() {
+        println!("No suppressed addresses");
+        return Ok(());
+    }
+
+    println!(
+        "{:<40} {:<30} {:<12} {:<20} DESCRIPTION",
+        "EMAIL", "DOMAIN", "REASON", "CREATED AT"
+    );
+    for entry in entries {
+        println!(
+            "{:<40} {:<30} {:<12} {:<20} {}",
+            entry.email,
+            entry.domain,
+            entry.reason,
+            entry.created_at,
+            entry.description.unwrap_or_default()
+        );
+    }
+    Ok(())
+}
+
+pub async fn execute_suppression_add(
+    db: &mut DBConnection,
+    domain: &str,
+    email: &str,
+    description: Option<&str>,
+) -> Result<()> {
+    if !email.contains('@') {
+        bail!("Invalid email address {}", email);
+    }
+
+    if !suppress(
+        db,
+        domain,
+        email,
+        SuppressionReason::Manual,
+        description,
+        None,
+    )
+    .await?
+    {
+        println!("{} is already suppressed for {}", email, domain);
+    }
+    Ok(())
+}
+
+pub async fn execute_suppression_remove(
+    db: &mut DBConnection,
+    domain: Option<&str>,
+    email: &str,
+) -> Result<()> {
+    let query_builder = &*db.query_builder;
+    let mut query = Query::delete();
+    query
+        .from_table(Suppression::Table)
+        .and_where(Expr::col(Suppression::Email).eq(email.to_lowercase()));
+    if let Some(domain) = domain {
+        query.and_where(Expr::col(Suppression::Domain).eq(domain.to_lowercase()));
+    }
+    let (sql, values) = query.build_any_sqlx(query_builder);
+
+    let result = sqlx::query_with(&sql, values)
+        .execute(&mut db.connection)
+        .await?;
+    if result.rows_affected() == 0 {
+        bail!("{} is not suppressed", email);
+    }
+    info!(
+        email = email,
+        count = result.rows_affected(),
+        "Removed suppression"
+    );
+    Ok(())
+}
+
+pub async fn execute_suppression_export(
+    db: &mut DBConnection,
+    domain: Option<&str>,
+    format: ExportFormat,
+    postfix_action: &str,
+) -> Result<()> {
+    let entries = load_suppressions(db, domain).await?;
+    for line in export_lines(entries, format, postfix_action) {
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+fn export_lines(
+    entries: Vec<SuppressionEntry>,
+    format: ExportFormat,
+    postfix_action: &str,
+) -> Vec<String> {
+    match format {
+        ExportFormat::Csv => {
+            let mut lines = vec!["domain,email,reason,description,created_at".to_string()];
+            lines.extend(entries.into_iter().map(|entry| {
+                format!(
+                    "{},{},{},{},{}",
+                    csv_field(&entry.domain),
+                    csv_field(&entry.email),
+                    csv_field(&entry.reason),
+                    csv_field(entry.description.as_deref().unwrap_or_default()),
+                    csv_field(&entry.created_at)
+                )
+            }));
+            lines
+        }
+        ExportFormat::Postfix => {
+            // Postfix looks up the recipient address only, so list each address once
+            entries
+                .into_iter()
+                .map(|entry| entry.email)
+                .collect::<BTreeSet<_>>()
+                .into_iter()
+                .map(|email| format!("{} {}", email, postfix_action))
+                .collect()
+        }
+    }
+}
+
+async fn load_suppressions(
+    db: &mut DBConnection,
+    domain: Option<&str>,
+) -> Result<Vec<SuppressionEntry>> {
+    let query_builder = &*db.query_builder;
+    let mut query = Query::select();
+    query
+        .columns([
+            Suppression::Domain,
+            Suppression::Email,
+            Suppression::Reason,
+            Suppression::Description,
+        ])
+        .expr_as(
+            timestamp_as_text(db, Suppression::CreatedAt),
+            Suppression::CreatedAt,
+        )
+        .from(Suppression::Table)
+        .order_by(Suppression::Domain, Order::Asc)
+        .order_by(Suppression::Email, Order::Asc);
+    if let Some(domain) = domain {
+        query.and_where(Expr::col(Suppression::Domain).eq(domain.to_lowercase()));
+    }
+    let (sql, values) = query.build_any_sqlx(query_builder);
+
+    sqlx::query_as_with(&sql, values)
+        .fetch_all(&mut db.connection)
+        .await
+        .with_context(|| "Failed to load suppressions")
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::connect_test_database;
+
+    async fn add_soft_bounce(db: &mut DBConnection, email: &str, received_at: OffsetDateTime) {
+        let (sql, values) = Query::insert()
+            .into_table(BounceEvent::Table)
+            .columns([
+                BounceEvent::EventType,
+                BounceEvent::Domain,
+                BounceEvent::Recipient,
+                BounceEvent::Category,
+                BounceEvent::ReceivedAt,
+            ])
+            .values_panic([
+                "bounce".into(),
+                "example.com".into(),
+                email.into(),
+                "soft".into(),
+                timestamp_value(db, received_at),
+            ])
+            .build_any_sqlx(&*db.query_builder);
+        sqlx::query_with(&sql, values)
+            .execute(&mut db.connection)
+            .await
+            .unwrap();
+    }
+
+    fn entry(email: &str, description: Option<&str>) -> SuppressionEntry {
+        SuppressionEntry {
+            domain: "example.com".to_string(),
+            email: email.to_string(),
+            reason: "manual".to_string(),
+            description: description.map(str::to_string),
+            created_at: "2026-01-02 03:04:05".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn suppress_is_idempotent() {
+        let mut db = connect_test_database().await;
+        let hard_bounce = SuppressionReason::HardBounce;
+
+        assert!(
+            suppress(
+                &mut db,
+                "Example.com",
+                "User@Example.org",
+                hard_bounce,
+                None,
+                None
+            )
+            .await
+            .unwrap()
+        );
+        assert!(
+            !suppress(
+                &mut db,
+                "example.com",
+                "user@example.org",
+                hard_bounce,
+                None,
+                None
+            )
+            .await
+            .unwrap()
+        );
+        // Each route domain keeps its own suppression list
+        assert!(
+            suppress(
+                &mut db,
+                "example.net",
+                "user@example.org",
+                hard_bounce,
+                None,
+                None
+            )
+            .await
+            .unwrap()
+        );
+
+        let entries = load_suppressions(&mut db, Some("EXAMPLE.COM"))
+            .await
+            .unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].email, "user@example.org");
+        assert_eq!(entries[0].reason, "hard_bounce");
+    }
+
+    #[tokio::test]
+    async fn find_suppression_filters_reasons() {
+        let mut db = connect_test_database().await;
+        let complaint = SuppressionReason::Complaint;
+        suppress(
+            &mut db,
+            "example.com",
+            "user@example.org",
+            complaint,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+
+        let hard_bounce = [SuppressionReason::HardBounce];
+        assert_eq!(
+            find_suppression(&mut db, "User@example.org", &hard_bounce)
+                .await
+                .unwrap(),
+            None
+        );
+        let reasons = SuppressionReason::parse_list("hard_bounce, complaint").unwrap();
+        assert_eq!(
+            find_suppression(&mut db, "User@example.org", &reasons)
+                .await
+                .unwrap()
+                .as_deref(),
+            Some("complaint")
+        );
+    }
+
+    #[test]
+    fn parse_reason_list() {
+        assert_eq!(
+            SuppressionReason::parse_list("hard_bounce,manual").unwrap(),
+            [SuppressionReason::HardBounce, SuppressionReason::Manual]
+        );
+        assert!(SuppressionReason::parse_list("hard_bounce,bounce").is_err());
+    }
+
+    #[tokio::test]
+    async fn count_soft_bounces_within_window() {
+        let mut db = connect_test_database().await;
+        let now = OffsetDateTime::now_utc();
+        add_soft_bounce(&mut db, "user@example.org", now).await;
+        add_soft_bounce(&mut db, "user@example.org", now - Duration::days(1)).await;
+        add_soft_bounce(&mut db, "user@example.org", now - Duration::days(10)).await;
+        add_soft_bounce(&mut db, "other@example.org", now).await;
+
+        assert_eq!(
+            count_soft_bounces(&mut db, "Example.com", "User@example.org", 7)
+                .await
+                .unwrap(),
+            2
+        );
+        assert_eq!(
+            count_soft_bounces(&mut db, "example.com", "user@example.org", 30)
+                .await
+                .unwrap(),
+            3
+        );
+    }
+
+    #[tokio::test]
+    async fn add_and_remove_suppressions() {
+        let mut db = connect_test_database().await;
+
+        assert!(
+            execute_suppression_add(&mut db, "example.com", "invalid", None)
+                .await
+                .is_err()
+        );
+        execute_suppression_add(&mut db, "example.com", "user@example.org", Some("Asked"))
+            .await
+            .unwrap();
+        // Adding it again is not an error
+        execute_suppression_add(&mut db, "example.com", "user@example.org", None)
+            .await
+            .unwrap();
+        execute_suppression_add(&mut db, "example.net", "user@example.org", None)
+            .await
+            .unwrap();
+
+        let entries = load_suppressions(&mut db, None).await.unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].description.as_deref(), Some("Asked"));
+        assert_eq!(entries[0].reason, "manual");
+
+        execute_suppression_remove(&mut db, Some("example.net"), "User@example.org")
+            .await
+            .unwrap();
+        assert_eq!(load_suppressions(&mut db, None).await.unwrap().len(), 1);
+        execute_suppression_remove(&mut db, None, "user@example.org")
+            .await
+            .unwrap();
+        assert!(
+            execute_suppression_remove(&mut db, None, "user@example.org")
+                .await
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn export_csv() {
+        let entries = vec![
+            entry("a@example.org", Some("Said \"stop\", twice")),
+            entry("b@example.org", None),
+        ];
+
+        assert_eq!(
+            export_lines(entries, ExportFormat::Csv, "REJECT"),
+            [
+                "domain,email,reason,description,created_at",
+                "example.com,a@example.org,manual,\"Said \"\"stop\"\", twice\",2026-01-02 03:04:05",
+                "example.com,b@example.org,manual,,2026-01-02 03:04:05",
+            ]
+        );
+    }
+
+    #[test]
+    fn export_postfix_lists_each_address_once() {
+        let mut other_domain = entry("a@example.org", None);
+        other_domain.domain = "example.net".to_string();
+        let entries = vec![
+            entry("a@example.org", None),
+            entry("b@example.org", None),
+            other_domain,
+        ];
+
+        assert_eq!(
+            export_lines(entries, ExportFormat::Postfix, "REJECT Suppressed"),
+            [
+                "a@example.org REJECT Suppressed",
+                "b@example.org REJECT Suppressed"
+            ]
+        );
+    }
+}
@@ -1,16 +1,30 @@
 use crate::AppConfig;
-use crate::db::{DBConnection, EmailRoute, WebhookQueue};
-use anyhow::{Context, Result, bail};
+use crate::backoff::{BackoffPolicy, parse_retry_after};
+use crate::classify::Classifier;
+use crate::db::{DBConnection, DeadLetter, EmailRoute, WebhookQueue, bool_as_int, timestamp_value};
+use crate::dead_letter::{DeadLetterNotice, DeadLetterReason, NotifyTarget, notify_dead_letters};
+use crate::queue::{Attempt, append_attempt};
+use crate::response_policy::ResponsePolicy;
+use crate::spool::replay_spool;
+use anyhow::{Context, Result};
 use base64::Engine;
 use base64::prelude::BASE64_STANDARD;
 use hmac::{Hmac, Mac};
-use reqwest::Client;
-use sea_query::{Alias, Expr, Order, Query};
+use reqwest::{Client, RequestBuilder, StatusCode};
+use sea_query::{
+    Alias, Cond, Expr, Keyword, LockBehavior, LockType, Order, Query, QueryBuilder,
+    SelectStatement, SimpleExpr,
+};
 use sea_query_binder::SqlxBinder;
 use sha2::Sha512;
+use sqlx::{AnyConnection, Connection, Row};
+use std::collections::HashMap;
+use std::fmt;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use time::format_description::well_known::Rfc3339;
 use time::{Duration as TimeDuration, OffsetDateTime};
 use tokio::signal;
+use tokio::task::{self, JoinError, JoinSet};
 use tracing::{debug, error, info, warn};
 
 type HmacSha512 = Hmac<Sha512>;
@@ -18,10 +32,133 @@ type HmacSha512 = Hmac<Sha512>;
 #[derive(Debug, sqlx::FromRow)]
 struct JobToExecute {
     id: i32,
+    email_route_id: i32,
     url: String,
     secret_token: String,
     payload: String,
     attempts: i32,
+    attempt_log: Option<String>,
+    max_concurrency: Option<i32>,
+    ordered_delivery: i32,
+    retry_base_delay_seconds: Option<i32>,
+    retry_multiplier: Option<f64>,
+    retry_max_delay_seconds: Option<i32>,
+    retry_status_codes: Option<String>,
+    permanent_status_codes: Option<String>,
+    disable_after_gone: Option<i32>,
+    notify_url: Option<String>,
+    notify_email: Option<String>,
+}
+
+/// Non-success response of a webhook receiver
+#[derive(Debug)]
+struct HttpStatusError {
+    status: StatusCode,
+    body: String,
+    /// `Retry-After` of 429 and 503 responses
+    retry_after: Option<Duration>,
+}
+
+impl fmt::Display for HttpStatusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.status, self.body)
+    }
+}
+
+impl std::error::Error for HttpStatusError {}
+
+/// Deliveries in flight, kept across worker iterations so due jobs are claimed while
+/// slow receivers are still being called
+#[derive(Default)]
+struct Deliveries {
+    tasks: JoinSet<(JobToExecute, Result<()>)>,
+    /// Route of each delivery task, to free its slot even if the task panicked
+    task_routes: HashMap<task::Id, i32>,
+    routes: HashMap<i32, RouteSlots>,
+    /// Dead letters whose route owners are notified in the next iteration
+    dead_letters: Vec<(JobToExecute, DeadLetterNotice)>,
+}
+
+/// Deliveries of a route in flight and how many it may have
+#[derive(Debug, Clone, Copy)]
+struct RouteSlots {
+    in_flight: usize,
+    limit: usize,
+}
+
+/// Due job that may be claimed, with the concurrency settings of its route
+#[derive(Debug, sqlx::FromRow)]
+struct Candidate {
+    id: i32,
+    email_route_id: i32,
+    max_concurrency: Option<i32>,
+    ordered_delivery: i32,
+}
+
+impl Deliveries {
+    fn start(&mut self, client: &Client, job: JobToExecute, route_concurrency: usize) {
+        let limit = route_limit(job.max_concurrency, job.ordered_delivery, route_concurrency);
+        let route = self.routes.entry(job.email_route_id).or_insert(RouteSlots {
+            in_flight: 0,
+            limit,
+        });
+        route.in_flight += 1;
+        route.limit = limit;
+
+        let client = client.clone();
+        let route_id = job.email_route_id;
+        let task = self.tasks.spawn(async move {
+            let result = process_job(&client, &job).await;
+            (job, result)
+        });
+        self.task_routes.insert(task.id(), route_id);
+    }
+
+    fn finish(&mut self, task: task::Id) {
+        let Some(route_id) = self.task_routes.remove(&task) else {
+            return;
+        };
+        if let Some(route) = self.routes.get_mut(&route_id) {
+            route.in_flight -= 1;
+            if route.in_flight == 0 {
+                self.routes.remove(&route_id);
+            }
+        }
+    }
+
+    /// Routes without a free slot for another delivery
+    fn full_routes(&self) -> Vec<i32> {
+        self.routes
+            .iter()
+            .filter(|(_, route)| route.in_flight >= route.limit)
+            .map(|(route_id, _)| *route_id)
+            .collect()
+    }
+
+    /// Sends one notification per route, a receiver that went away fails many
+    /// webhooks at once
+    async fn notify_dead_letters(&mut self, config: &AppConfig, client: &Client) {
+        while let Some((job, notice)) = self.dead_letters.pop() {
+            let mut notices = vec![notice];
+            let mut index = 0;
+            while index < self.dead_letters.len() {
+                if self.dead_letters[index].0.email_route_id == job.email_route_id {
+                    notices.push(self.dead_letters.swap_remove(index).1);
+                } else {
+                    index += 1;
+                }
+            }
+            notices.sort_by_key(|notice| notice.dead_letter_id);
+            let target = NotifyTarget {
+                route_id: job.email_route_id,
+                url: &job.url,
+                secret_token: &job.secret_token,
+                notify_url: job.notify_url.as_deref(),
+                notify_email: job.notify_email.as_deref(),
+            };
+            notify_dead_letters(config, client, &target, &notices).await;
+        }
+    }
 }
 
 pub async fn execute_worker(config: AppConfig, mut db: DBConnection) -> Result<()> {
@@ -33,171 +170,691 @@ pub async fn execute_worker(config: AppConfig, mut db: DBConnection) -> Result<(
             env!("CARGO_PKG_VERSION")
         ))
         .build()?;
+    let classifier = Classifier::new(config.classification_rules_file.as_deref())?;
+    let backoff = BackoffPolicy::from_config(&config)?;
+    let worker_id = config.worker_id.clone().unwrap_or_else(default_worker_id);
+    if config.worker_lease_seconds <= config.worker_api_timeout_seconds {
+        warn!(
+            lease_seconds = config.worker_lease_seconds,
+            api_timeout_seconds = config.worker_api_timeout_seconds,
+            "worker_lease_seconds should be well above worker_api_timeout_seconds, other workers may deliver webhooks twice"
+        );
+    }
 
     info!(
+        worker_id = worker_id,
         interval_seconds = config.worker_interval_seconds,
         items_per_iteration = config.worker_items_per_iteration,
+        concurrency = config.worker_concurrency,
         "Worker started"
     );
 
+    let mut deliveries = Deliveries::default();
     let mut interval = tokio::time::interval(Duration::from_secs(config.worker_interval_seconds));
     loop {
         tokio::select! {
-            _ = interval.tick() => {}
+            _ = interval.tick() => {
+                // Messages spooled by ingest while the database was unavailable
+                if let Err(e) = replay_spool(&config, &classifier, &mut db).await {
+                    error!(error = format!("{:#}", e), "Failed to replay spool");
+                }
+                deliveries.notify_dead_letters(&config, &client).await;
+                if let Err(e) = start_jobs(&config, &client, &mut db, &worker_id, &mut deliveries).await {
+                    error!(error = format!("{:#}", e), "Failed to claim jobs");
+                }
+            }
+            Some(finished) = deliveries.tasks.join_next_with_id() => {
+                finish_delivery(&config, &backoff, &mut db, &worker_id, &mut deliveries, finished).await;
+            }
             _ = signal::ctrl_c() => {
                 info!("Worker shutting down");
                 break;
             }
         }
+    }
 
-        let jobs = find_jobs(&mut db, config.worker_items_per_iteration).await?;
-        if !jobs.is_empty() {
-            debug!(count = jobs.len(), "Found jobs to process");
+    // Let running deliveries finish, their jobs stay leased otherwise
+    while let Some(finished) = deliveries.tasks.join_next_with_id().await {
+        finish_delivery(
+            &config,
+            &backoff,
+            &mut db,
+            &worker_id,
+            &mut deliveries,
+            finished,
+        )
+        .await;
+    }
+    deliveries.notify_dead_letters(&config, &client).await;
+
+    Ok(())
+}
+
+/// Claims as many due jobs as can start right away, up to `worker_concurrency`
+/// deliveries in flight and up to the limit of each route, so a slow receiver cannot
+/// hold up other routes. Jobs are not claimed to wait behind a busy route, their
+/// leases could run out meanwhile.
+async fn start_jobs(
+    config: &AppConfig,
+    client: &Client,
+    db: &mut DBConnection,
+    worker_id: &str,
+    deliveries: &mut Deliveries,
+) -> Result<()> {
+    let mut free = config
+        .worker_concurrency
+        .max(1)
+        .saturating_sub(deliveries.tasks.len())
+        .min(config.worker_items_per_iteration as usize);
+    // Routes that fill up are left out of the next round, so the jobs of other routes
+    // are claimed even when a busy route has many due jobs
+    while free > 0 {
+        let jobs = claim_jobs(config, db, worker_id, deliveries, free).await?;
+        if jobs.is_empty() {
+            break;
         }
+        debug!(count = jobs.len(), "Claimed jobs to process");
+        free = free.saturating_sub(jobs.len());
         for job in jobs {
-            match process_job(&client, &job).await {
-                Ok(_) => {
-                    info!(id = job.id, url = job.url.as_str(), "Delivered webhook");
-                    delete_job(&mut db, job).await?;
-                }
-                Err(e) => {
-                    reschedule_job(
-                        config.worker_max_retries,
-                        config.worker_max_delay_seconds,
-                        &mut db,
-                        job,
-                        &format!("{:#}", e),
-                    )
-                    .await?
-                }
+            deliveries.start(client, job, config.worker_route_concurrency);
+        }
+    }
+    Ok(())
+}
+
+/// Handles a finished delivery task. Failures are only logged, the job stays leased
+/// and is delivered again once its lease runs out.
+async fn finish_delivery(
+    config: &AppConfig,
+    backoff: &BackoffPolicy,
+    db: &mut DBConnection,
+    worker_id: &str,
+    deliveries: &mut Deliveries,
+    finished: Result<(task::Id, (JobToExecute, Result<()>)), JoinError>,
+) {
+    match finished {
+        Ok((task, (job, result))) => {
+            deliveries.finish(task);
+            let id = job.id;
+            if let Err(e) =
+                finish_job(config, backoff, db, worker_id, deliveries, job, result).await
+            {
+                error!(id = id, error = format!("{:#}", e), "Failed to finish job");
             }
         }
+        Err(e) => {
+            deliveries.finish(e.id());
+            error!(error = %e, "Webhook delivery task failed");
+        }
+    }
+}
+
+/// Writes the result of a delivery back to the queue
+async fn finish_job(
+    config: &AppConfig,
+    backoff: &BackoffPolicy,
+    db: &mut DBConnection,
+    worker_id: &str,
+    deliveries: &mut Deliveries,
+    job: JobToExecute,
+    result: Result<()>,
+) -> Result<()> {
+    // Count consecutive 410 Gone responses of routes that are disabled after them
+    if job.disable_after_gone.is_some_and(|limit| limit > 0) {
+        let status = match &result {
+            Ok(_) => Some(StatusCode::OK),
+            Err(e) => e.downcast_ref::<HttpStatusError>().map(|e| e.status),
+        };
+        if let Some(status) = status {
+            record_gone(db, &job, status == StatusCode::GONE).await?;
+        }
     }
 
+    match result {
+        Ok(_) => {
+            info!(id = job.id, url = job.url.as_str(), "Delivered webhook");
+            delete_job(db, worker_id, job).await?;
+        }
+        Err(e) => {
+            let notice =
+                reschedule_job(config.worker_max_retries, backoff, db, worker_id, &job, e).await?;
+            if let Some(notice) = notice
+                && (job.notify_url.is_some() || job.notify_email.is_some())
+            {
+                deliveries.dead_letters.push((job, notice));
+            }
+        }
+    }
     Ok(())
 }
 
-async fn process_job(client: &Client, job: &JobToExecute) -> Result<()> {
-    // Create signature
-    let timestamp = SystemTime::now()
-        .duration_since(UNIX_EPOCH)?
-        .as_secs()
-        .to_string();
+/// Deliveries of a route a worker runs at the same time, ordered routes deliver one
+/// webhook at a time
+fn route_limit(
+    max_concurrency: Option<i32>,
+    ordered_delivery: i32,
+    route_concurrency: usize,
+) -> usize {
+    if ordered_delivery != 0 {
+        1
+    } else {
+        max_concurrency
+            .map(|limit| limit as usize)
+            .unwrap_or(route_concurrency)
+            .max(1)
+    }
+}
 
-    let mut mac = HmacSha512::new_from_slice(job.secret_token.as_bytes())?;
-    mac.update(format!("{}.{}", timestamp, job.payload).as_bytes());
-    let signature = BASE64_STANDARD.encode(mac.finalize().into_bytes());
+/// Picks up to `free` candidates, within the free slots of their routes
+fn pick_candidates(
+    candidates: &[Candidate],
+    free: usize,
+    routes: &HashMap<i32, RouteSlots>,
+    route_concurrency: usize,
+) -> Vec<i32> {
+    let mut claimed: HashMap<i32, usize> = HashMap::new();
+    let mut picked = Vec::new();
+    for candidate in candidates {
+        if picked.len() >= free {
+            break;
+        }
+        let in_flight = routes
+            .get(&candidate.email_route_id)
+            .map_or(0, |route| route.in_flight);
+        let limit = route_limit(
+            candidate.max_concurrency,
+            candidate.ordered_delivery,
+            route_concurrency,
+        );
+        let count = claimed.entry(candidate.email_route_id).or_default();
+        if in_flight + *count < limit {
+            *count += 1;
+            picked.push(candidate.id);
+        }
+    }
+    picked
+}
 
-    let response = client
-        .post(&job.url)
-        .header("Content-Type", "application/json")
-        .header("X-Timestamp", &timestamp)
-        .header("X-Signature", &signature)
-        .body(job.payload.to_owned())
+async fn process_job(client: &Client, job: &JobToExecute) -> Result<()> {
+    let response = signed_request(client, &job.url, &job.secret_token, &job.payload)?
         .send()
         .await
         .with_context(|| format!("Failed to call webhook url {}", job.url))?;
 
     let status = response.status();
     if !status.is_success() {
-        let body = response.text().await.with_context(|| {
-            format!(
-                "Failed to get response body for {} and status {}",
-                job.url, status
-            )
-        })?;
+        let retry_after = match status {
+            StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE => {
+                parse_retry_after(response.headers())
+            }
+            _ => None,
+        };
+        // Keep the status for classifying the failure even if the body is lost
+        let body = response
+            .text()
+            .await
+            .unwrap_or_else(|e| format!("failed to get response body: {}", e));
 
-        bail!("{}: {}", status, body);
+        return Err(HttpStatusError {
+            status,
+            body,
+            retry_after,
+        }
+        .into());
     }
 
     Ok(())
 }
 
-async fn delete_job(db: &mut DBConnection, job: JobToExecute) -> Result<()> {
+/// Builds the POST of a JSON payload, signed with the secret token of the route
+pub fn signed_request(
+    client: &Client,
+    url: &str,
+    secret_token: &str,
+    payload: &str,
+) -> Result<RequestBuilder> {
+    // Create signature
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)?
+        .as_secs()
+        .to_string();
+
+    let mut mac = HmacSha512::new_from_slice(secret_token.as_bytes())?;
+    mac.update(format!("{}.{}", timestamp, payload).as_bytes());
+    let signature = BASE64_STANDARD.encode(mac.finalize().into_bytes());
+
+    Ok(client
+        .post(url)
+        .header("Content-Type", "application/json")
+        .header("X-Timestamp", &timestamp)
+        .header("X-Signature", &signature)
+        .body(payload.to_owned()))
+}
+
+/// Deletes a delivered job, unless its lease expired and another worker claimed it
+async fn delete_job(db: &mut DBConnection, worker_id: &str, job: JobToExecute) -> Result<()> {
     let query_builder = &*db.query_builder;
     let (sql, values) = Query::delete()
         .from_table(WebhookQueue::Table)
         .and_where(Expr::col(WebhookQueue::Id).eq(job.id))
+        .and_where(Expr::col(WebhookQueue::LockedBy).eq(worker_id))
         .build_any_sqlx(query_builder);
 
-    sqlx::query_with(&sql, values)
+    let result = sqlx::query_with(&sql, values)
         .execute(&mut db.connection)
         .await?;
+    if result.rows_affected() == 0 {
+        warn!(
+            id = job.id,
+            "Lease of delivered webhook expired, another worker may deliver it again"
+        );
+    }
     Ok(())
 }
 
+/// Schedules the next attempt of a failed job, or moves it to the dead letters when it
+/// is given up on. Returns what to tell the route owner about a dead letter.
 async fn reschedule_job(
     max_retries: i32,
-    max_delay: i64,
+    backoff: &BackoffPolicy,
     db: &mut DBConnection,
-    job: JobToExecute,
-    error: &str,
-) -> Result<()> {
+    worker_id: &str,
+    job: &JobToExecute,
+    error: anyhow::Error,
+) -> Result<Option<DeadLetterNotice>> {
     let attempts = job.attempts + 1;
-    let is_expired = max_retries > 0 && attempts >= max_retries;
-    let minutes_to_wait = 2_i64.pow(attempts as u32).min(max_delay);
-    let next_try_at = OffsetDateTime::now_utc() + TimeDuration::minutes(minutes_to_wait);
+    let status = error.downcast_ref::<HttpStatusError>().map(|e| e.status);
+    let policy = ResponsePolicy::new(
+        job.retry_status_codes.as_deref(),
+        job.permanent_status_codes.as_deref(),
+    )
+    .unwrap_or_else(|e| {
+        warn!(
+            route_id = job.email_route_id,
+            error = format!("{:#}", e),
+            "Invalid status codes of route, using the default classification"
+        );
+        ResponsePolicy::default()
+    });
+    let is_permanent = policy.is_permanent(status);
+    let reason = if is_permanent {
+        Some(DeadLetterReason::PermanentFailure)
+    } else if max_retries > 0 && attempts >= max_retries {
+        Some(DeadLetterReason::MaxRetries)
+    } else {
+        None
+    };
+
+    let backoff = backoff
+        .with_overrides(
+            job.retry_base_delay_seconds,
+            job.retry_multiplier,
+            job.retry_max_delay_seconds,
+        )
+        .unwrap_or_else(|e| {
+            warn!(
+                route_id = job.email_route_id,
+                error = format!("{:#}", e),
+                "Invalid retry settings of route, using the worker settings"
+            );
+            backoff.clone()
+        });
+    let mut delay = backoff.delay(attempts);
+    // The receiver asked us to back off longer
+    if let Some(retry_after) = error
+        .downcast_ref::<HttpStatusError>()
+        .and_then(|e| e.retry_after)
+    {
+        delay = delay.max(retry_after);
+    }
+    let now = OffsetDateTime::now_utc();
+    let next_try_at = now + TimeDuration::seconds(delay.as_secs() as i64);
+    let error = format!("{:#}", error);
+    let attempt = Attempt {
+        attempt: attempts,
+        attempted_at: now.format(&Rfc3339)?,
+        status_code: status.map(|status| status.as_u16()),
+        error: error.clone(),
+    };
+    let attempt_log = append_attempt(job.attempt_log.as_deref(), &attempt);
 
-    if is_expired {
+    if let Some(reason) = reason {
+        let Some(dead_letter_id) =
+            dead_letter_job(db, worker_id, job, &attempt, &attempt_log, reason).await?
+        else {
+            return Ok(None);
+        };
         error!(
             id = job.id,
+            dead_letter_id = dead_letter_id,
             attempts = attempts,
+            reason = reason.as_str(),
             error = error,
-            "Webhook expired after max retries"
-        );
-    } else {
-        warn!(
-            id = job.id,
-            attempt = attempts,
-            retry_in_minutes = minutes_to_wait,
-            error = error,
-            "Webhook failed, scheduling retry"
+            "Webhook given up on, moved to dead letters"
         );
+        return Ok(Some(DeadLetterNotice {
+            dead_letter_id,
+            webhook_id: job.id,
+            reason,
+            attempts,
+            last_status_code: attempt.status_code,
+            last_error: error,
+        }));
     }
+    warn!(
+        id = job.id,
+        attempt = attempts,
+        retry_in_seconds = delay.as_secs(),
+        error = error,
+        "Webhook failed, scheduling retry"
+    );
 
     let query_builder = &*db.query_builder;
     let (sql, values) = Query::update()
         .table(WebhookQueue::Table)
         .values([
             (WebhookQueue::Attempts, attempts.into()),
-            (WebhookQueue::LastError, error.into()),
-            (WebhookQueue::IsExpired, is_expired.into()),
+            (WebhookQueue::LastError, error.as_str().into()),
             (
-                WebhookQueue::NextRetryAt,
-                if db.wrap_timestamp {
-                    Expr::val(next_try_at).cast_as(Alias::new("timestamp"))
-                } else {
-                    next_try_at.into()
-                },
+                WebhookQueue::LastStatusCode,
+                status.map(|status| status.as_u16() as i32).into(),
+            ),
+            (WebhookQueue::AttemptLog, attempt_log.into()),
+            (WebhookQueue::NextRetryAt, timestamp_value(db, next_try_at)),
+            (WebhookQueue::LockedBy, SimpleExpr::Keyword(Keyword::Null)),
+            (
+                WebhookQueue::LockedUntil,
+                SimpleExpr::Keyword(Keyword::Null),
             ),
         ])
         .and_where(Expr::col(WebhookQueue::Id).eq(job.id))
+        .and_where(Expr::col(WebhookQueue::LockedBy).eq(worker_id))
         .build_any_sqlx(query_builder);
 
     sqlx::query_with(&sql, values)
         .execute(&mut db.connection)
         .await?;
+    Ok(None)
+}
+
+/// Moves a job given up on from the queue to the dead letters, unless its lease was
+/// lost to another worker. Returns the id of the dead letter.
+async fn dead_letter_job(
+    db: &mut DBConnection,
+    worker_id: &str,
+    job: &JobToExecute,
+    attempt: &Attempt,
+    attempt_log: &str,
+    reason: DeadLetterReason,
+) -> Result<Option<i32>> {
+    let query_builder = &*db.query_builder;
+    let (insert_sql, insert_values) = Query::insert()
+        .into_table(DeadLetter::Table)
+        .columns([
+            DeadLetter::WebhookId,
+            DeadLetter::EmailRouteId,
+            DeadLetter::BounceEventId,
+            DeadLetter::Payload,
+            DeadLetter::CreatedAt,
+            DeadLetter::Attempts,
+            DeadLetter::AttemptLog,
+            DeadLetter::LastError,
+            DeadLetter::LastStatusCode,
+            DeadLetter::Reason,
+        ])
+        .select_from(
+            Query::select()
+                .columns([
+                    WebhookQueue::Id,
+                    WebhookQueue::EmailRouteId,
+                    WebhookQueue::BounceEventId,
+                    WebhookQueue::Payload,
+                    WebhookQueue::CreatedAt,
+                ])
+                .expr(Expr::val(attempt.attempt))
+                .expr(Expr::val(attempt_log))
+                .expr(Expr::val(attempt.error.as_str()))
+                .expr(Expr::val(attempt.status_code.map(|code| code as i32)))
+                .expr(Expr::val(reason.as_str()))
+                .from(WebhookQueue::Table)
+                .and_where(Expr::col(WebhookQueue::Id).eq(job.id))
+                .and_where(Expr::col(WebhookQueue::LockedBy).eq(worker_id))
+                .to_owned(),
+        )?
+        .build_any_sqlx(query_builder);
+    let (select_sql, select_values) = Query::select()
+        .column(DeadLetter::Id)
+        .from(DeadLetter::Table)
+        .and_where(Expr::col(DeadLetter::WebhookId).eq(job.id))
+        .order_by(DeadLetter::Id, Order::Desc)
+        .limit(1)
+        .build_any_sqlx(query_builder);
+    let (delete_sql, delete_values) = Query::delete()
+        .from_table(WebhookQueue::Table)
+        .and_where(Expr::col(WebhookQueue::Id).eq(job.id))
+        .build_any_sqlx(query_builder);
+
+    let mut transaction = db.connection.begin().await?;
+    let inserted = sqlx::query_with(&insert_sql, insert_values)
+        .execute(&mut *transaction)
+        .await
+        .with_context(|| "Failed to insert dead letter")?
+        .rows_affected();
+    if inserted == 0 {
+        transaction.rollback().await?;
+        warn!(
+            id = job.id,
+            "Lease of webhook was lost, not moving it to dead letters"
+        );
+        return Ok(None);
+    }
+    let dead_letter_id: i32 = sqlx::query_with(&select_sql, select_values)
+        .fetch_one(&mut *transaction)
+        .await?
+        .try_get(0)?;
+    sqlx::query_with(&delete_sql, delete_values)
+        .execute(&mut *transaction)
+        .await?;
+    transaction.commit().await?;
+    Ok(Some(dead_letter_id))
+}
+
+/// Counts a 410 Gone response of the route, any other response resets the count. The
+/// route is disabled once the count reaches its `disable_after_gone` limit, its queued
+/// webhooks are kept until it is enabled again.
+async fn record_gone(db: &mut DBConnection, job: &JobToExecute, gone: bool) -> Result<()> {
+    let query_builder = &*db.query_builder;
+    let mut update = Query::update();
+    update
+        .table(EmailRoute::Table)
+        .and_where(Expr::col(EmailRoute::Id).eq(job.email_route_id));
+    if !gone {
+        let (sql, values) = update
+            .value(EmailRoute::ConsecutiveGone, 0)
+            .and_where(Expr::col(EmailRoute::ConsecutiveGone).gt(0))
+            .build_any_sqlx(query_builder);
+        sqlx::query_with(&sql, values)
+            .execute(&mut db.connection)
+            .await?;
+        return Ok(());
+    }
+
+    let (sql, values) = update
+        .to_owned()
+        .value(
+            EmailRoute::ConsecutiveGone,
+            Expr::col(EmailRoute::ConsecutiveGone).add(1),
+        )
+        .build_any_sqlx(query_builder);
+    sqlx::query_with(&sql, values)
+        .execute(&mut db.connection)
+        .await?;
+
+    let (sql, values) = update
+        .value(EmailRoute::IsEnabled, false)
+        .and_where(Expr::col(EmailRoute::IsEnabled).eq(true))
+        .and_where(Expr::col(EmailRoute::ConsecutiveGone).gte(job.disable_after_gone.unwrap_or(0)))
+        .build_any_sqlx(query_builder);
+    let result = sqlx::query_with(&sql, values)
+        .execute(&mut db.connection)
+        .await?;
+    if result.rows_affected() > 0 {
+        error!(
+            route_id = job.email_route_id,
+            url = job.url.as_str(),
+            "Disabled route after consecutive 410 Gone responses"
+        );
+    }
     Ok(())
 }
 
-async fn find_jobs(db: &mut DBConnection, max_jobs: u64) -> Result<Vec<JobToExecute>> {
+/// Claims due jobs by leasing them to this worker. PostgreSQL and MySQL skip rows
+/// another worker is claiming at the same time, SQLite serializes writes, so the
+/// conditional lease update is enough there. Leases of crashed workers expire and the
+/// jobs are claimed again. Only up to `free` jobs within the free slots of their
+/// routes are claimed.
+async fn claim_jobs(
+    config: &AppConfig,
+    db: &mut DBConnection,
+    worker_id: &str,
+    deliveries: &Deliveries,
+    free: usize,
+) -> Result<Vec<JobToExecute>> {
+    let locked_until = timestamp_value(
+        db,
+        OffsetDateTime::now_utc() + TimeDuration::seconds(config.worker_lease_seconds as i64),
+    );
+    let skip_locked = db.connection.backend_name() != "SQLite";
     let query_builder = &*db.query_builder;
+
+    // Webhooks of ordered routes are held back while an older one is queued, whether it
+    // waits for its retry, is being delivered or is due again. Only the oldest webhook
+    // of an ordered route is ever a candidate.
+    let older = Alias::new("older");
+    let older_job = Query::select()
+        .expr(Expr::val(1))
+        .from_as(WebhookQueue::Table, older.clone())
+        .and_where(
+            Expr::col((older.clone(), WebhookQueue::EmailRouteId))
+                .equals((WebhookQueue::Table, WebhookQueue::EmailRouteId)),
+        )
+        .and_where(
+            Expr::col((older, WebhookQueue::Id))
+                .lt(Expr::col((WebhookQueue::Table, WebhookQueue::Id))),
+        )
+        .to_owned();
+
+    let mut candidates = Query::select();
+    candidates
+        .column((WebhookQueue::Table, WebhookQueue::Id))
+        .column((WebhookQueue::Table, WebhookQueue::EmailRouteId))
+        .column((EmailRoute::Table, EmailRoute::MaxConcurrency))
+        .expr_as(
+            bool_as_int((EmailRoute::Table, EmailRoute::OrderedDelivery)),
+            EmailRoute::OrderedDelivery,
+        )
+        .from(WebhookQueue::Table)
+        .inner_join(
+            EmailRoute::Table,
+            Expr::col((WebhookQueue::Table, WebhookQueue::EmailRouteId))
+                .equals((EmailRoute::Table, EmailRoute::Id)),
+        )
+        .and_where(
+            Expr::col((WebhookQueue::Table, WebhookQueue::NextRetryAt))
+                .lte(Expr::current_timestamp()),
+        )
+        .cond_where(lease_available())
+        .and_where(Expr::col((EmailRoute::Table, EmailRoute::IsEnabled)).eq(true))
+        .cond_where(
+            Cond::any()
+                .add(Expr::col((EmailRoute::Table, EmailRoute::OrderedDelivery)).eq(false))
+                .add(Expr::exists(older_job).not()),
+        )
+        .order_by((WebhookQueue::Table, WebhookQueue::NextRetryAt), Order::Asc)
+        .limit(config.worker_items_per_iteration);
+    let full_routes = deliveries.full_routes();
+    if !full_routes.is_empty() {
+        candidates.and_where(
+            Expr::col((WebhookQueue::Table, WebhookQueue::EmailRouteId)).is_not_in(full_routes),
+        );
+    }
+    if skip_locked {
+        candidates.lock_with_tables_behavior(
+            LockType::Update,
+            [WebhookQueue::Table],
+            LockBehavior::SkipLocked,
+        );
+    }
+    // SQLite cannot upgrade a read transaction while another worker writes, but each
+    // statement is atomic there
+    let pick = |candidates: &[Candidate]| {
+        pick_candidates(
+            candidates,
+            free,
+            &deliveries.routes,
+            config.worker_route_concurrency,
+        )
+    };
+    let ids = if skip_locked {
+        let mut transaction = db.connection.begin().await?;
+        let ids = lease_candidates(
+            &mut transaction,
+            query_builder,
+            &candidates,
+            &pick,
+            worker_id,
+            locked_until,
+        )
+        .await?;
+        transaction.commit().await?;
+        ids
+    } else {
+        lease_candidates(
+            &mut db.connection,
+            query_builder,
+            &candidates,
+            &pick,
+            worker_id,
+            locked_until,
+        )
+        .await?
+    };
+    if ids.is_empty() {
+        return Ok(Vec::new());
+    }
+
     let (sql, values) = Query::select()
         .column((WebhookQueue::Table, WebhookQueue::Id))
-        .columns([WebhookQueue::Payload, WebhookQueue::Attempts])
+        .columns([
+            WebhookQueue::EmailRouteId,
+            WebhookQueue::Payload,
+            WebhookQueue::Attempts,
+            WebhookQueue::AttemptLog,
+        ])
         .column((EmailRoute::Table, EmailRoute::Url))
         .column((EmailRoute::Table, EmailRoute::SecretToken))
+        .column((EmailRoute::Table, EmailRoute::MaxConcurrency))
+        .column((EmailRoute::Table, EmailRoute::RetryBaseDelaySeconds))
+        .column((EmailRoute::Table, EmailRoute::RetryMultiplier))
+        .column((EmailRoute::Table, EmailRoute::RetryMaxDelaySeconds))
+        .column((EmailRoute::Table, EmailRoute::RetryStatusCodes))
+        .column((EmailRoute::Table, EmailRoute::PermanentStatusCodes))
+        .column((EmailRoute::Table, EmailRoute::DisableAfterGone))
+        .column((EmailRoute::Table, EmailRoute::NotifyUrl))
+        .column((EmailRoute::Table, EmailRoute::NotifyEmail))
+        .expr_as(
+            bool_as_int((EmailRoute::Table, EmailRoute::OrderedDelivery)),
+            EmailRoute::OrderedDelivery,
+        )
         .from(WebhookQueue::Table)
-        .left_join(
+        .inner_join(
             EmailRoute::Table,
             Expr::col((WebhookQueue::Table, WebhookQueue::EmailRouteId))
                 .equals((EmailRoute::Table, EmailRoute::Id)),
         )
-        .and_where(Expr::col(WebhookQueue::NextRetryAt).lte(Expr::current_timestamp()))
-        .and_where(Expr::col(WebhookQueue::IsExpired).eq(false))
-        .and_where(Expr::col((EmailRoute::Table, EmailRoute::IsEnabled)).eq(true))
+        .and_where(Expr::col((WebhookQueue::Table, WebhookQueue::Id)).is_in(ids))
+        .and_where(Expr::col(WebhookQueue::LockedBy).eq(worker_id))
         .order_by(WebhookQueue::NextRetryAt, Order::Asc)
-        .limit(max_jobs)
         .build_any_sqlx(query_builder);
 
     sqlx::query_as_with(&sql, values)
@@ -205,3 +862,137 @@ async fn find_jobs(db: &mut DBConnection, max_jobs: u64) -> Result<Vec<JobToExec
         .await
         .with_context(|| "Failed to load queue entries")
 }
+
+/// Leases the picked candidate jobs to this worker, unless another worker leased them
+/// meanwhile. Returns the picked ids.
+async fn lease_candidates(
+    connection: &mut AnyConnection,
+    query_builder: &dyn QueryBuilder,
+    candidates: &SelectStatement,
+    pick: &dyn Fn(&[Candidate]) -> Vec<i32>,
+    worker_id: &str,
+    locked_until: SimpleExpr,
+) -> Result<Vec<i32>> {
+    let (sql, values) = candidates.build_any_sqlx(query_builder);
+    let candidates: Vec<Candidate> = sqlx::query_as_with(&sql, values)
+        .fetch_all(&mut *connection)
+        .await
+        .with_context(|| "Failed to load queue entries")?;
+    let ids = pick(&candidates);
+    if ids.is_empty() {
+        return Ok(ids);
+    }
+
+    let (sql, values) = Query::update()
+        .table(WebhookQueue::Table)
+        .values([
+            (WebhookQueue::LockedBy, worker_id.into()),
+            (WebhookQueue::LockedUntil, locked_until),
+        ])
+        .and_where(Expr::col(WebhookQueue::Id).is_in(ids.iter().copied()))
+        .cond_where(lease_available())
+        .build_any_sqlx(query_builder);
+    sqlx::query_with(&sql, values)
+        .execute(&mut *connection)
+        .await
+        .with_context(|| "Failed to lease queue entries")?;
+    Ok(ids)
+}
+
+/// Jobs that are not leased, or whose lease expired
+pub fn lease_available() -> Cond {
+    Cond::any()
+        .add(Expr::col((WebhookQueue::Table, WebhookQueue::LockedUntil)).is_null())
+        .add(
+            Expr::col((WebhookQueue::Table, WebhookQueue::LockedUntil))
+                .lt(Expr::current_timestamp()),
+        )
+}
+
+/// Identifies this worker in leases, `worker_id` or host name and process id
+fn default_worker_id() -> String {
+    let hostname = std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .map(|hostname| hostname.trim().to_string())
+        .filter(|hostname| !hostname.is_empty())
+        .unwrap_or("localhost".to_string());
+    format!("{}:{}", hostname, std::process::id())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(id: i32, email_route_id: i32, max_concurrency: Option<i32>) -> Candidate {
+        Candidate {
+            id,
+            email_route_id,
+            max_concurrency,
+            ordered_delivery: 0,
+        }
+    }
+
+    #[test]
+    fn pick_candidates_respects_route_slots() {
+        let candidates = [
+            candidate(1, 1, None),
+            candidate(2, 1, None),
+            candidate(3, 1, None),
+            candidate(4, 2, Some(1)),
+            candidate(5, 2, Some(1)),
+            candidate(6, 3, None),
+        ];
+        let picked = pick_candidates(&candidates, 10, &HashMap::new(), 2);
+        assert_eq!(picked, [1, 2, 4, 6]);
+    }
+
+    #[test]
+    fn pick_candidates_counts_deliveries_in_flight() {
+        let candidates = [
+            candidate(1, 1, None),
+            candidate(2, 1, None),
+            candidate(3, 2, None),
+        ];
+        let routes = HashMap::from([(
+            1,
+            RouteSlots {
+                in_flight: 1,
+                limit: 2,
+            },
+        )]);
+        assert_eq!(pick_candidates(&candidates, 10, &routes, 2), [1, 3]);
+        assert_eq!(pick_candidates(&candidates, 1, &routes, 2), [1]);
+    }
+
+    #[test]
+    fn ordered_routes_deliver_one_at_a_time() {
+        assert_eq!(route_limit(Some(5), 1, 2), 1);
+        assert_eq!(route_limit(Some(5), 0, 2), 5);
+        assert_eq!(route_limit(None, 0, 2), 2);
+        assert_eq!(route_limit(Some(0), 0, 2), 1);
+    }
+
+    #[tokio::test]
+    async fn panicked_deliveries_free_their_route_slot() {
+        let mut deliveries = Deliveries::default();
+        deliveries.routes.insert(
+            1,
+            RouteSlots {
+                in_flight: 1,
+                limit: 1,
+            },
+        );
+        let task = deliveries
+            .tasks
+            .spawn(async { panic!("delivery panicked") });
+        deliveries.task_routes.insert(task.id(), 1);
+
+        let finished = deliveries.tasks.join_next_with_id().await.unwrap();
+        let error = finished.err().unwrap();
+        assert!(error.is_panic());
+        deliveries.finish(error.id());
+        assert!(deliveries.routes.is_empty());
+        assert!(deliveries.task_routes.is_empty());
+    }
+}